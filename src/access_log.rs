@@ -0,0 +1,518 @@
+use std::{
+    convert::Infallible,
+    fmt::Write as _,
+    future::Future,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use bytes::Bytes;
+use futures_util::TryStreamExt;
+use http::{header::HeaderName, HeaderMap, HeaderValue, Request, Response};
+use hyper::{body::HttpBody, service::Service, Body};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::OpenOptions,
+    io::{self, AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+use tonic::async_trait;
+
+use crate::{errors::Component, faults::Reset, redact, serve::Peer, supervisor::Task};
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+const QUEUE_SIZE: usize = 4096;
+
+/// Destination of the access log, either `stdout` or the path of a file that is appended to
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "PathBuf")]
+pub enum Output {
+    Stdout,
+    File(PathBuf),
+}
+
+impl From<PathBuf> for Output {
+    fn from(path: PathBuf) -> Self {
+        if path.as_os_str() == "stdout" {
+            Self::Stdout
+        } else {
+            Self::File(path)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    #[default]
+    Json,
+    /// Common Log Format, which only contains a subset of the fields
+    Clf,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogSettings {
+    /// Requests are not logged when unset
+    pub output: Option<Output>,
+    pub format: Format,
+}
+
+/// Organization and service a request is routed to, filled in by the proxy handlers
+#[derive(Debug, Default)]
+struct Target {
+    organization: Option<String>,
+    service: Option<String>,
+    upstream: Option<String>,
+}
+
+tokio::task_local! {
+    static TARGET: Arc<Mutex<Target>>;
+}
+
+/// Records the organization and service of the request that is currently handled
+pub fn set_target(organization: Option<&str>, service: &str) {
+    let _ = TARGET.try_with(|target| {
+        let mut target = target.lock().unwrap();
+        target.organization = organization.map(String::from);
+        target.service = Some(service.to_string());
+    });
+}
+
+/// Records the address the request that is currently handled is proxied to
+pub fn set_upstream(upstream: &str) {
+    let _ = TARGET.try_with(|target| {
+        target.lock().unwrap().upstream = Some(upstream.to_string());
+    });
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    timestamp: String,
+    component: &'static str,
+    remote_addr: Option<SocketAddr>,
+    /// Serial number and name of the organization in the client certificate (mTLS only)
+    caller: Option<String>,
+    caller_name: Option<String>,
+    organization: Option<String>,
+    service: Option<String>,
+    method: String,
+    path: String,
+    query: Option<String>,
+    protocol: String,
+    /// Status of the response, 0 when it was reset by an injected fault
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// Time until the response body was sent in milliseconds
+    latency_ms: f64,
+    upstream: Option<String>,
+    request_id: String,
+    #[serde(skip)]
+    time: SystemTime,
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Escapes a value like Apache does for its logs: `"` and `\` get a backslash, other bytes
+/// that aren't printable ASCII become `\xhh`. A request can't break out of its quotes or add
+/// lines to the log this way.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for byte in value.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(byte as char);
+            }
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => {
+                let _ = write!(escaped, "\\x{:02x}", byte);
+            }
+        }
+    }
+
+    escaped
+}
+
+impl Entry {
+    fn to_clf(&self) -> String {
+        // YYYY-MM-DDTHH:MM:SSZ
+        let time = humantime::format_rfc3339_seconds(self.time).to_string();
+        let month = time[5..7].parse::<usize>().unwrap_or(1);
+        let mut line = format!(
+            "{} - {} [{}/{}/{}:{} +0000] \"{} {}",
+            self.remote_addr
+                .map(|addr| addr.ip().to_string())
+                .unwrap_or_else(|| "-".to_string()),
+            self.caller
+                .as_deref()
+                .map_or_else(|| "-".to_string(), escape),
+            &time[8..10],
+            MONTHS[month - 1],
+            &time[0..4],
+            &time[11..19],
+            self.method,
+            escape(&self.path)
+        );
+
+        if let Some(query) = &self.query {
+            let _ = write!(line, "?{}", escape(query));
+        }
+
+        let _ = write!(line, " {}\" {} ", self.protocol, self.status);
+
+        match self.bytes_out {
+            0 => line.push('-'),
+            bytes => {
+                let _ = write!(line, "{}", bytes);
+            }
+        }
+
+        line
+    }
+}
+
+struct Logger {
+    tx: mpsc::Sender<String>,
+    component: Component,
+    format: Format,
+}
+
+static LOGGER: OnceCell<Logger> = OnceCell::new();
+
+/// Request of which the entry is written once the response body is sent (or dropped)
+struct Pending {
+    entry: Entry,
+    start: Instant,
+    bytes_in: Arc<AtomicU64>,
+    target: Arc<Mutex<Target>>,
+}
+
+impl Pending {
+    fn new(logger: &Logger, request: &mut Request<Body>) -> Self {
+        let request_id = match request.headers().get(&X_REQUEST_ID) {
+            Some(value) => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            None => {
+                let request_id = format!("{:032x}", rand::random::<u128>());
+                request.headers_mut().insert(
+                    &X_REQUEST_ID,
+                    HeaderValue::from_str(&request_id).expect("valid request id"),
+                );
+
+                request_id
+            }
+        };
+        let peer = request.extensions().get::<Peer>();
+        let identity = peer.and_then(|peer| peer.identity.as_ref());
+        let time = SystemTime::now();
+        let entry = Entry {
+            timestamp: humantime::format_rfc3339_millis(time).to_string(),
+            component: logger.component.as_str(),
+            remote_addr: peer.map(|peer| peer.addr),
+            caller: identity.and_then(|identity| identity.serial_number.clone()),
+            caller_name: identity.and_then(|identity| identity.organization_name.clone()),
+            organization: None,
+            service: None,
+            method: request.method().to_string(),
+            path: request.uri().path().to_string(),
+            query: request
+                .uri()
+                .query()
+                .map(|query| redact::query(query).into_owned()),
+            protocol: format!("{:?}", request.version()),
+            status: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            latency_ms: 0.0,
+            upstream: None,
+            request_id,
+            time,
+        };
+
+        // Count the bytes of the request body as they are read by the handler
+        let bytes_in = Arc::new(AtomicU64::new(0));
+        let body = std::mem::take(request.body_mut());
+        *request.body_mut() = Body::wrap_stream({
+            let bytes_in = Arc::clone(&bytes_in);
+            body.inspect_ok(move |chunk| {
+                bytes_in.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            })
+        });
+
+        Self {
+            entry,
+            start: Instant::now(),
+            bytes_in,
+            target: Arc::default(),
+        }
+    }
+
+    fn finish(self, logger: &Logger) {
+        let mut entry = self.entry;
+        let target = std::mem::take(&mut *self.target.lock().unwrap());
+
+        entry.organization = target.organization;
+        entry.service = target.service;
+        entry.upstream = target.upstream;
+        entry.bytes_in = self.bytes_in.load(Ordering::Relaxed);
+        entry.latency_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+
+        let line = match logger.format {
+            Format::Json => match serde_json::to_string(&entry) {
+                Ok(line) => line,
+                Err(e) => {
+                    log::warn!("failed to serialize access log entry: {}", e);
+                    return;
+                }
+            },
+            Format::Clf => entry.to_clf(),
+        };
+
+        if logger.tx.try_send(line).is_err() {
+            log::debug!("access log entry dropped as the queue is full");
+        }
+    }
+}
+
+/// Response body which counts the bytes that are sent and writes the access log entry when
+/// it is dropped
+pub struct LoggedBody {
+    body: Body,
+    pending: Option<Box<Pending>>,
+}
+
+impl HttpBody for LoggedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.body).poll_data(cx);
+
+        if let (Poll::Ready(Some(Ok(chunk))), Some(pending)) = (&poll, &mut this.pending) {
+            pending.entry.bytes_out += chunk.len() as u64;
+        }
+
+        poll
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.get_mut().body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> hyper::body::SizeHint {
+        self.body.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let (Some(pending), Some(logger)) = (self.pending.take(), LOGGER.get()) {
+            pending.finish(logger);
+        }
+    }
+}
+
+/// Writes an access log entry for every request that is handled by the inner service
+#[derive(Clone)]
+pub struct AccessLogService<S> {
+    inner: S,
+}
+
+pub fn service<S>(inner: S) -> AccessLogService<S> {
+    AccessLogService { inner }
+}
+
+impl<S> Service<Request<Body>> for AccessLogService<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<LoggedBody>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<Body>) -> Self::Future {
+        let logger = match LOGGER.get() {
+            Some(logger) => logger,
+            None => {
+                let response = self.inner.call(request);
+
+                return Box::pin(async move {
+                    let response = response.await?;
+                    Ok(response.map(|body| LoggedBody {
+                        body,
+                        pending: None,
+                    }))
+                });
+            }
+        };
+
+        let mut pending = Pending::new(logger, &mut request);
+        let response = TARGET.scope(Arc::clone(&pending.target), self.inner.call(request));
+
+        Box::pin(async move {
+            let response = response.await?;
+            pending.entry.status = match response.extensions().get::<Reset>() {
+                Some(_) => 0,
+                None => response.status().as_u16(),
+            };
+
+            Ok(response.map(|body| LoggedBody {
+                body,
+                pending: Some(Box::new(pending)),
+            }))
+        })
+    }
+}
+
+/// Writes the access log entries to the output
+pub struct Writer {
+    output: Output,
+    rx: mpsc::Receiver<String>,
+}
+
+impl Writer {
+    async fn open(&self) -> Result<Box<dyn AsyncWrite + Send + Unpin>> {
+        Ok(match &self.output {
+            Output::Stdout => Box::new(io::stdout()),
+            Output::File(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("failed to open access log {}", path.display()))?,
+            ),
+        })
+    }
+}
+
+#[async_trait]
+impl Task for Writer {
+    async fn run(&mut self) -> Result<()> {
+        let mut output = BufWriter::new(self.open().await?);
+
+        while let Some(line) = self.rx.recv().await {
+            output.write_all(line.as_bytes()).await?;
+            output.write_all(b"\n").await?;
+
+            // Flush once the queue is drained, so bursts are written at once
+            while let Ok(line) = self.rx.try_recv() {
+                output.write_all(line.as_bytes()).await?;
+                output.write_all(b"\n").await?;
+            }
+
+            output.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Installs the global access logger, returns the writer task if access logging is enabled
+pub fn init(settings: &AccessLogSettings, component: Component) -> Option<Writer> {
+    let output = settings.output.clone()?;
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    let logger = Logger {
+        tx,
+        component,
+        format: settings.format,
+    };
+
+    LOGGER.set(logger).ok()?;
+
+    Some(Writer { output, rx })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn entry(path: &str, query: Option<&str>, caller: Option<&str>) -> Entry {
+        Entry {
+            timestamp: String::new(),
+            component: "inway",
+            remote_addr: Some("10.0.0.1:44321".parse().unwrap()),
+            caller: caller.map(String::from),
+            caller_name: None,
+            organization: None,
+            service: None,
+            method: "GET".to_string(),
+            path: path.to_string(),
+            query: query.map(String::from),
+            protocol: "HTTP/1.1".to_string(),
+            status: 200,
+            bytes_in: 0,
+            bytes_out: 512,
+            latency_ms: 1.0,
+            upstream: None,
+            request_id: String::new(),
+            time: SystemTime::UNIX_EPOCH + Duration::from_secs(1_666_000_000),
+        }
+    }
+
+    #[test]
+    fn formats_common_log_format() {
+        assert_eq!(
+            entry("/kentekens/1", Some("fields=merk"), Some("00000001")).to_clf(),
+            "10.0.0.1 - 00000001 [17/Oct/2022:09:46:40 +0000] \"GET /kentekens/1?fields=merk \
+             HTTP/1.1\" 200 512"
+        );
+        assert_eq!(
+            Entry {
+                bytes_out: 0,
+                ..entry("/", None, None)
+            }
+            .to_clf(),
+            "10.0.0.1 - - [17/Oct/2022:09:46:40 +0000] \"GET / HTTP/1.1\" 200 -"
+        );
+    }
+
+    #[test]
+    fn escapes_values() {
+        let line = entry(
+            "/a\"b\\c",
+            Some("q=\"\r\n127.0.0.1 - - [x] \"GET /"),
+            Some("0001\t\u{7f}é"),
+        )
+        .to_clf();
+
+        assert_eq!(
+            line,
+            "10.0.0.1 - 0001\\x09\\x7f\\xc3\\xa9 [17/Oct/2022:09:46:40 +0000] \
+             \"GET /a\\\"b\\\\c?q=\\\"\\x0d\\x0a127.0.0.1 - - [x] \\\"GET / HTTP/1.1\" 200 512"
+        );
+        assert!(!line.contains('\n'));
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use anyhow::Result;
+use http::StatusCode;
+use rustls::ServerConfig;
+use serde::Serialize;
+use tokio::sync::Notify;
+use tonic::async_trait;
+use warp::{filters::BoxedFilter, reply::Response, Filter, Reply};
+
+use crate::{
+    errors::{self, Component},
+    serve,
+    supervisor::{serialize_time, Supervisor, Task},
+    tls::{TlsPair, Validity},
+};
+
+/// Signals to background tasks that are triggered through the admin API
+#[derive(Clone, Default)]
+pub struct Controls {
+    /// Polls the config right away instead of waiting for the next interval
+    pub refresh_config: Arc<Notify>,
+    /// Registers the gateway with the management API and directory again
+    pub register: Arc<Notify>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Version {
+    /// Hash of the config
+    version: String,
+    #[serde(serialize_with = "serialize_time")]
+    updated_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ConfigVersion {
+    source: String,
+    #[serde(flatten)]
+    current: Option<Version>,
+}
+
+/// Version of the config that is in use and where it came from
+#[derive(Clone)]
+pub struct ConfigStatus {
+    inner: Arc<RwLock<ConfigVersion>>,
+}
+
+impl ConfigStatus {
+    pub fn new(source: String) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(ConfigVersion {
+                source,
+                current: None,
+            })),
+        }
+    }
+
+    pub fn set_version(&self, hash: u64) {
+        self.inner.write().unwrap().current = Some(Version {
+            version: format!("{:016x}", hash),
+            updated_at: SystemTime::now(),
+        });
+    }
+
+    fn get(&self) -> ConfigVersion {
+        self.inner.read().unwrap().clone()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Certificate {
+    name: &'static str,
+    serial_number: Option<String>,
+    organization_name: Option<String>,
+    common_name: Option<String>,
+    #[serde(serialize_with = "serialize_time")]
+    not_before: SystemTime,
+    #[serde(serialize_with = "serialize_time")]
+    not_after: SystemTime,
+}
+
+impl Certificate {
+    fn new(name: &'static str, pair: &TlsPair) -> Result<Self> {
+        let identity = pair.identity()?;
+        let validity = pair.validity()?;
+
+        Ok(Self {
+            name,
+            serial_number: identity.serial_number,
+            organization_name: identity.organization_name,
+            common_name: identity.common_name,
+            not_before: validity.not_before,
+            not_after: validity.not_after,
+        })
+    }
+
+    fn is_valid(&self) -> bool {
+        Validity {
+            not_before: self.not_before,
+            not_after: self.not_after,
+        }
+        .is_valid()
+    }
+}
+
+/// Certificate with its validity at the time of the request
+#[derive(Serialize)]
+struct CertificateStatus<'a> {
+    #[serde(flatten)]
+    certificate: &'a Certificate,
+    valid: bool,
+}
+
+#[derive(Serialize)]
+struct Accepted {
+    accepted: bool,
+}
+
+fn accepted() -> Response {
+    warp::reply::with_status(
+        warp::reply::json(&Accepted { accepted: true }),
+        StatusCode::ACCEPTED,
+    )
+    .into_response()
+}
+
+/// Admin API to inspect and control a running gateway, it is served without TLS on a loopback
+/// address and requires a client certificate of the internal PKI otherwise
+pub struct Admin {
+    addr: SocketAddr,
+    tls_config: Option<ServerConfig>,
+    component: Component,
+    supervisor: Supervisor,
+    controls: Controls,
+    config: ConfigStatus,
+    certificates: Arc<Vec<Certificate>>,
+}
+
+impl Admin {
+    pub fn new(
+        addr: SocketAddr,
+        component: Component,
+        internal_tls_pair: &TlsPair,
+        org_tls_pair: &TlsPair,
+        supervisor: Supervisor,
+        controls: Controls,
+        config: ConfigStatus,
+    ) -> Result<Self> {
+        let tls_config = if addr.ip().is_loopback() {
+            None
+        } else {
+            Some(internal_tls_pair.server_config()?)
+        };
+        let certificates = vec![
+            Certificate::new("internal", internal_tls_pair)?,
+            Certificate::new("organization", org_tls_pair)?,
+        ];
+
+        Ok(Self {
+            addr,
+            tls_config,
+            component,
+            supervisor,
+            controls,
+            config,
+            certificates: Arc::new(certificates),
+        })
+    }
+
+    /// Returns the admin server, `routes` are the component specific routes
+    pub fn server(self, routes: BoxedFilter<(Response,)>) -> Server {
+        Server {
+            admin: self,
+            routes,
+        }
+    }
+
+    fn routes(&self) -> BoxedFilter<(Response,)> {
+        let supervisor = self.supervisor.clone();
+        let tasks = warp::get()
+            .and(warp::path("tasks"))
+            .and(warp::path::end())
+            .map(move || warp::reply::json(&supervisor.states()).into_response());
+        let config_status = self.config.clone();
+        let config = warp::get()
+            .and(warp::path("config"))
+            .and(warp::path::end())
+            .map(move || warp::reply::json(&config_status.get()).into_response());
+        let certificates = Arc::clone(&self.certificates);
+        let certificates = warp::get()
+            .and(warp::path("certificates"))
+            .and(warp::path::end())
+            .map(move || {
+                let statuses = certificates
+                    .iter()
+                    .map(|certificate| CertificateStatus {
+                        certificate,
+                        valid: certificate.is_valid(),
+                    })
+                    .collect::<Vec<_>>();
+
+                warp::reply::json(&statuses).into_response()
+            });
+        let refresh_config = Arc::clone(&self.controls.refresh_config);
+        let refresh = warp::post()
+            .and(warp::path("config"))
+            .and(warp::path("refresh"))
+            .and(warp::path::end())
+            .map(move || {
+                log::info!("config refresh requested through the admin API");
+                refresh_config.notify_one();
+                accepted()
+            });
+        let register = Arc::clone(&self.controls.register);
+        let registration = warp::post()
+            .and(warp::path("registration"))
+            .and(warp::path::end())
+            .map(move || {
+                log::info!("registration requested through the admin API");
+                register.notify_one();
+                accepted()
+            });
+
+        tasks
+            .or(config)
+            .unify()
+            .or(certificates)
+            .unify()
+            .or(refresh)
+            .unify()
+            .or(registration)
+            .unify()
+            .boxed()
+    }
+}
+
+/// Serves the admin API
+pub struct Server {
+    admin: Admin,
+    routes: BoxedFilter<(Response,)>,
+}
+
+#[async_trait]
+impl Task for Server {
+    async fn run(&mut self) -> Result<()> {
+        let component = self.admin.component;
+        let routes = self
+            .admin
+            .routes()
+            .or(self.routes.clone())
+            .unify()
+            .recover(move |rejection| errors::recover(component, rejection));
+
+        log::info!("starting admin server on {}", self.admin.addr);
+
+        serve::serve(
+            self.admin.addr,
+            warp::service(routes),
+            self.admin.tls_config.clone(),
+            self.admin.supervisor.shutdown_token(),
+        )
+        .await
+    }
+}
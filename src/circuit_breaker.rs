@@ -0,0 +1,341 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::metrics;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerSettings {
+    pub enabled: bool,
+    /// Period in which requests are counted to determine the error rate
+    #[serde(with = "humantime_serde")]
+    pub window: Duration,
+    /// Minimum number of requests in the window before the circuit can open
+    pub min_requests: u32,
+    /// Fraction of failed requests (0.0 - 1.0) at which the circuit opens
+    pub error_rate: f64,
+    /// Requests that take longer than this to respond are counted as failures
+    #[serde(with = "humantime_serde")]
+    pub latency_threshold: Duration,
+    /// How long the circuit stays open before trial requests are let through
+    #[serde(with = "humantime_serde")]
+    pub open_duration: Duration,
+    /// Number of successful trial requests needed to close the circuit again
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            window: Duration::from_secs(10),
+            min_requests: 20,
+            error_rate: 0.5,
+            latency_threshold: Duration::from_secs(10),
+            open_duration: Duration::from_secs(30),
+            half_open_requests: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Closed => "closed",
+            Self::Open => "open",
+            Self::HalfOpen => "half_open",
+        }
+    }
+}
+
+enum Inner {
+    Closed {
+        window_start: Instant,
+        requests: u32,
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        in_flight: u32,
+        successes: u32,
+    },
+}
+
+impl Inner {
+    fn closed() -> Self {
+        Self::Closed {
+            window_start: Instant::now(),
+            requests: 0,
+            failures: 0,
+        }
+    }
+
+    fn state(&self) -> CircuitState {
+        match self {
+            Self::Closed { .. } => CircuitState::Closed,
+            Self::Open { .. } => CircuitState::Open,
+            Self::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+}
+
+/// Circuit breaker for a single upstream
+pub struct Circuit {
+    name: String,
+    settings: Arc<CircuitBreakerSettings>,
+    inner: Mutex<Inner>,
+}
+
+impl Circuit {
+    fn new(name: String, settings: Arc<CircuitBreakerSettings>) -> Self {
+        metrics::CIRCUIT_STATE.with_label_values(&[&name]).set(0);
+
+        Self {
+            name,
+            settings,
+            inner: Mutex::new(Inner::closed()),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.inner.lock().unwrap().state()
+    }
+
+    /// Returns true if a request would currently get a permit, without acquiring one
+    pub fn is_available(&self) -> bool {
+        if !self.settings.enabled {
+            return true;
+        }
+
+        match &*self.inner.lock().unwrap() {
+            Inner::Closed { .. } => true,
+            Inner::Open { until } => Instant::now() >= *until,
+            Inner::HalfOpen { in_flight, .. } => *in_flight < self.settings.half_open_requests,
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, to: Inner) {
+        let (from, to_state) = (inner.state(), to.state());
+
+        match to_state {
+            CircuitState::Open => log::warn!(
+                "circuit for {} changed from {} to {}",
+                self.name,
+                from.as_str(),
+                to_state.as_str()
+            ),
+            _ => log::info!(
+                "circuit for {} changed from {} to {}",
+                self.name,
+                from.as_str(),
+                to_state.as_str()
+            ),
+        }
+
+        metrics::CIRCUIT_STATE
+            .with_label_values(&[&self.name])
+            .set(to_state as i64);
+        metrics::CIRCUIT_TRANSITIONS
+            .with_label_values(&[&self.name, to_state.as_str()])
+            .inc();
+
+        *inner = to;
+    }
+
+    fn open(&self, inner: &mut Inner) {
+        let until = Instant::now() + self.settings.open_duration;
+        self.transition(inner, Inner::Open { until });
+    }
+
+    /// Returns a permit if a request to the upstream is allowed
+    pub fn try_acquire(self: &Arc<Self>) -> Option<Permit> {
+        if !self.settings.enabled {
+            return Some(Permit::new(Arc::clone(self), false));
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+
+        match &mut *inner {
+            Inner::Closed { .. } => Some(Permit::new(Arc::clone(self), false)),
+            Inner::Open { until } if Instant::now() >= *until => {
+                self.transition(
+                    &mut inner,
+                    Inner::HalfOpen {
+                        in_flight: 1,
+                        successes: 0,
+                    },
+                );
+
+                Some(Permit::new(Arc::clone(self), true))
+            }
+            Inner::HalfOpen { in_flight, .. } if *in_flight < self.settings.half_open_requests => {
+                *in_flight += 1;
+                Some(Permit::new(Arc::clone(self), true))
+            }
+            _ => {
+                metrics::CIRCUIT_REJECTED
+                    .with_label_values(&[&self.name])
+                    .inc();
+                None
+            }
+        }
+    }
+
+    fn release(&self, half_open: bool, success: Option<bool>) {
+        if !self.settings.enabled {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+
+        match &mut *inner {
+            Inner::Closed {
+                window_start,
+                requests,
+                failures,
+            } => {
+                let success = match success {
+                    Some(success) => success,
+                    None => return,
+                };
+
+                if window_start.elapsed() > self.settings.window {
+                    *window_start = Instant::now();
+                    *requests = 0;
+                    *failures = 0;
+                }
+
+                *requests += 1;
+
+                if !success {
+                    *failures += 1;
+                }
+
+                if *requests >= self.settings.min_requests
+                    && *failures as f64 / *requests as f64 >= self.settings.error_rate
+                {
+                    self.open(&mut inner);
+                }
+            }
+            Inner::HalfOpen {
+                in_flight,
+                successes,
+            } if half_open => {
+                *in_flight = in_flight.saturating_sub(1);
+
+                match success {
+                    Some(true) => {
+                        *successes += 1;
+
+                        if *successes >= self.settings.half_open_requests {
+                            self.transition(&mut inner, Inner::closed());
+                        }
+                    }
+                    Some(false) => self.open(&mut inner),
+                    None => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Tracks the outcome of a single request, requests that are dropped without an
+/// outcome (e.g. when the client disconnects) are not counted.
+pub struct Permit {
+    circuit: Arc<Circuit>,
+    half_open: bool,
+    done: bool,
+}
+
+impl Permit {
+    fn new(circuit: Arc<Circuit>, half_open: bool) -> Self {
+        Self {
+            circuit,
+            half_open,
+            done: false,
+        }
+    }
+
+    pub fn record(mut self, success: bool, latency: Duration) {
+        let success = success && latency <= self.circuit.settings.latency_threshold;
+
+        self.done = true;
+        self.circuit.release(self.half_open, Some(success));
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.done {
+            self.circuit.release(self.half_open, None);
+        }
+    }
+}
+
+/// Circuit breakers for all upstreams, created on first use
+#[derive(Clone)]
+pub struct CircuitBreakers {
+    settings: Arc<CircuitBreakerSettings>,
+    circuits: Arc<RwLock<HashMap<String, Arc<Circuit>>>>,
+}
+
+impl CircuitBreakers {
+    pub fn new(settings: CircuitBreakerSettings) -> Self {
+        Self {
+            settings: Arc::new(settings),
+            circuits: Arc::default(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Arc<Circuit> {
+        if let Some(circuit) = self.circuits.read().unwrap().get(name) {
+            return Arc::clone(circuit);
+        }
+
+        let mut circuits = self.circuits.write().unwrap();
+        let circuit = circuits.entry(name.to_string()).or_insert_with(|| {
+            Arc::new(Circuit::new(name.to_string(), Arc::clone(&self.settings)))
+        });
+
+        Arc::clone(circuit)
+    }
+
+    /// Returns the state of the circuit of the upstream, if any request was made to it
+    pub fn state(&self, name: &str) -> Option<CircuitState> {
+        self.circuits
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|circuit| circuit.state())
+    }
+
+    /// Returns false if the circuit of the upstream currently rejects requests
+    pub fn is_available(&self, name: &str) -> bool {
+        self.circuits
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|circuit| circuit.is_available())
+            .unwrap_or(true)
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use hyper::Client;
+
+/// HTTP version(s) a client may use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    /// HTTP/2 if the upstream supports it, otherwise HTTP/1.1
+    Auto,
+    /// Upgrades are only possible on HTTP/1.1 connections
+    Http1,
+    /// gRPC requires HTTP/2, also for plain text connections
+    Http2,
+}
+
+/// Connection settings that need a separate client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientKey {
+    pub connect_timeout: Option<Duration>,
+    pub protocol: Protocol,
+}
+
+type BuildFn<K, C> = dyn Fn(&K) -> Client<C> + Send + Sync;
+
+/// Lazily creates a (pooled) HTTP client for every distinct set of connection settings
+pub struct ClientPool<K, C> {
+    clients: Arc<RwLock<HashMap<K, Client<C>>>>,
+    build: Arc<BuildFn<K, C>>,
+}
+
+impl<K, C> Clone for ClientPool<K, C> {
+    fn clone(&self) -> Self {
+        Self {
+            clients: Arc::clone(&self.clients),
+            build: Arc::clone(&self.build),
+        }
+    }
+}
+
+impl<K, C> ClientPool<K, C>
+where
+    K: Hash + Eq + Clone,
+    C: Clone,
+{
+    pub fn new(build: impl Fn(&K) -> Client<C> + Send + Sync + 'static) -> Self {
+        Self {
+            clients: Arc::default(),
+            build: Arc::new(build),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Client<C> {
+        if let Some(client) = self.clients.read().unwrap().get(key) {
+            return client.clone();
+        }
+
+        self.clients
+            .write()
+            .unwrap()
+            .entry(key.clone())
+            .or_insert_with(|| (self.build)(key))
+            .clone()
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    convert::Infallible,
+    fmt::{self, Display},
+    time::Duration,
+};
+
+use http::{header::RETRY_AFTER, HeaderValue, StatusCode};
+use serde::Serialize;
+use warp::{
+    reject::{MethodNotAllowed, PayloadTooLarge, Reject},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    faults::InjectedFault,
+    grpc,
+    inway::{AccessDenied, BackendAuthError, InMaintenance, Limit, RateLimited},
+    outway::FixtureError,
+    reverse_proxy::{IntoRequestError, ProxyError},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Component {
+    Inway,
+    Outway,
+}
+
+impl Component {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Inway => "nlx-inway",
+            Self::Outway => "nlx-outway",
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    pub code: &'static str,
+    pub message: String,
+    pub component: &'static str,
+}
+
+pub fn reply(
+    component: Component,
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+) -> Response {
+    let body = ErrorResponse {
+        code,
+        message,
+        component: component.as_str(),
+    };
+
+    let mut response = warp::reply::with_status(warp::reply::json(&body), status).into_response();
+
+    // Keep the details around in case the error needs to be returned in another format
+    response.extensions_mut().insert(body);
+    response
+}
+
+/// Extracts true for gRPC requests
+pub fn is_grpc() -> impl Filter<Extract = (bool,), Error = Infallible> + Clone {
+    warp::header::value("content-type")
+        .map(|value: HeaderValue| grpc::is_grpc_content_type(&value))
+        .or(warp::any().map(|| false))
+        .unify()
+}
+
+/// Returns the error responses of the gateway as gRPC errors to gRPC clients, as they
+/// expect the status in the `grpc-status` header or trailer instead of the HTTP status
+pub fn with_grpc_status(is_grpc: bool, reply: impl Reply) -> Response {
+    let response = reply.into_response();
+
+    if !is_grpc {
+        return response;
+    }
+
+    match response.extensions().get::<ErrorResponse>() {
+        Some(error) => grpc::error_response(
+            response.status().into(),
+            &error.message,
+            &[
+                ("nlx-error-code", error.code),
+                ("nlx-component", error.component),
+            ],
+        ),
+        None => response,
+    }
+}
+
+/// Failures to find a route for a request
+#[derive(Debug)]
+pub enum RouteError {
+    /// The service is unknown in the directory (outway) or not offered (inway)
+    UnknownService(String),
+    /// The service is known but none of its inways can be used
+    NoInways(String),
+    /// The service is offered but all of its backend endpoints have weight 0
+    NoEndpoints(String),
+}
+
+impl Reject for RouteError {}
+
+impl Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownService(service) => write!(f, "service {} does not exist", service),
+            Self::NoInways(service) => write!(f, "service {} has no available inways", service),
+            Self::NoEndpoints(service) => {
+                write!(f, "service {} has no available endpoints", service)
+            }
+        }
+    }
+}
+
+fn recover_proxy_error(component: Component, e: &ProxyError) -> Response {
+    match e {
+        ProxyError::CircuitOpen(_) => reply(
+            component,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "CIRCUIT_OPEN",
+            e.to_string(),
+        ),
+        ProxyError::Timeout { .. } => reply(
+            component,
+            StatusCode::GATEWAY_TIMEOUT,
+            "UPSTREAM_TIMEOUT",
+            e.to_string(),
+        ),
+        // For the outway the upstream is always an inway
+        ProxyError::Hyper(_) | ProxyError::MaxRetries(_) => {
+            let code = match component {
+                Component::Outway => "INWAY_UNREACHABLE",
+                Component::Inway if e.is_connection_refused() => "UPSTREAM_REFUSED",
+                Component::Inway => "UPSTREAM_UNREACHABLE",
+            };
+
+            reply(component, StatusCode::BAD_GATEWAY, code, e.to_string())
+        }
+    }
+}
+
+/// Adds a Retry-After header in whole seconds
+fn with_retry_after(mut response: Response, retry_after: Duration) -> Response {
+    // Rounded up, so clients don't retry too early
+    let seconds = retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64;
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+
+    response
+}
+
+/// Turns every rejection into an error response
+pub async fn recover(component: Component, rejection: Rejection) -> Result<Response, Rejection> {
+    if let Some(e) = rejection.find::<ProxyError>() {
+        return Ok(recover_proxy_error(component, e));
+    }
+
+    let response = if let Some(e) = rejection.find::<RouteError>() {
+        let (status, code) = match e {
+            RouteError::UnknownService(_) => (StatusCode::NOT_FOUND, "SERVICE_UNKNOWN"),
+            RouteError::NoInways(_) => (StatusCode::SERVICE_UNAVAILABLE, "NO_INWAYS_AVAILABLE"),
+            RouteError::NoEndpoints(_) => {
+                (StatusCode::SERVICE_UNAVAILABLE, "NO_ENDPOINTS_AVAILABLE")
+            }
+        };
+
+        reply(component, status, code, e.to_string())
+    } else if let Some(e) = rejection.find::<AccessDenied>() {
+        reply(
+            component,
+            StatusCode::FORBIDDEN,
+            "ACCESS_DENIED",
+            e.to_string(),
+        )
+    } else if let Some(e) = rejection.find::<BackendAuthError>() {
+        reply(
+            component,
+            StatusCode::BAD_GATEWAY,
+            "BACKEND_AUTH_FAILED",
+            e.to_string(),
+        )
+    } else if let Some(e) = rejection.find::<InMaintenance>() {
+        let response = reply(
+            component,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "SERVICE_MAINTENANCE",
+            e.to_string(),
+        );
+
+        with_retry_after(response, e.retry_after)
+    } else if let Some(e) = rejection.find::<RateLimited>() {
+        let code = match e.limit {
+            Limit::Rate => "RATE_LIMITED",
+            Limit::Quota => "QUOTA_EXCEEDED",
+        };
+        let response = reply(
+            component,
+            StatusCode::TOO_MANY_REQUESTS,
+            code,
+            e.to_string(),
+        );
+
+        with_retry_after(response, e.retry_after)
+    } else if let Some(e) = rejection.find::<InjectedFault>() {
+        reply(component, e.status, "FAULT_INJECTED", e.to_string())
+    } else if let Some(e) = rejection.find::<FixtureError>() {
+        let (status, code) = match e {
+            FixtureError::NotFound(_) => (StatusCode::NOT_FOUND, "FIXTURE_NOT_FOUND"),
+            FixtureError::Invalid(_) => (StatusCode::INTERNAL_SERVER_ERROR, "INVALID_FIXTURE"),
+        };
+
+        reply(component, status, code, e.to_string())
+    } else if let Some(e) = rejection.find::<IntoRequestError>() {
+        let code = match e {
+            IntoRequestError::InvalidPath(_) => "INVALID_PATH",
+            _ => "INVALID_REQUEST",
+        };
+
+        reply(component, StatusCode::BAD_REQUEST, code, e.to_string())
+    } else if rejection.is_not_found() {
+        reply(
+            component,
+            StatusCode::NOT_FOUND,
+            "NOT_FOUND",
+            "no route matches the request".to_string(),
+        )
+    } else if let Some(e) = rejection.find::<MethodNotAllowed>() {
+        reply(
+            component,
+            StatusCode::METHOD_NOT_ALLOWED,
+            "METHOD_NOT_ALLOWED",
+            e.to_string(),
+        )
+    } else if let Some(e) = rejection.find::<PayloadTooLarge>() {
+        reply(
+            component,
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "PAYLOAD_TOO_LARGE",
+            e.to_string(),
+        )
+    } else {
+        log::error!("unhandled rejection: {:?}", rejection);
+
+        reply(
+            component,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "INTERNAL_ERROR",
+            "internal error".to_string(),
+        )
+    };
+
+    Ok(response)
+}
@@ -0,0 +1,438 @@
+use std::{
+    fmt::{self, Display},
+    future::Future,
+    io,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http::StatusCode;
+use hyper::{body::HttpBody, Body};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use warp::{
+    filters::BoxedFilter,
+    reject::{self, Reject},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    errors::{self, Component},
+    metrics,
+};
+
+fn default_percentage() -> f64 {
+    100.0
+}
+
+fn default_chunk_size() -> usize {
+    1024
+}
+
+// Unknown fields are denied here rather than on `Fault`, serde ignores the attribute on a
+// struct with a flattened field. The fields `Fault` doesn't know end up in the kind.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum FaultKind {
+    /// Delays the request before it is proxied
+    Latency {
+        #[serde(with = "humantime_serde")]
+        delay: Duration,
+    },
+    /// Answers with the status instead of proxying the request
+    Abort { status: u16 },
+    /// Closes the connection (HTTP/1) or resets the stream (HTTP/2) before the response is
+    /// complete. A struct variant, so unknown fields are denied for it too.
+    Reset {},
+    /// Streams the response body in chunks with a pause before every chunk
+    SlowBody {
+        #[serde(with = "humantime_serde")]
+        interval: Duration,
+        #[serde(default = "default_chunk_size")]
+        chunk_size: usize,
+    },
+}
+
+impl FaultKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Latency { .. } => "latency",
+            Self::Abort { .. } => "abort",
+            Self::Reset {} => "reset",
+            Self::SlowBody { .. } => "slow_body",
+        }
+    }
+}
+
+/// Fault that is injected into a share of the requests, for resilience testing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fault {
+    /// Service the fault applies to, every service when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<String>,
+    /// Serial number of the organization the fault applies to, the caller for the inway and
+    /// the provider for the outway. Every organization when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+    /// Percentage of the matching requests the fault is injected into
+    #[serde(default = "default_percentage")]
+    pub percentage: f64,
+    #[serde(flatten)]
+    pub kind: FaultKind,
+}
+
+impl Fault {
+    fn validate(&self) -> Result<()> {
+        if !(0.0..=100.0).contains(&self.percentage) {
+            return Err(anyhow!("percentage must be between 0 and 100"));
+        }
+
+        match self.kind {
+            FaultKind::Abort { status } if !(400..=599).contains(&status) => Err(anyhow!(
+                "abort status must be an error status (400-599): {}",
+                status
+            )),
+            FaultKind::SlowBody { chunk_size: 0, .. } => {
+                Err(anyhow!("chunk size must be greater than zero"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn applies_to(&self, service: &str, organization: Option<&str>) -> bool {
+        !matches!(&self.service, Some(name) if name != service)
+            && !matches!(&self.organization, Some(serial) if Some(serial.as_str()) != organization)
+    }
+}
+
+/// The request was aborted by an injected fault
+#[derive(Debug)]
+pub struct InjectedFault {
+    pub status: StatusCode,
+}
+
+impl Reject for InjectedFault {}
+
+impl Display for InjectedFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request aborted by an injected fault")
+    }
+}
+
+/// Marks a response that is reset by an injected fault, the access log records status 0 for
+/// it as no complete response is sent
+#[derive(Debug, Clone, Copy)]
+pub struct Reset;
+
+/// Faults that are injected, set in the settings or through the admin API
+#[derive(Clone, Default)]
+pub struct Faults {
+    faults: Arc<RwLock<Vec<Fault>>>,
+}
+
+impl Faults {
+    pub fn new(faults: Vec<Fault>) -> Result<Self> {
+        for fault in &faults {
+            fault.validate()?;
+        }
+
+        if !faults.is_empty() {
+            log::warn!("injecting faults: {:?}", faults);
+        }
+
+        Ok(Self {
+            faults: Arc::new(RwLock::new(faults)),
+        })
+    }
+
+    pub fn list(&self) -> Vec<Fault> {
+        self.faults.read().unwrap().clone()
+    }
+
+    /// Replaces the faults, an empty list switches fault injection off
+    pub fn set(&self, faults: Vec<Fault>) -> Result<()> {
+        for fault in &faults {
+            fault.validate()?;
+        }
+
+        if faults.is_empty() {
+            log::info!("fault injection is switched off");
+        } else {
+            log::warn!("injecting faults: {:?}", faults);
+        }
+
+        *self.faults.write().unwrap() = faults;
+        Ok(())
+    }
+
+    /// Returns the fault to inject into a request, the first matching fault that is rolled wins
+    pub fn pick(&self, service: &str, organization: Option<&str>) -> Option<FaultKind> {
+        let faults = self.faults.read().unwrap();
+
+        if faults.is_empty() {
+            return None;
+        }
+
+        let mut rng = rand::thread_rng();
+        let fault = faults.iter().find(|fault| {
+            fault.applies_to(service, organization) && rng.gen::<f64>() * 100.0 < fault.percentage
+        })?;
+
+        metrics::FAULTS_INJECTED
+            .with_label_values(&[service, fault.kind.name()])
+            .inc();
+        log::debug!(
+            "injecting fault into request for {}: {:?}",
+            service,
+            fault.kind
+        );
+
+        Some(fault.kind.clone())
+    }
+}
+
+/// Returns a response of which the body fails right away. Over HTTP/1 hyper then closes the
+/// connection before the head is written. Over HTTP/2 the HEADERS frame with status 200 is
+/// already sent, the stream is reset with INTERNAL_ERROR before any data follows.
+fn reset() -> Response {
+    let error = io::Error::new(io::ErrorKind::ConnectionReset, "injected fault");
+    let body = Body::wrap_stream(futures_util::stream::once(
+        async move { Err::<Bytes, _>(error) },
+    ));
+    let mut response = Response::new(body);
+
+    response.extensions_mut().insert(Reset);
+    response
+}
+
+/// Forwards the body in chunks of at most `chunk_size` bytes with a pause before every chunk
+fn slow_body(mut body: Body, interval: Duration, chunk_size: usize) -> Body {
+    let (mut tx, rx) = Body::channel();
+
+    tokio::spawn(async move {
+        let pipe = async {
+            while let Some(mut data) = body.data().await.transpose()? {
+                while !data.is_empty() {
+                    let chunk = data.split_to(chunk_size.min(data.len()));
+
+                    time::sleep(interval).await;
+                    tx.send_data(chunk).await?;
+                }
+            }
+
+            if let Some(trailers) = body.trailers().await? {
+                tx.send_trailers(trailers).await?;
+            }
+
+            Ok::<_, hyper::Error>(())
+        };
+
+        if let Err(e) = pipe.await {
+            log::debug!("failed to stream slowed down body: {}", e);
+        }
+    });
+
+    rx
+}
+
+/// Answers the request for aborts and resets, which are injected before the request is
+/// proxied. The handlers return the result right away, so the request is never metered,
+/// rewritten or recorded.
+pub fn intercept(fault: Option<&FaultKind>) -> Option<Result<Response, Rejection>> {
+    match fault? {
+        FaultKind::Abort { status } => Some(Err(reject::custom(InjectedFault {
+            status: StatusCode::from_u16(*status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+        }))),
+        FaultKind::Reset {} => Some(Ok(reset())),
+        FaultKind::Latency { .. } | FaultKind::SlowBody { .. } => None,
+    }
+}
+
+/// Injects latency and slow bodies into a proxied request. Aborts and resets are handled by
+/// [`intercept`], which the handlers call first; they are proxied as is here.
+pub async fn inject<F>(fault: Option<FaultKind>, proxy: F) -> Result<Response, Rejection>
+where
+    F: Future<Output = Result<Response, Rejection>>,
+{
+    match fault {
+        Some(FaultKind::Latency { delay }) => {
+            time::sleep(delay).await;
+            proxy.await
+        }
+        Some(FaultKind::SlowBody {
+            interval,
+            chunk_size,
+        }) => {
+            let mut response = proxy.await?;
+            let body = std::mem::take(response.body_mut());
+            *response.body_mut() = slow_body(body, interval, chunk_size);
+
+            Ok(response)
+        }
+        _ => proxy.await,
+    }
+}
+
+/// Admin routes to list and replace the injected faults
+pub fn routes(faults: Faults, component: Component) -> BoxedFilter<(Response,)> {
+    let with_faults = warp::any().map(move || faults.clone());
+    let list = warp::get()
+        .and(warp::path("faults"))
+        .and(warp::path::end())
+        .and(with_faults.clone())
+        .map(|faults: Faults| warp::reply::json(&faults.list()).into_response());
+    let set = warp::put()
+        .and(warp::path("faults"))
+        .and(warp::path::end())
+        .and(warp::body::bytes())
+        .and(with_faults.clone())
+        .map(move |body: Bytes, faults: Faults| {
+            let result = serde_json::from_slice::<Vec<Fault>>(&body)
+                .map_err(anyhow::Error::from)
+                .and_then(|list| faults.set(list));
+
+            match result {
+                Ok(_) => StatusCode::NO_CONTENT.into_response(),
+                Err(e) => errors::reply(
+                    component,
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_REQUEST",
+                    e.to_string(),
+                ),
+            }
+        });
+    let clear = warp::delete()
+        .and(warp::path("faults"))
+        .and(warp::path::end())
+        .and(with_faults)
+        .map(|faults: Faults| {
+            faults.set(vec![]).expect("no faults are valid");
+            StatusCode::NO_CONTENT.into_response()
+        });
+
+    list.or(set).unify().or(clear).unify().boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fault(service: Option<&str>, organization: Option<&str>, percentage: f64) -> Fault {
+        Fault {
+            service: service.map(String::from),
+            organization: organization.map(String::from),
+            percentage,
+            kind: FaultKind::Abort { status: 503 },
+        }
+    }
+
+    #[test]
+    fn deserializes_faults() {
+        let fault: Fault =
+            serde_json::from_str(r#"{"type": "latency", "delay": "2s", "service": "kentekens"}"#)
+                .unwrap();
+
+        assert_eq!(fault.service.as_deref(), Some("kentekens"));
+        assert_eq!(fault.percentage, 100.0);
+        assert_eq!(
+            fault.kind,
+            FaultKind::Latency {
+                delay: Duration::from_secs(2)
+            }
+        );
+
+        let fault: Fault = serde_json::from_str(r#"{"type": "reset", "percentage": 5}"#).unwrap();
+
+        assert_eq!(fault.kind, FaultKind::Reset {});
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        for json in [
+            r#"{"type": "abort", "status": 503, "percentge": 5}"#,
+            r#"{"type": "reset", "percentge": 5}"#,
+            r#"{"type": "slow_body", "interval": "1s", "chunksize": 10}"#,
+        ] {
+            assert!(serde_json::from_str::<Fault>(json).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn validates_faults() {
+        assert!(fault(None, None, 50.0).validate().is_ok());
+        assert!(fault(None, None, 100.1).validate().is_err());
+        assert!(fault(None, None, -1.0).validate().is_err());
+
+        for status in [200, 204, 302, 600] {
+            let fault = Fault {
+                kind: FaultKind::Abort { status },
+                ..fault(None, None, 100.0)
+            };
+
+            assert!(fault.validate().is_err(), "{}", status);
+        }
+
+        let fault = Fault {
+            kind: FaultKind::SlowBody {
+                interval: Duration::from_secs(1),
+                chunk_size: 0,
+            },
+            ..fault(None, None, 100.0)
+        };
+
+        assert!(fault.validate().is_err());
+    }
+
+    #[test]
+    fn applies_to_matching_requests() {
+        assert!(fault(None, None, 100.0).applies_to("kentekens", None));
+        assert!(fault(Some("kentekens"), None, 100.0).applies_to("kentekens", Some("00000001")));
+        assert!(!fault(Some("kentekens"), None, 100.0).applies_to("brp", None));
+        assert!(fault(None, Some("00000001"), 100.0).applies_to("brp", Some("00000001")));
+        assert!(!fault(None, Some("00000001"), 100.0).applies_to("brp", Some("00000002")));
+        // Requests without a known organization don't match a fault for one
+        assert!(!fault(None, Some("00000001"), 100.0).applies_to("brp", None));
+    }
+
+    #[test]
+    fn picks_the_first_matching_fault() {
+        let faults = Faults::new(vec![
+            fault(Some("brp"), None, 100.0),
+            fault(Some("kentekens"), None, 0.0),
+            Fault {
+                kind: FaultKind::Reset {},
+                ..fault(None, None, 100.0)
+            },
+            fault(None, None, 100.0),
+        ])
+        .unwrap();
+
+        assert_eq!(
+            faults.pick("brp", None),
+            Some(FaultKind::Abort { status: 503 })
+        );
+        // The fault for kentekens is never rolled, the next matching one is picked
+        for _ in 0..100 {
+            assert_eq!(faults.pick("kentekens", None), Some(FaultKind::Reset {}));
+        }
+    }
+
+    #[test]
+    fn picks_nothing_without_matching_faults() {
+        assert_eq!(Faults::default().pick("brp", None), None);
+
+        let faults = Faults::new(vec![
+            fault(Some("kentekens"), None, 100.0),
+            fault(None, None, 0.0),
+        ])
+        .unwrap();
+
+        for _ in 0..100 {
+            assert_eq!(faults.pick("brp", None), None);
+        }
+    }
+}
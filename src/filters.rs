@@ -1,3 +1,7 @@
+use bytes::Buf;
+use futures_util::{Stream, TryStreamExt};
+use hyper::Body;
+
 macro_rules! with_request {
     () => {{
         let optional_query = warp::filters::query::raw()
@@ -9,11 +13,28 @@ macro_rules! with_request {
             .and(warp::filters::path::tail())
             .and(optional_query)
             .and(warp::header::headers_cloned())
-            .and(warp::body::bytes())
-            .map(|method, path: warp::path::Tail, query, headers, body| {
-                crate::reverse_proxy::Request::new(method, path, query, headers, body)
-            })
+            .and(warp::body::stream())
+            .and(warp::ext::optional::<crate::serve::Upgrade>())
+            .and(warp::ext::optional::<crate::serve::Peer>())
+            .map(
+                |method, path: warp::path::Tail, query, headers, body, upgrade, peer| {
+                    let body = crate::filters::into_body(body);
+                    let path = path.as_str().to_string();
+                    crate::reverse_proxy::Request::new(
+                        method, path, query, headers, body, upgrade, peer,
+                    )
+                },
+            )
     }};
 }
 
 pub(crate) use with_request;
+
+/// Turns the body stream of warp back into a body that can be sent with hyper
+pub fn into_body<S, B>(stream: S) -> Body
+where
+    S: Stream<Item = Result<B, warp::Error>> + Send + 'static,
+    B: Buf,
+{
+    Body::wrap_stream(stream.map_ok(|mut buf| buf.copy_to_bytes(buf.remaining())))
+}
@@ -0,0 +1,125 @@
+use http::{
+    header::{HeaderName, FORWARDED, VIA},
+    HeaderMap, HeaderValue, Version,
+};
+use serde::Deserialize;
+
+use crate::serve::Peer;
+
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+static X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+const PSEUDONYM: &str = "nlx-gateway";
+
+/// Headers that tell the upstream about the original client and protocol
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardingSettings {
+    /// Add the `Forwarded` header (RFC 7239)
+    pub forwarded: bool,
+    /// Add the `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` headers
+    pub x_forwarded: bool,
+    /// Add the `Via` header
+    pub via: bool,
+    /// Keep the forwarding headers that were sent by the client, otherwise they are replaced.
+    /// Only enable this when every client is trusted, e.g. not on an inway that is reached by
+    /// other organizations.
+    pub trust_incoming: bool,
+}
+
+impl Default for ForwardingSettings {
+    fn default() -> Self {
+        Self {
+            forwarded: true,
+            x_forwarded: true,
+            via: true,
+            trust_incoming: false,
+        }
+    }
+}
+
+fn protocol_version(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        Version::HTTP_3 => "3",
+        _ => "1.1",
+    }
+}
+
+/// Adds the value to the list of values in the header
+fn append(headers: &mut HeaderMap, name: &HeaderName, value: String) {
+    // Multiple header lines are combined into a single list
+    let mut values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|existing| existing.to_str().ok())
+        .filter(|existing| !existing.is_empty())
+        .collect::<Vec<_>>();
+    values.push(&value);
+
+    let value = values.join(", ");
+
+    match HeaderValue::try_from(value) {
+        Ok(value) => {
+            headers.insert(name, value);
+        }
+        Err(e) => log::debug!("invalid value for header {}: {}", name, e),
+    }
+}
+
+fn set_default(headers: &mut HeaderMap, name: &HeaderName, value: &str) {
+    if !headers.contains_key(name) {
+        if let Ok(value) = HeaderValue::try_from(value) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+/// Adds the forwarding headers for the hop from the peer to the upstream, the headers sent by
+/// an untrusted client are removed even if the peer is unknown
+pub fn add_headers(headers: &mut HeaderMap, peer: Option<&Peer>, settings: &ForwardingSettings) {
+    if !settings.trust_incoming {
+        for name in [
+            &FORWARDED,
+            &X_FORWARDED_FOR,
+            &X_FORWARDED_PROTO,
+            &X_FORWARDED_HOST,
+        ] {
+            headers.remove(name);
+        }
+    }
+
+    let peer = match peer {
+        Some(peer) => peer,
+        None => return,
+    };
+    let proto = if peer.tls { "https" } else { "http" };
+
+    if settings.forwarded {
+        // The address is quoted as it contains a port (and brackets for IPv6)
+        let mut value = format!("for=\"{}\";proto={}", peer.addr, proto);
+
+        if let Some(host) = &peer.host {
+            value.push_str(&format!(";host=\"{}\"", host));
+        }
+
+        append(headers, &FORWARDED, value);
+    }
+
+    if settings.x_forwarded {
+        append(headers, &X_FORWARDED_FOR, peer.addr.ip().to_string());
+        set_default(headers, &X_FORWARDED_PROTO, proto);
+
+        if let Some(host) = &peer.host {
+            set_default(headers, &X_FORWARDED_HOST, host);
+        }
+    }
+
+    if settings.via {
+        let value = format!("{} {}", protocol_version(peer.version), PSEUDONYM);
+        append(headers, &VIA, value);
+    }
+}
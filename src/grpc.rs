@@ -0,0 +1,91 @@
+use http::{
+    header::{CONTENT_TYPE, TE},
+    HeaderMap, HeaderValue, StatusCode,
+};
+use warp::reply::Response;
+
+const CONTENT_TYPE_GRPC: &str = "application/grpc";
+
+/// Status codes as defined in https://github.com/grpc/grpc/blob/master/doc/statuscodes.md
+#[derive(Debug, Clone, Copy)]
+pub enum Code {
+    Unknown = 2,
+    InvalidArgument = 3,
+    DeadlineExceeded = 4,
+    PermissionDenied = 7,
+    ResourceExhausted = 8,
+    Unimplemented = 12,
+    Internal = 13,
+    Unavailable = 14,
+    Unauthenticated = 16,
+}
+
+impl From<StatusCode> for Code {
+    fn from(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => Self::InvalidArgument,
+            StatusCode::UNAUTHORIZED => Self::Unauthenticated,
+            StatusCode::FORBIDDEN => Self::PermissionDenied,
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED => Self::Unimplemented,
+            StatusCode::TOO_MANY_REQUESTS => Self::ResourceExhausted,
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE => Self::Unavailable,
+            StatusCode::GATEWAY_TIMEOUT => Self::DeadlineExceeded,
+            status if status.is_server_error() => Self::Internal,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+pub fn is_grpc_content_type(value: &HeaderValue) -> bool {
+    value.as_bytes().starts_with(CONTENT_TYPE_GRPC.as_bytes())
+}
+
+/// Returns true if the request or response contains a gRPC message
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    matches!(headers.get(CONTENT_TYPE), Some(value) if is_grpc_content_type(value))
+}
+
+/// Returns true if the client indicated that it accepts trailers
+pub fn accepts_trailers(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// Percent-encodes the message as required for the `grpc-message` header
+fn encode_message(message: &str) -> String {
+    let mut encoded = String::with_capacity(message.len());
+
+    for byte in message.bytes() {
+        match byte {
+            b' '..=b'~' if byte != b'%' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+/// Creates a trailers-only response which is how gRPC servers report an error without a body
+pub fn error_response(code: Code, message: &str, metadata: &[(&'static str, &str)]) -> Response {
+    let mut response = Response::default();
+    let headers = response.headers_mut();
+
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE_GRPC));
+    headers.insert("grpc-status", HeaderValue::from(code as u16));
+
+    if let Ok(value) = HeaderValue::try_from(encode_message(message)) {
+        headers.insert("grpc-message", value);
+    }
+
+    for (name, value) in metadata {
+        if let Ok(value) = HeaderValue::try_from(*value) {
+            headers.insert(*name, value);
+        }
+    }
+
+    response
+}
@@ -0,0 +1,167 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use http::{StatusCode, Uri};
+use hyper::{client::connect::Connect, Body, Client};
+use serde::Serialize;
+use warp::{Filter, Rejection, Reply};
+
+use crate::{supervisor::Supervisor, tls::Validity};
+
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Inner {
+    config_received: AtomicBool,
+    registered: AtomicBool,
+}
+
+/// Keeps track of the conditions that need to be met before the gateway can serve traffic
+#[derive(Clone)]
+pub struct Readiness {
+    inner: Arc<Inner>,
+    certificate: Validity,
+}
+
+#[derive(Serialize)]
+pub struct Checks {
+    pub config: bool,
+    pub registration: bool,
+    pub certificate: bool,
+}
+
+impl Checks {
+    pub fn ok(&self) -> bool {
+        self.config && self.registration && self.certificate
+    }
+}
+
+impl Readiness {
+    pub fn new(certificate: Validity) -> Self {
+        Self {
+            inner: Arc::default(),
+            certificate,
+        }
+    }
+
+    pub fn set_config_received(&self) {
+        if !self.inner.config_received.swap(true, Ordering::Relaxed) {
+            log::info!("first config received");
+        }
+    }
+
+    pub fn set_registered(&self) {
+        if !self.inner.registered.swap(true, Ordering::Relaxed) {
+            log::info!("registration succeeded");
+        }
+    }
+
+    /// Passes the config and registration checks for a gateway that runs without the
+    /// directory and management API
+    pub fn set_standalone(&self) {
+        self.inner.config_received.store(true, Ordering::Relaxed);
+        self.inner.registered.store(true, Ordering::Relaxed);
+    }
+
+    pub fn checks(&self) -> Checks {
+        Checks {
+            config: self.inner.config_received.load(Ordering::Relaxed),
+            registration: self.inner.registered.load(Ordering::Relaxed),
+            certificate: self.certificate.is_valid(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Live {
+    pub live: bool,
+}
+
+#[derive(Serialize)]
+pub struct Ready {
+    pub ready: bool,
+    pub checks: Checks,
+}
+
+fn with_status(reply: impl Reply, ok: bool) -> impl Reply {
+    let status = if ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    warp::reply::with_status(reply, status)
+}
+
+/// Liveness and readiness endpoints which are shared by the inway and outway
+pub fn routes(
+    readiness: Readiness,
+    supervisor: Supervisor,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let live = warp::path("live").and(warp::path::end()).map(move || {
+        let live = supervisor.is_healthy();
+        with_status(warp::reply::json(&Live { live }), live)
+    });
+    let ready = warp::path("ready").and(warp::path::end()).map(move || {
+        let checks = readiness.checks();
+        let ready = checks.ok();
+
+        with_status(warp::reply::json(&Ready { ready, checks }), ready)
+    });
+
+    warp::get().and(warp::path(".nlx")).and(live.or(ready))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Probe {
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
+
+/// Sends a GET request to the given URI and checks the response status
+pub async fn probe<C>(
+    http: &Client<C>,
+    uri: Uri,
+    timeout: Duration,
+    expect: impl Fn(StatusCode) -> bool,
+) -> Probe
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let start = Instant::now();
+    let request = hyper::Request::get(uri)
+        .body(Body::empty())
+        .expect("probe request is valid");
+    let result = tokio::time::timeout(timeout, http.request(request)).await;
+    let latency_ms = start.elapsed().as_millis() as u64;
+
+    match result {
+        Ok(Ok(response)) => Probe {
+            healthy: expect(response.status()),
+            status: Some(response.status().as_u16()),
+            error: None,
+            latency_ms,
+        },
+        Ok(Err(e)) => Probe {
+            healthy: false,
+            status: None,
+            error: Some(e.to_string()),
+            latency_ms,
+        },
+        Err(_) => Probe {
+            healthy: false,
+            status: None,
+            error: Some("timeout".to_string()),
+            latency_ms,
+        },
+    }
+}
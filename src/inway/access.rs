@@ -0,0 +1,175 @@
+use std::fmt::{self, Display};
+
+use warp::reject::Reject;
+
+use crate::tls::PeerIdentity;
+
+use super::config::Route;
+
+/// The caller has no access grant for the service
+#[derive(Debug)]
+pub enum AccessDenied {
+    /// The caller didn't present a certificate with the serial number of its organization
+    NoIdentity(String),
+    /// The organization of the caller was not granted access to the service with the key
+    /// of its certificate
+    NotAuthorized {
+        service: String,
+        serial_number: String,
+        public_key_hash: String,
+    },
+}
+
+impl Reject for AccessDenied {}
+
+impl Display for AccessDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoIdentity(service) => write!(
+                f,
+                "access to service {} requires the serial number of the organization in the client certificate",
+                service
+            ),
+            Self::NotAuthorized {
+                service,
+                serial_number,
+                public_key_hash,
+            } => write!(
+                f,
+                "organization {} with public key hash {} has no access to service {}",
+                serial_number, public_key_hash, service
+            ),
+        }
+    }
+}
+
+/// Checks that the organization of the caller was granted access to the service for the
+/// public key of its certificate, a grant belongs to the key of one outway
+pub fn check(
+    service: &str,
+    route: &Route,
+    caller: Option<&PeerIdentity>,
+) -> Result<(), AccessDenied> {
+    let (serial_number, public_key_hash) = caller
+        .and_then(|caller| {
+            Some((
+                caller.serial_number.as_deref()?,
+                caller.public_key_hash.as_deref()?,
+            ))
+        })
+        .ok_or_else(|| AccessDenied::NoIdentity(service.to_string()))?;
+
+    if route.authorizations.iter().any(|authorization| {
+        authorization.serial_number == serial_number
+            && authorization.public_key_hash == public_key_hash
+    }) {
+        Ok(())
+    } else {
+        Err(AccessDenied::NotAuthorized {
+            service: service.to_string(),
+            serial_number: serial_number.to_string(),
+            public_key_hash: public_key_hash.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inway::config::Authorization;
+
+    const KEY: &str = "nDZ7q9ZT7ZSbqG0Yt4ML7iD5YnP4lW4BVzUTY0bHjx0=";
+    const OTHER_KEY: &str = "2jmj7l5rSw0yVb/vlWAYkK/YBwk5mY1xvgWOHYqwgBY=";
+
+    fn route(grants: &[(&str, &str)]) -> Route {
+        Route {
+            endpoints: vec![],
+            authorizations: grants
+                .iter()
+                .map(|(serial_number, public_key_hash)| Authorization {
+                    serial_number: serial_number.to_string(),
+                    organization_name: String::new(),
+                    public_key_hash: public_key_hash.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn caller(serial_number: Option<&str>, public_key_hash: &str) -> PeerIdentity {
+        PeerIdentity {
+            serial_number: serial_number.map(String::from),
+            public_key_hash: Some(public_key_hash.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn allows_authorized_keys() {
+        let route = route(&[("00000001", OTHER_KEY), ("00000002", KEY)]);
+
+        assert!(check(
+            "basisregister",
+            &route,
+            Some(&caller(Some("00000002"), KEY))
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn denies_other_organizations() {
+        let caller = caller(Some("00000002"), KEY);
+
+        assert!(matches!(
+            check("basisregister", &route(&[("00000001", KEY)]), Some(&caller)),
+            Err(AccessDenied::NotAuthorized { .. })
+        ));
+        assert!(matches!(
+            check("basisregister", &route(&[]), Some(&caller)),
+            Err(AccessDenied::NotAuthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn denies_other_keys_of_an_authorized_organization() {
+        let route = route(&[("00000001", KEY)]);
+
+        for public_key_hash in [OTHER_KEY, ""] {
+            assert!(matches!(
+                check(
+                    "basisregister",
+                    &route,
+                    Some(&caller(Some("00000001"), public_key_hash))
+                ),
+                Err(AccessDenied::NotAuthorized { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn denies_grants_without_key() {
+        let route = route(&[("00000001", "")]);
+
+        assert!(matches!(
+            check(
+                "basisregister",
+                &route,
+                Some(&caller(Some("00000001"), KEY))
+            ),
+            Err(AccessDenied::NotAuthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn denies_callers_without_identity() {
+        let route = route(&[("00000001", KEY)]);
+
+        assert!(matches!(
+            check("basisregister", &route, None),
+            Err(AccessDenied::NoIdentity(_))
+        ));
+        assert!(matches!(
+            check("basisregister", &route, Some(&caller(None, KEY))),
+            Err(AccessDenied::NoIdentity(_))
+        ));
+    }
+}
@@ -0,0 +1,159 @@
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use http::StatusCode;
+use serde::Serialize;
+use warp::{filters::BoxedFilter, reply::Response, Filter, Reply};
+
+use crate::{
+    circuit_breaker::{CircuitBreakers, CircuitState},
+    errors::{self, Component},
+    faults::{self, Faults},
+};
+
+use super::{
+    health_check::BackendHealthState,
+    maintenance::{Maintenance, MaintenanceMode},
+    server::ServiceInwayMapState,
+};
+
+#[derive(Serialize)]
+struct RouteEndpoint {
+    url: String,
+    weight: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    healthy: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    circuit: Option<CircuitState>,
+}
+
+#[derive(Serialize)]
+struct Route {
+    endpoints: Vec<RouteEndpoint>,
+    maintenance: bool,
+}
+
+async fn routing_table(
+    state: &ServiceInwayMapState,
+    breakers: &CircuitBreakers,
+    backend_health: &BackendHealthState,
+    maintenance: &Maintenance,
+) -> BTreeMap<String, Route> {
+    let routes = state.read().await;
+    let backend_health = backend_health.read().unwrap();
+    let in_maintenance = maintenance.services();
+
+    routes
+        .iter()
+        .map(|(service, route)| {
+            let health = backend_health.get(service);
+            let endpoints = route
+                .endpoints
+                .iter()
+                .map(|endpoint| RouteEndpoint {
+                    url: endpoint.url.clone(),
+                    weight: endpoint.weight,
+                    healthy: health
+                        .and_then(|health| health.get(&endpoint.url))
+                        .map(|health| health.healthy),
+                    circuit: breakers.state(&endpoint.circuit_name(service)),
+                })
+                .collect();
+            let route = Route {
+                endpoints,
+                maintenance: matches!(in_maintenance.get(service), Some(status) if status.active),
+            };
+
+            (service.clone(), route)
+        })
+        .collect()
+}
+
+/// Admin routes to inspect the routing table, switch the maintenance mode of services and
+/// inject faults
+pub fn routes(
+    state: ServiceInwayMapState,
+    breakers: CircuitBreakers,
+    backend_health: BackendHealthState,
+    maintenance: Maintenance,
+    faults: Faults,
+) -> BoxedFilter<(Response,)> {
+    let with_maintenance = warp::any().map(move || maintenance.clone());
+    let routing = warp::get()
+        .and(warp::path("routes"))
+        .and(warp::path::end())
+        .and(warp::any().map(move || state.clone()))
+        .and(warp::any().map(move || breakers.clone()))
+        .and(warp::any().map(move || backend_health.clone()))
+        .and(with_maintenance.clone())
+        .then(
+            |state: ServiceInwayMapState,
+             breakers: CircuitBreakers,
+             backend_health: BackendHealthState,
+             maintenance: Maintenance| async move {
+                let table = routing_table(&state, &breakers, &backend_health, &maintenance).await;
+                warp::reply::json(&table).into_response()
+            },
+        );
+    let list = warp::get()
+        .and(warp::path("maintenance"))
+        .and(warp::path::end())
+        .and(with_maintenance.clone())
+        .map(|maintenance: Maintenance| warp::reply::json(&maintenance.services()).into_response());
+    let enable = warp::put()
+        .and(warp::path("maintenance"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::body::bytes())
+        .and(with_maintenance.clone())
+        .map(|service: String, body: Bytes, maintenance: Maintenance| {
+            // The body is optional, without it the default message is used
+            let mode = if body.is_empty() {
+                Ok(MaintenanceMode::default())
+            } else {
+                serde_json::from_slice::<MaintenanceMode>(&body).map_err(anyhow::Error::from)
+            }
+            .and_then(|mode| mode.validate().map(|_| mode));
+
+            match mode {
+                Ok(mode) => {
+                    maintenance.enable(service, mode);
+                    StatusCode::NO_CONTENT.into_response()
+                }
+                Err(e) => errors::reply(
+                    Component::Inway,
+                    StatusCode::BAD_REQUEST,
+                    "INVALID_REQUEST",
+                    e.to_string(),
+                ),
+            }
+        });
+    let disable = warp::delete()
+        .and(warp::path("maintenance"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(with_maintenance)
+        .map(|service: String, maintenance: Maintenance| {
+            if maintenance.disable(&service) {
+                StatusCode::NO_CONTENT.into_response()
+            } else {
+                errors::reply(
+                    Component::Inway,
+                    StatusCode::NOT_FOUND,
+                    "NOT_IN_MAINTENANCE",
+                    format!("service {} is not in maintenance", service),
+                )
+            }
+        });
+
+    routing
+        .or(list)
+        .unify()
+        .or(enable)
+        .unify()
+        .or(disable)
+        .unify()
+        .or(faults::routes(faults, Component::Inway))
+        .unify()
+        .boxed()
+}
@@ -0,0 +1,257 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Context, Result};
+use http::{header::CONTENT_TYPE, HeaderValue, Method, StatusCode};
+use hyper::{client::connect::Connect, Body, Client};
+use serde::Deserialize;
+use tokio::{fs, sync::Mutex, time};
+use warp::reject::Reject;
+
+use super::settings::InwaySettings;
+
+/// Secret that is read from a file or an environment variable, so it doesn't end up in the
+/// settings file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Secret {
+    File { file: PathBuf },
+    Env { env: String },
+}
+
+impl Secret {
+    async fn read(&self) -> Result<String> {
+        match self {
+            Self::File { file } => fs::read_to_string(file)
+                .await
+                .map(|secret| secret.trim_end().to_string())
+                .with_context(|| format!("failed to read secret from {}", file.display())),
+            Self::Env { env } => std::env::var(env).with_context(|| {
+                format!("failed to read secret from environment variable {}", env)
+            }),
+        }
+    }
+}
+
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn default_refresh_before() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Credentials that are added to every request to the backend of a service
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum BackendAuth {
+    Basic {
+        username: String,
+        password: Secret,
+    },
+    Bearer {
+        token: Secret,
+    },
+    /// OAuth2 client credentials grant
+    ClientCredentials {
+        token_url: String,
+        client_id: String,
+        client_secret: Secret,
+        scope: Option<String>,
+        /// Tokens are refreshed this long before they expire
+        #[serde(default = "default_refresh_before", with = "humantime_serde")]
+        refresh_before: Duration,
+    },
+}
+
+/// Failed to obtain credentials for the backend
+#[derive(Debug)]
+pub struct BackendAuthError {
+    pub service: String,
+    pub reason: String,
+}
+
+impl Reject for BackendAuthError {}
+
+impl Display for BackendAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "failed to obtain credentials for service {}: {}",
+            self.service, self.reason
+        )
+    }
+}
+
+/// Creates the value of an `Authorization` header which is kept out of the logs
+fn authorization(scheme: &str, credentials: &str) -> Result<HeaderValue> {
+    let mut value = HeaderValue::try_from(format!("{} {}", scheme, credentials))?;
+    value.set_sensitive(true);
+
+    Ok(value)
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+struct Token {
+    value: HeaderValue,
+    refresh_at: Option<Instant>,
+}
+
+struct TokenSource<C> {
+    http: Client<C>,
+    token_url: String,
+    form: String,
+    refresh_before: Duration,
+    token: Mutex<Option<Token>>,
+}
+
+impl<C> TokenSource<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    async fn fetch(&self) -> Result<Token> {
+        let request = hyper::Request::builder()
+            .method(Method::POST)
+            .uri(&self.token_url)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(self.form.clone()))?;
+        let response = time::timeout(TOKEN_TIMEOUT, self.http.request(request))
+            .await
+            .map_err(|_| anyhow!("token endpoint did not respond in time"))??;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+
+        if status != StatusCode::OK {
+            return Err(anyhow!("token endpoint responded with {}", status));
+        }
+
+        let response: TokenResponse = serde_json::from_slice(&body)?;
+        let value = authorization("Bearer", &response.access_token)?;
+        let refresh_at = response.expires_in.map(|expires_in| {
+            Instant::now() + Duration::from_secs(expires_in).saturating_sub(self.refresh_before)
+        });
+
+        Ok(Token { value, refresh_at })
+    }
+
+    /// Returns the cached token or fetches a new one when it (almost) expired, concurrent
+    /// requests wait for the same fetch
+    async fn get(&self) -> Result<HeaderValue> {
+        let mut token = self.token.lock().await;
+
+        match &*token {
+            Some(Token {
+                value,
+                refresh_at: None,
+            }) => return Ok(value.clone()),
+            Some(Token {
+                value,
+                refresh_at: Some(refresh_at),
+            }) if Instant::now() < *refresh_at => return Ok(value.clone()),
+            _ => {}
+        }
+
+        log::debug!("fetching access token from {}", self.token_url);
+
+        let new_token = self.fetch().await?;
+        let value = new_token.value.clone();
+        *token = Some(new_token);
+
+        Ok(value)
+    }
+}
+
+enum Credentials<C> {
+    Static(HeaderValue),
+    Token(Box<TokenSource<C>>),
+}
+
+/// Credentials per service, secrets are read once when the inway starts
+pub struct BackendCredentials<C> {
+    services: HashMap<String, Credentials<C>>,
+}
+
+impl<C> BackendCredentials<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    /// Reads the secrets of every service, `token_client` returns the client for the token
+    /// endpoint of a service
+    pub async fn load(
+        settings: &InwaySettings,
+        token_client: impl Fn(&str) -> Client<C>,
+    ) -> Result<Self> {
+        let mut services = HashMap::new();
+
+        for (service, settings) in &settings.services {
+            let credentials = match &settings.auth {
+                Some(BackendAuth::Basic { username, password }) => {
+                    let password = password.read().await?;
+                    let encoded = base64::encode(format!("{}:{}", username, password));
+
+                    Credentials::Static(authorization("Basic", &encoded)?)
+                }
+                Some(BackendAuth::Bearer { token }) => {
+                    let token = token.read().await?;
+
+                    Credentials::Static(authorization("Bearer", &token)?)
+                }
+                Some(BackendAuth::ClientCredentials {
+                    token_url,
+                    client_id,
+                    client_secret,
+                    scope,
+                    refresh_before,
+                }) => {
+                    let client_secret = client_secret.read().await?;
+                    let mut form = vec![
+                        ("grant_type", "client_credentials"),
+                        ("client_id", client_id.as_str()),
+                        ("client_secret", &client_secret),
+                    ];
+
+                    if let Some(scope) = scope {
+                        form.push(("scope", scope.as_str()));
+                    }
+
+                    Credentials::Token(Box::new(TokenSource {
+                        http: token_client(service),
+                        token_url: token_url.clone(),
+                        form: serde_urlencoded::to_string(form)?,
+                        refresh_before: *refresh_before,
+                        token: Mutex::default(),
+                    }))
+                }
+                None => continue,
+            };
+
+            services.insert(service.clone(), credentials);
+        }
+
+        Ok(Self { services })
+    }
+
+    /// Returns the value of the `Authorization` header for the backend of the service
+    pub async fn authorization(
+        &self,
+        service: &str,
+    ) -> Result<Option<HeaderValue>, BackendAuthError> {
+        match self.services.get(service) {
+            Some(Credentials::Static(value)) => Ok(Some(value.clone())),
+            Some(Credentials::Token(source)) => {
+                source.get().await.map(Some).map_err(|e| BackendAuthError {
+                    service: service.to_string(),
+                    reason: e.to_string(),
+                })
+            }
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, Context as _, Result};
+use http::{uri::Scheme, Uri};
+use hyper::{client::HttpConnector, service::Service, Client};
+use hyper_rustls::MaybeHttpsStream;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, PrivateKey, RootCertStore, ServerName,
+};
+use serde::Deserialize;
+use tokio::{fs, net::TcpStream};
+use tokio_rustls::TlsConnector;
+
+use super::settings::InwaySettings;
+use crate::{
+    client_pool::{ClientKey, ClientPool, Protocol},
+    trace::TracedConnector,
+};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// TLS settings for the connections to the backend of a service
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendTls {
+    /// PEM bundle with CA certificates that are trusted in addition to the native roots
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate (chain) for backends that require mutual TLS
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    /// Name that is sent (SNI) and verified instead of the host of the endpoint URL
+    pub server_name: Option<String>,
+    /// Accept any certificate of the backend, only meant for lab setups
+    pub insecure: bool,
+}
+
+async fn read_pem(path: &Path) -> Result<Vec<pem::Pem>> {
+    let contents = fs::read(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+
+    pem::parse_many(contents).with_context(|| format!("invalid PEM in {}", path.display()))
+}
+
+/// Skips the verification of server certificates
+struct NoVerification;
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+impl BackendTls {
+    /// Returns the settings without the server name, which doesn't affect the client config
+    fn without_server_name(&self) -> Self {
+        Self {
+            server_name: None,
+            ..self.clone()
+        }
+    }
+
+    async fn client_config(&self) -> Result<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        let native = rustls_native_certs::load_native_certs()
+            .context("failed to load native root certificates")?
+            .into_iter()
+            .map(|cert| cert.0)
+            .collect::<Vec<_>>();
+        roots.add_parsable_certificates(&native);
+
+        if let Some(ca_file) = &self.ca_file {
+            for pem in read_pem(ca_file).await? {
+                roots.add(&Certificate(pem.contents))?;
+            }
+        }
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut config = match (&self.cert_file, &self.key_file) {
+            (Some(cert_file), Some(key_file)) => {
+                let cert_chain = read_pem(cert_file)
+                    .await?
+                    .into_iter()
+                    .map(|pem| Certificate(pem.contents))
+                    .collect();
+                let key = read_pem(key_file)
+                    .await?
+                    .into_iter()
+                    .next()
+                    .map(|pem| PrivateKey(pem.contents))
+                    .ok_or_else(|| anyhow!("no key found in {}", key_file.display()))?;
+
+                builder.with_single_cert(cert_chain, key)?
+            }
+            (None, None) => builder.with_no_client_auth(),
+            _ => return Err(anyhow!("both cert_file and key_file are required")),
+        };
+        config.enable_early_data = true;
+
+        if self.insecure {
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoVerification));
+        }
+
+        Ok(config)
+    }
+}
+
+/// Connects to backends over plain TCP or TLS, like `hyper_rustls::HttpsConnector` but with
+/// an optional fixed server name
+#[derive(Clone)]
+pub struct BackendConnector {
+    http: HttpConnector,
+    tls: Arc<ClientConfig>,
+    server_name: Option<ServerName>,
+}
+
+impl Service<Uri> for BackendConnector {
+    type Response = MaybeHttpsStream<TcpStream>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.http.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let tls = match uri.scheme() {
+            Some(scheme) if *scheme == Scheme::HTTPS => {
+                let host = uri.host().unwrap_or_default().trim_matches(['[', ']']);
+                let server_name = match &self.server_name {
+                    Some(server_name) => Ok(server_name.clone()),
+                    None => ServerName::try_from(host),
+                };
+
+                Some((TlsConnector::from(Arc::clone(&self.tls)), server_name))
+            }
+            _ => None,
+        };
+        let connecting = self.http.call(uri);
+
+        Box::pin(async move {
+            let tcp = connecting.await?;
+
+            match tls {
+                Some((connector, server_name)) => {
+                    let stream = connector.connect(server_name?, tcp).await?;
+                    Ok(MaybeHttpsStream::Https(stream))
+                }
+                None => Ok(MaybeHttpsStream::Http(tcp)),
+            }
+        })
+    }
+}
+
+/// Connection settings of a backend that need a separate client
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct BackendKey {
+    client: ClientKey,
+    tls: BackendTls,
+}
+
+/// Pooled clients for the backends of services, there is a separate client for every set of
+/// TLS settings
+#[derive(Clone)]
+pub struct BackendClients {
+    pool: ClientPool<BackendKey, TracedConnector<BackendConnector>>,
+    settings: Arc<InwaySettings>,
+}
+
+impl BackendClients {
+    /// Loads the certificates of every service, so invalid settings are detected at startup
+    pub async fn load(settings: Arc<InwaySettings>) -> Result<Self> {
+        let mut configs = HashMap::new();
+        let default_tls = BackendTls::default();
+
+        for (name, tls) in settings
+            .services
+            .iter()
+            .filter_map(|(name, service)| Some((name.as_str(), service.tls.as_ref()?)))
+            .chain([("", &default_tls)])
+        {
+            if tls.insecure {
+                log::warn!("certificates of the backend of {} are not verified", name);
+            }
+
+            let key = tls.without_server_name();
+
+            if configs.contains_key(&key) {
+                continue;
+            }
+
+            if let Some(server_name) = &tls.server_name {
+                ServerName::try_from(server_name.as_str())
+                    .map_err(|_| anyhow!("invalid server name: {}", server_name))?;
+            }
+
+            let config = tls.client_config().await?;
+            configs.insert(key, config);
+        }
+
+        let pool = ClientPool::new(move |key: &BackendKey| {
+            let mut tls = configs[&key.tls.without_server_name()].clone();
+            tls.alpn_protocols = match key.client.protocol {
+                Protocol::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+                Protocol::Http1 => vec![b"http/1.1".to_vec()],
+                Protocol::Http2 => vec![b"h2".to_vec()],
+            };
+
+            let mut http = HttpConnector::new();
+            http.enforce_http(false);
+            http.set_connect_timeout(key.client.connect_timeout);
+
+            let connector = BackendConnector {
+                http,
+                tls: Arc::new(tls),
+                server_name: key
+                    .tls
+                    .server_name
+                    .as_deref()
+                    .and_then(|name| ServerName::try_from(name).ok()),
+            };
+
+            Client::builder()
+                .retry_canceled_requests(true)
+                .http2_adaptive_window(true)
+                .http2_only(key.client.protocol == Protocol::Http2)
+                .build(TracedConnector::new(connector))
+        });
+
+        Ok(Self { pool, settings })
+    }
+
+    /// Returns the client for the backend of the service
+    pub fn get(
+        &self,
+        service: &str,
+        protocol: Protocol,
+    ) -> Client<TracedConnector<BackendConnector>> {
+        let settings = self.settings.services.get(service);
+
+        self.pool.get(&BackendKey {
+            client: ClientKey {
+                connect_timeout: self.settings.timeouts(service).connect,
+                protocol,
+            },
+            tls: settings
+                .and_then(|settings| settings.tls.clone())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Returns the client for the token endpoint of the service, which has the TLS settings
+    /// of the service as the identity provider is often behind the same (internal) CA. The
+    /// server name only applies to the backend.
+    pub fn token_client(&self, service: &str) -> Client<TracedConnector<BackendConnector>> {
+        let tls = self
+            .settings
+            .services
+            .get(service)
+            .and_then(|settings| settings.tls.as_ref())
+            .map(BackendTls::without_server_name)
+            .unwrap_or_default();
+
+        self.pool.get(&BackendKey {
+            client: ClientKey {
+                connect_timeout: self.settings.timeouts(service).connect,
+                protocol: Protocol::Auto,
+            },
+            tls,
+        })
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use rand::Rng;
+
+use super::config::Endpoint;
+
+/// Selects the endpoint for a request by weight. Endpoints of which the circuit is open are
+/// skipped, as are endpoints that failed their health check. When that leaves no endpoint,
+/// the health check is ignored first and the circuits after that. With a sticky key the same
+/// endpoint is selected for the same key as long as the set of candidates doesn't change.
+pub fn select<'a>(
+    endpoints: &'a [Endpoint],
+    is_healthy: impl Fn(&Endpoint) -> bool,
+    is_available: impl Fn(&Endpoint) -> bool,
+    sticky_key: Option<&str>,
+) -> Option<&'a Endpoint> {
+    let weighted = endpoints
+        .iter()
+        .filter(|endpoint| endpoint.weight > 0)
+        .collect::<Vec<_>>();
+    let available = weighted
+        .iter()
+        .copied()
+        .filter(|endpoint| is_available(endpoint))
+        .collect::<Vec<_>>();
+    let healthy = available
+        .iter()
+        .copied()
+        .filter(|endpoint| is_healthy(endpoint))
+        .collect::<Vec<_>>();
+    let candidates = [healthy, available, weighted]
+        .into_iter()
+        .find(|candidates| !candidates.is_empty())?;
+
+    if let [endpoint] = candidates[..] {
+        return Some(endpoint);
+    }
+
+    let total = candidates
+        .iter()
+        .map(|endpoint| u64::from(endpoint.weight))
+        .sum::<u64>();
+    let mut point = match sticky_key {
+        Some(key) => {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            hasher.finish() % total
+        }
+        None => rand::thread_rng().gen_range(0..total),
+    };
+
+    for endpoint in candidates {
+        let weight = u64::from(endpoint.weight);
+
+        if point < weight {
+            return Some(endpoint);
+        }
+
+        point -= weight;
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn endpoints(weights: &[u32]) -> Vec<Endpoint> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(i, weight)| Endpoint {
+                url: format!("http://backend-{}", i),
+                weight: *weight,
+            })
+            .collect()
+    }
+
+    fn url(endpoint: Option<&Endpoint>) -> Option<&str> {
+        endpoint.map(|endpoint| endpoint.url.as_str())
+    }
+
+    #[test]
+    fn spreads_requests_by_weight() {
+        let endpoints = endpoints(&[3, 1, 0]);
+        let mut counts = HashMap::new();
+
+        for _ in 0..10_000 {
+            let endpoint = select(&endpoints, |_| true, |_| true, None).unwrap();
+            *counts.entry(endpoint.url.clone()).or_insert(0) += 1;
+        }
+
+        let first = counts["http://backend-0"];
+        let second = counts["http://backend-1"];
+
+        assert!((7000..8000).contains(&first), "{}", first);
+        assert!((2000..3000).contains(&second), "{}", second);
+        assert!(!counts.contains_key("http://backend-2"));
+    }
+
+    #[test]
+    fn returns_none_without_weight() {
+        assert_eq!(select(&endpoints(&[]), |_| true, |_| true, None), None);
+        assert_eq!(select(&endpoints(&[0, 0]), |_| true, |_| true, None), None);
+    }
+
+    #[test]
+    fn sticky_key_selects_the_same_endpoint() {
+        let endpoints = endpoints(&[1, 1, 1, 1]);
+
+        for key in ["00000001", "00000002", "00000003"] {
+            let first = select(&endpoints, |_| true, |_| true, Some(key));
+
+            for _ in 0..100 {
+                assert_eq!(select(&endpoints, |_| true, |_| true, Some(key)), first);
+            }
+        }
+    }
+
+    #[test]
+    fn sticky_keys_are_spread_by_weight() {
+        let endpoints = endpoints(&[9, 1]);
+        let canary = (0..1000)
+            .filter(|i| {
+                let key = format!("{:020}", i);
+                url(select(&endpoints, |_| true, |_| true, Some(&key))) == Some("http://backend-1")
+            })
+            .count();
+
+        assert!((50..150).contains(&canary), "{}", canary);
+    }
+
+    #[test]
+    fn skips_unhealthy_endpoints() {
+        let endpoints = endpoints(&[1, 1]);
+        let is_healthy = |endpoint: &Endpoint| endpoint.url == "http://backend-1";
+
+        for _ in 0..100 {
+            let endpoint = select(&endpoints, is_healthy, |_| true, None);
+            assert_eq!(url(endpoint), Some("http://backend-1"));
+        }
+    }
+
+    #[test]
+    fn skips_endpoints_with_an_open_circuit() {
+        let endpoints = endpoints(&[1, 1]);
+        let is_available = |endpoint: &Endpoint| endpoint.url == "http://backend-0";
+
+        for _ in 0..100 {
+            let endpoint = select(&endpoints, |_| true, is_available, Some("00000001"));
+            assert_eq!(url(endpoint), Some("http://backend-0"));
+        }
+    }
+
+    #[test]
+    fn prefers_a_closed_circuit_over_a_passed_health_check() {
+        let endpoints = endpoints(&[1, 1]);
+        let is_healthy = |endpoint: &Endpoint| endpoint.url == "http://backend-0";
+        let is_available = |endpoint: &Endpoint| endpoint.url == "http://backend-1";
+
+        for _ in 0..100 {
+            let endpoint = select(&endpoints, is_healthy, is_available, None);
+            assert_eq!(url(endpoint), Some("http://backend-1"));
+        }
+    }
+
+    #[test]
+    fn falls_back_on_every_endpoint() {
+        let endpoints = endpoints(&[1, 0]);
+
+        assert_eq!(
+            url(select(&endpoints, |_| false, |_| false, None)),
+            Some("http://backend-0")
+        );
+    }
+}
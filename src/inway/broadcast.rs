@@ -1,12 +1,12 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use async_channel::Receiver;
-use tokio::{task::JoinHandle, time};
-use tonic::{transport::Channel, Request};
+use tokio::{sync::Notify, time};
+use tonic::{async_trait, Request};
 
 use crate::{
-    backoff::retry_backoff,
+    health::Readiness,
     pb::{
         directory::{
             directory_client::DirectoryClient, register_inway_request::RegisterService,
@@ -14,12 +14,17 @@ use crate::{
         },
         management::{management_client::ManagementClient, Inway},
     },
+    supervisor::Task,
+    trace::TracedChannel,
+    VERSION,
 };
 
-use super::Config;
+use super::{
+    health_check::{self, BackendHealthState},
+    Config,
+};
 
 const BROADCAST_INTERVAL: Duration = Duration::from_secs(10);
-const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 fn get_hostname() -> Result<String> {
     hostname::get()
@@ -33,25 +38,49 @@ fn get_hostname() -> Result<String> {
 pub struct Broadcast {
     inway_name: String,
     inway_address: String,
-    management: ManagementClient<Channel>,
-    directory: DirectoryClient<Channel>,
+    management: ManagementClient<TracedChannel>,
+    directory: DirectoryClient<TracedChannel>,
+    rx: Receiver<Config>,
+    config: Option<Config>,
+    readiness: Readiness,
+    backend_health: Option<BackendHealthState>,
+    register: Arc<Notify>,
 }
 
 impl Broadcast {
     pub fn new(
-        management: ManagementClient<Channel>,
-        directory: DirectoryClient<Channel>,
+        management: ManagementClient<TracedChannel>,
+        directory: DirectoryClient<TracedChannel>,
         inway_name: String,
         inway_address: String,
+        rx: Receiver<Config>,
+        readiness: Readiness,
     ) -> Self {
         Self {
             inway_name,
             inway_address,
             management,
             directory,
+            rx,
+            config: None,
+            readiness,
+            backend_health: None,
+            register: Arc::default(),
         }
     }
 
+    /// Leave services with an unhealthy backend out of the announcement
+    pub fn omit_unhealthy(mut self, backend_health: BackendHealthState) -> Self {
+        self.backend_health = Some(backend_health);
+        self
+    }
+
+    /// Registers the inway and announces its services again whenever the trigger is notified
+    pub fn with_register_trigger(mut self, trigger: Arc<Notify>) -> Self {
+        self.register = trigger;
+        self
+    }
+
     async fn register_inway(&mut self) -> Result<()> {
         self.management
             .register_inway(Inway {
@@ -74,8 +103,12 @@ impl Broadcast {
             inway_address: self.inway_address.clone(),
             services: config
                 .services
-                .iter()
-                .map(|(_, service)| RegisterService {
+                .values()
+                .filter(|service| match &self.backend_health {
+                    Some(backend_health) => health_check::is_healthy(backend_health, &service.name),
+                    None => true,
+                })
+                .map(|service| RegisterService {
                     name: service.name.clone(),
                     documentation_url: service.documentation_url.clone(),
                     api_specification_type: String::new(),
@@ -111,7 +144,7 @@ impl Broadcast {
         Ok(())
     }
 
-    async fn broadcast(&mut self, rx: &mut Receiver<Config>) -> Result<()> {
+    async fn broadcast(&mut self) -> Result<()> {
         self.register_inway().await?;
         log::info!("inway registered");
 
@@ -119,18 +152,28 @@ impl Broadcast {
         log::info!("directory version: {}", response.into_inner().version);
 
         // We don't want to announce services without receiving the configuration as we
-        // would clear the services in the directory otherwise.
-        let mut inway_config = None;
+        // would clear the services in the directory otherwise. The last configuration is
+        // kept in between restarts as it's only sent again when it changes.
         let mut announce_interval = time::interval(BROADCAST_INTERVAL);
 
         loop {
             tokio::select! {
-                _ = announce_interval.tick(), if inway_config.is_some() => {
-                    self.announce(inway_config.as_ref().unwrap()).await?;
+                _ = announce_interval.tick(), if self.config.is_some() => {
+                    let config = self.config.clone().unwrap();
+                    self.announce(&config).await?;
+                    self.readiness.set_registered();
                 }
-                result = rx.recv() => match result {
+                _ = self.register.notified() => {
+                    self.register_inway().await?;
+                    log::info!("inway registered");
+
+                    if let Some(config) = self.config.clone() {
+                        self.announce(&config).await?;
+                    }
+                }
+                result = self.rx.recv() => match result {
                     Ok(config) =>  {
-                        inway_config = Some(config);
+                        self.config = Some(config);
                     }
                     Err(_) => {
                         log::info!("broadcast channel closed");
@@ -140,19 +183,12 @@ impl Broadcast {
             }
         }
     }
+}
 
-    pub fn broadcast_start(mut self, mut rx: Receiver<Config>) -> JoinHandle<()> {
+#[async_trait]
+impl Task for Broadcast {
+    async fn run(&mut self) -> Result<()> {
         log::info!("start broadcasting");
-
-        tokio::spawn(async move {
-            retry_backoff!(
-                self.broadcast(&mut rx),
-                |err, duration: Duration| log::warn!(
-                    "broadcast failed: {:?}, retrying in {}s",
-                    err,
-                    duration.as_secs()
-                )
-            );
-        })
+        self.broadcast().await
     }
 }
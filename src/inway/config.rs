@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 
+use serde::Deserialize;
 use wyhash2::WyHash;
 
 #[derive(Debug, Clone, Default)]
@@ -28,7 +29,55 @@ pub struct Service {
     pub one_time_costs: i32,
     pub monthly_costs: i32,
     pub request_costs: i32,
+    /// Organizations that were granted access to the service
+    pub authorizations: Vec<Authorization>,
 }
 
-/// Maps a service name to an HTTP endpoint
-pub type ServiceInwayMap = HashMap<String, Arc<String>, WyHash>;
+/// Access grant of an organization to a service, for the outway with the public key
+#[derive(Debug, Clone, Default, Hash)]
+pub struct Authorization {
+    /// The OIN of the organization
+    pub serial_number: String,
+    pub organization_name: String,
+    /// Base64 encoded SHA-256 hash of the public key of the outway
+    pub public_key_hash: String,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Backend endpoint of a service
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Endpoint {
+    pub url: String,
+    /// Share of the requests relative to the other endpoints, endpoints with weight 0 don't
+    /// receive requests
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+impl Endpoint {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            weight: default_weight(),
+        }
+    }
+
+    /// Name of the circuit breaker of the endpoint, circuits are kept per service and endpoint
+    pub fn circuit_name(&self, service: &str) -> String {
+        format!("{} ({})", service, self.url)
+    }
+}
+
+/// Backend endpoints of a service and the organizations that may call it
+#[derive(Debug, Clone, Default)]
+pub struct Route {
+    pub endpoints: Vec<Endpoint>,
+    pub authorizations: Vec<Authorization>,
+}
+
+/// Maps a service name to its route
+pub type ServiceInwayMap = HashMap<String, Arc<Route>, WyHash>;
@@ -6,16 +6,18 @@ use std::{
 use anyhow::Result;
 use async_channel::Sender;
 use futures_util::future::try_join_all;
-use tonic::{async_trait, transport::Channel};
+use tonic::async_trait;
 
 use crate::{
+    admin::ConfigStatus,
     pb::management::{
         management_client::ManagementClient, GetInwayConfigRequest, GetInwayConfigResponse,
     },
     poller::Poll,
+    trace::TracedChannel,
 };
 
-use super::{Config, Service};
+use super::{config::Authorization, Config, Service};
 
 fn map_config(response: GetInwayConfigResponse) -> Config {
     Config {
@@ -35,6 +37,21 @@ fn map_config(response: GetInwayConfigResponse) -> Config {
                         one_time_costs: s.one_time_costs,
                         monthly_costs: s.monthly_costs,
                         request_costs: s.request_costs,
+                        authorizations: s
+                            .authorization_settings
+                            .map(|settings| settings.authorizations)
+                            .unwrap_or_default()
+                            .into_iter()
+                            .filter_map(|authorization| {
+                                let organization = authorization.organization?;
+
+                                Some(Authorization {
+                                    serial_number: organization.serial_number,
+                                    organization_name: organization.name,
+                                    public_key_hash: authorization.public_key_hash,
+                                })
+                            })
+                            .collect(),
                     },
                 )
             })
@@ -45,15 +62,17 @@ fn map_config(response: GetInwayConfigResponse) -> Config {
 pub struct ConfigPoller {
     inway_name: String,
     config_hash: Option<u64>,
-    management: ManagementClient<Channel>,
+    status: Option<ConfigStatus>,
+    management: ManagementClient<TracedChannel>,
     subscribers: Vec<Sender<Config>>,
 }
 
 impl ConfigPoller {
-    pub fn new(management: ManagementClient<Channel>, inway_name: String) -> Self {
+    pub fn new(management: ManagementClient<TracedChannel>, inway_name: String) -> Self {
         ConfigPoller {
             management,
             config_hash: None,
+            status: None,
             inway_name,
             subscribers: vec![],
         }
@@ -62,6 +81,12 @@ impl ConfigPoller {
     pub fn subscribe(&mut self, tx: Sender<Config>) {
         self.subscribers.push(tx);
     }
+
+    /// Keeps the version of the config up to date in `status`
+    pub fn with_status(mut self, status: ConfigStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
 }
 
 #[async_trait]
@@ -92,6 +117,10 @@ impl Poll for ConfigPoller {
             .await?;
 
             self.config_hash = Some(new_hash);
+
+            if let Some(status) = &self.status {
+                status.set_version(new_hash);
+            }
         }
 
         Ok(())
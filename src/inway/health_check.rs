@@ -0,0 +1,258 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futures_util::future::join_all;
+use http::{StatusCode, Uri};
+use tokio::time;
+use tonic::async_trait;
+
+use crate::{
+    client_pool::Protocol,
+    health::{self, Probe},
+    metrics,
+    supervisor::Task,
+};
+
+use super::{
+    backend_tls::BackendClients,
+    server::ServiceInwayMapState,
+    settings::{HealthCheck, InwaySettings},
+};
+
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct BackendHealth {
+    pub healthy: bool,
+    pub last_probe: Probe,
+    successes: u32,
+    failures: u32,
+}
+
+impl BackendHealth {
+    /// Records the result of a probe and returns true if the health changed
+    fn record(&mut self, check: &HealthCheck, probe: Probe) -> bool {
+        if probe.healthy {
+            self.successes += 1;
+            self.failures = 0;
+        } else {
+            self.failures += 1;
+            self.successes = 0;
+        }
+
+        self.last_probe = probe;
+
+        let healthy = if self.healthy {
+            self.failures < check.unhealthy_threshold
+        } else {
+            self.successes >= check.healthy_threshold
+        };
+        let changed = healthy != self.healthy;
+
+        self.healthy = healthy;
+        changed
+    }
+}
+
+/// Maps a service name to the health of its backend endpoints, keyed by URL
+pub type BackendHealthState = Arc<RwLock<HashMap<String, HashMap<String, BackendHealth>>>>;
+
+/// Returns false if all backend endpoints of the service are known to be unhealthy
+pub fn is_healthy(state: &BackendHealthState, service: &str) -> bool {
+    state
+        .read()
+        .unwrap()
+        .get(service)
+        .map(|endpoints| endpoints.is_empty() || endpoints.values().any(|health| health.healthy))
+        .unwrap_or(true)
+}
+
+/// Returns false if the backend endpoint of the service is known to be unhealthy
+pub fn is_endpoint_healthy(state: &BackendHealthState, service: &str, url: &str) -> bool {
+    state
+        .read()
+        .unwrap()
+        .get(service)
+        .and_then(|endpoints| endpoints.get(url))
+        .map(|health| health.healthy)
+        .unwrap_or(true)
+}
+
+fn probe_uri(endpoint_url: &str, path: &str) -> Result<Uri, http::uri::InvalidUri> {
+    let endpoint_url = endpoint_url.trim_end_matches('/');
+    let path = path.trim_start_matches('/');
+
+    format!("{}/{}", endpoint_url, path).parse()
+}
+
+/// Periodically probes the backends of services that have a health check configured
+pub struct HealthChecker {
+    clients: BackendClients,
+    routes: ServiceInwayMapState,
+    settings: Arc<InwaySettings>,
+    state: BackendHealthState,
+    /// Next probe per service and endpoint URL
+    next_probe: HashMap<(String, String), Instant>,
+}
+
+impl HealthChecker {
+    pub fn new(
+        clients: BackendClients,
+        routes: ServiceInwayMapState,
+        settings: Arc<InwaySettings>,
+        state: BackendHealthState,
+    ) -> Self {
+        Self {
+            clients,
+            routes,
+            settings,
+            state,
+            next_probe: HashMap::new(),
+        }
+    }
+
+    async fn probe(&self, service: &str, endpoint_url: &str, check: &HealthCheck) -> Probe {
+        let uri = match probe_uri(endpoint_url, &check.path) {
+            Ok(uri) => uri,
+            Err(e) => {
+                return Probe {
+                    healthy: false,
+                    status: None,
+                    error: Some(format!("invalid health check URI: {}", e)),
+                    latency_ms: 0,
+                }
+            }
+        };
+
+        log::trace!("probing backend of {} (uri={})", service, uri);
+
+        let expected_status = check.expected_status;
+        let client = self.clients.get(service, Protocol::Auto);
+        let probe = health::probe(&client, uri, check.timeout, |status: StatusCode| {
+            status.as_u16() == expected_status
+        })
+        .await;
+
+        metrics::BACKEND_PROBE_DURATION
+            .with_label_values(&[service])
+            .observe(probe.latency_ms as f64 / 1000.0);
+        metrics::BACKEND_PROBES
+            .with_label_values(&[service, if probe.healthy { "success" } else { "failure" }])
+            .inc();
+
+        probe
+    }
+
+    async fn tick(&mut self) {
+        let now = Instant::now();
+        let (due, current) = {
+            let routes = self.routes.read().await;
+            let checked = self
+                .settings
+                .services
+                .iter()
+                .filter_map(|(name, settings)| {
+                    Some((name, settings.health_check.as_ref()?, routes.get(name)?))
+                })
+                .collect::<Vec<_>>();
+            let due = checked
+                .iter()
+                .flat_map(|(name, check, route)| {
+                    route
+                        .endpoints
+                        .iter()
+                        .filter(|endpoint| {
+                            let key = (name.to_string(), endpoint.url.clone());
+
+                            !matches!(self.next_probe.get(&key), Some(next) if *next > now)
+                        })
+                        .map(|endpoint| (name.to_string(), endpoint.url.clone(), *check))
+                })
+                .collect::<Vec<_>>();
+            let current = checked
+                .into_iter()
+                .map(|(name, _, route)| (name.to_string(), Arc::clone(route)))
+                .collect::<HashMap<_, _>>();
+
+            (due, current)
+        };
+
+        let probes = join_all(
+            due.iter()
+                .map(|(name, endpoint_url, check)| self.probe(name, endpoint_url, check)),
+        )
+        .await;
+
+        let mut state = self.state.write().unwrap();
+
+        for ((name, endpoint_url, check), probe) in due.into_iter().zip(probes) {
+            self.next_probe
+                .insert((name.clone(), endpoint_url.clone()), now + check.interval);
+
+            // Backends are assumed to be healthy until proven otherwise
+            let health = state
+                .entry(name.clone())
+                .or_default()
+                .entry(endpoint_url.clone())
+                .or_insert_with(|| BackendHealth {
+                    healthy: true,
+                    last_probe: probe.clone(),
+                    successes: 0,
+                    failures: 0,
+                });
+
+            if health.record(check, probe) {
+                if health.healthy {
+                    log::info!("backend {} of {} is healthy", endpoint_url, name);
+                } else {
+                    log::warn!(
+                        "backend {} of {} is unhealthy: {}",
+                        endpoint_url,
+                        name,
+                        health
+                            .last_probe
+                            .error
+                            .clone()
+                            .or_else(|| health.last_probe.status.map(|s| format!("status {}", s)))
+                            .unwrap_or_default()
+                    );
+                }
+            }
+        }
+
+        // Forget endpoints that were removed from the config
+        for (name, route) in &current {
+            if let Some(health) = state.get_mut(name) {
+                health.retain(|url, _| route.endpoints.iter().any(|endpoint| &endpoint.url == url));
+            }
+        }
+
+        self.next_probe.retain(|(name, url), _| {
+            matches!(current.get(name), Some(route) if route.endpoints.iter().any(|endpoint| &endpoint.url == url))
+        });
+
+        for (name, endpoints) in state.iter() {
+            let healthy = endpoints.values().any(|health| health.healthy);
+
+            metrics::BACKEND_UP
+                .with_label_values(&[name])
+                .set(healthy as i64);
+        }
+    }
+}
+
+#[async_trait]
+impl Task for HealthChecker {
+    async fn run(&mut self) -> Result<()> {
+        let mut interval = time::interval(TICK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            self.tick().await;
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use warp::reject::Reject;
+
+use super::settings::InwaySettings;
+
+/// Retry-After for maintenance without an end
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceMode {
+    /// Message that is returned to consumers instead of the default one
+    pub message: Option<String>,
+    /// Start of the maintenance window, maintenance starts right away when unset
+    #[serde(with = "humantime_serde")]
+    pub start: Option<SystemTime>,
+    /// End of the maintenance window, maintenance lasts until it is switched off when unset
+    #[serde(with = "humantime_serde")]
+    pub end: Option<SystemTime>,
+    /// Value of the Retry-After header when there is no end, defaults to a minute
+    #[serde(with = "humantime_serde")]
+    pub retry_after: Option<Duration>,
+}
+
+impl MaintenanceMode {
+    pub fn validate(&self) -> Result<()> {
+        match (self.start, self.end) {
+            (Some(start), Some(end)) if end <= start => {
+                Err(anyhow!("maintenance window must end after it starts"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn is_active(&self, now: SystemTime) -> bool {
+        !matches!(self.start, Some(start) if now < start)
+            && !matches!(self.end, Some(end) if now >= end)
+    }
+
+    fn retry_after(&self, now: SystemTime) -> Duration {
+        match self.end {
+            Some(end) => end.duration_since(now).unwrap_or_default(),
+            None => self.retry_after.unwrap_or(DEFAULT_RETRY_AFTER),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceStatus {
+    /// False before the window starts and after it ends
+    pub active: bool,
+    #[serde(flatten)]
+    pub mode: MaintenanceMode,
+}
+
+/// The service is in maintenance, requests are rejected while it stays announced
+#[derive(Debug)]
+pub struct InMaintenance {
+    pub service: String,
+    pub message: Option<String>,
+    pub retry_after: Duration,
+}
+
+impl Reject for InMaintenance {}
+
+impl Display for InMaintenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.message {
+            Some(message) => write!(f, "{}", message),
+            None => write!(f, "service {} is in maintenance", self.service),
+        }
+    }
+}
+
+/// Services that are (scheduled to be) in maintenance, set in the settings or through the
+/// admin API
+#[derive(Clone, Default)]
+pub struct Maintenance {
+    services: Arc<RwLock<BTreeMap<String, MaintenanceMode>>>,
+}
+
+impl Maintenance {
+    pub fn new(settings: &InwaySettings) -> Result<Self> {
+        let mut services = BTreeMap::new();
+
+        for (service, settings) in &settings.services {
+            if let Some(mode) = &settings.maintenance {
+                mode.validate()?;
+                services.insert(service.clone(), mode.clone());
+            }
+        }
+
+        Ok(Self {
+            services: Arc::new(RwLock::new(services)),
+        })
+    }
+
+    pub fn enable(&self, service: String, mode: MaintenanceMode) {
+        log::info!("service {} is in maintenance", service);
+        self.services.write().unwrap().insert(service, mode);
+    }
+
+    /// Returns false if the service was not in maintenance
+    pub fn disable(&self, service: &str) -> bool {
+        let removed = self.services.write().unwrap().remove(service).is_some();
+
+        if removed {
+            log::info!("service {} is no longer in maintenance", service);
+        }
+
+        removed
+    }
+
+    pub fn status(&self, service: &str) -> Option<MaintenanceStatus> {
+        self.services
+            .read()
+            .unwrap()
+            .get(service)
+            .map(|mode| MaintenanceStatus {
+                active: mode.is_active(SystemTime::now()),
+                mode: mode.clone(),
+            })
+    }
+
+    pub fn services(&self) -> BTreeMap<String, MaintenanceStatus> {
+        let now = SystemTime::now();
+
+        self.services
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(service, mode)| {
+                let status = MaintenanceStatus {
+                    active: mode.is_active(now),
+                    mode: mode.clone(),
+                };
+
+                (service.clone(), status)
+            })
+            .collect()
+    }
+
+    pub fn check(&self, service: &str) -> Result<(), InMaintenance> {
+        let now = SystemTime::now();
+
+        match self.services.read().unwrap().get(service) {
+            Some(mode) if mode.is_active(now) => Err(InMaintenance {
+                service: service.to_string(),
+                message: mode.message.clone(),
+                retry_after: mode.retry_after(now),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,9 +1,25 @@
+mod access;
+mod admin;
+mod backend_auth;
+mod backend_tls;
+mod balancer;
 mod broadcast;
 mod config;
 mod config_poller;
+mod health_check;
+mod maintenance;
+mod rate_limit;
+mod rewrite;
 mod server;
+mod settings;
 
+pub use access::AccessDenied;
+pub use backend_auth::BackendAuthError;
 pub use broadcast::Broadcast;
 pub use config::{Config, Service};
 pub use config_poller::ConfigPoller;
+pub use health_check::BackendHealthState;
+pub use maintenance::InMaintenance;
+pub use rate_limit::{Limit, RateLimited};
 pub use server::Server;
+pub use settings::{InwaySettings, UnhealthyServices};
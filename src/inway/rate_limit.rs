@@ -0,0 +1,505 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, time};
+use tonic::async_trait;
+use warp::reject::Reject;
+
+use super::settings::InwaySettings;
+use crate::{metrics, supervisor::Task};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const PERSIST_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Limits per consumer organization and service, unset values fall back on a less specific
+/// level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Sustained number of requests per second
+    pub rate: Option<f64>,
+    /// Number of requests that can be made at once, defaults to one second worth of requests
+    pub burst: Option<u32>,
+    /// Number of requests per day (UTC)
+    pub daily_quota: Option<u64>,
+}
+
+impl Limits {
+    /// Fills in the unset values from `other`
+    pub fn or(self, other: Limits) -> Limits {
+        Limits {
+            rate: self.rate.or(other.rate),
+            burst: self.burst.or(other.burst),
+            daily_quota: self.daily_quota.or(other.daily_quota),
+        }
+    }
+
+    fn burst(&self, rate: f64) -> f64 {
+        match self.burst {
+            Some(burst) => burst.max(1) as f64,
+            None => rate.ceil().max(1.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Rate,
+    Quota,
+}
+
+/// The consumer organization made more requests than it is allowed to
+#[derive(Debug)]
+pub struct RateLimited {
+    pub organization: String,
+    pub service: String,
+    pub limit: Limit,
+    pub retry_after: Duration,
+}
+
+impl Reject for RateLimited {}
+
+impl Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit = match self.limit {
+            Limit::Rate => "rate limit",
+            Limit::Quota => "daily quota",
+        };
+
+        write!(
+            f,
+            "organization {} exceeded the {} of service {}",
+            self.organization, limit, self.service
+        )
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Usage {
+    organization: String,
+    service: String,
+    /// Days since the UNIX epoch
+    day: u64,
+    requests: u64,
+}
+
+/// Returns the current day and the time until the next one starts
+fn today() -> (u64, Duration) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let day = now.as_secs() / SECONDS_PER_DAY;
+
+    (
+        day,
+        Duration::from_secs((day + 1) * SECONDS_PER_DAY).saturating_sub(now),
+    )
+}
+
+type Key = (String, String);
+
+/// Token bucket rate limiter and daily quotas keyed on the serial number of the consumer
+/// organization and the service
+pub struct RateLimiter {
+    settings: Arc<InwaySettings>,
+    buckets: Mutex<HashMap<Key, Bucket>>,
+    usage: Mutex<HashMap<Key, Usage>>,
+    changed: AtomicBool,
+}
+
+impl RateLimiter {
+    /// Creates the rate limiter and restores the quota usage of today from the quota file
+    pub async fn load(settings: Arc<InwaySettings>) -> Result<Self> {
+        for limits in settings
+            .services
+            .values()
+            .map(|service| &service.rate_limit)
+            .chain([&settings.rate_limit])
+        {
+            if matches!(limits.rate, Some(rate) if rate.is_nan() || rate <= 0.0) {
+                return Err(anyhow!("rate limits must be greater than zero"));
+            }
+        }
+
+        let mut usage = HashMap::new();
+
+        if let Some(path) = &settings.quota_file {
+            if path.exists() {
+                let contents = fs::read(path)
+                    .await
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                let entries: Vec<Usage> = serde_json::from_slice(&contents)
+                    .with_context(|| format!("invalid quota file {}", path.display()))?;
+                let (day, _) = today();
+
+                for entry in entries.into_iter().filter(|entry| entry.day == day) {
+                    metrics::QUOTA_USED
+                        .with_label_values(&[&entry.organization, &entry.service])
+                        .set(entry.requests as i64);
+                    usage.insert((entry.organization.clone(), entry.service.clone()), entry);
+                }
+            }
+        }
+
+        Ok(Self {
+            settings,
+            buckets: Mutex::default(),
+            usage: Mutex::new(usage),
+            changed: AtomicBool::new(false),
+        })
+    }
+
+    fn take_token(&self, key: &Key, limits: &Limits) -> Option<Duration> {
+        let rate = limits.rate?;
+        let burst = limits.burst(rate);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: burst,
+            updated_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated_at = now;
+
+        if bucket.tokens < 1.0 {
+            return Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate));
+        }
+
+        bucket.tokens -= 1.0;
+        None
+    }
+
+    /// Counts the request when both limits allow it, otherwise returns the limit that is
+    /// exceeded. The quota is checked first, so requests that exceed it don't take a token.
+    fn count_request(&self, key: &Key, limits: &Limits) -> Option<(Limit, Duration)> {
+        let (day, until_tomorrow) = today();
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(key.clone()).or_insert_with(|| Usage {
+            organization: key.0.clone(),
+            service: key.1.clone(),
+            day,
+            requests: 0,
+        });
+
+        if entry.day != day {
+            entry.day = day;
+            entry.requests = 0;
+        }
+
+        if matches!(limits.daily_quota, Some(quota) if entry.requests >= quota) {
+            return Some((Limit::Quota, until_tomorrow));
+        }
+
+        if let Some(retry_after) = self.take_token(key, limits) {
+            return Some((Limit::Rate, retry_after));
+        }
+
+        entry.requests += 1;
+        self.changed.store(true, Ordering::Relaxed);
+
+        metrics::QUOTA_USED
+            .with_label_values(&[&key.0, &key.1])
+            .set(entry.requests as i64);
+
+        None
+    }
+
+    /// Counts a request of the organization for the service, fails when one of the limits
+    /// is exceeded
+    pub fn check(&self, organization: &str, service: &str) -> Result<(), RateLimited> {
+        let limits = self.settings.rate_limit(service);
+
+        if limits.rate.is_none() && limits.daily_quota.is_none() {
+            return Ok(());
+        }
+
+        let key = (organization.to_string(), service.to_string());
+        let exceeded = self.count_request(&key, &limits);
+        let result = match exceeded {
+            None => "allowed",
+            Some((Limit::Rate, _)) => "rate_limited",
+            Some((Limit::Quota, _)) => "quota_exceeded",
+        };
+
+        metrics::RATE_LIMIT_REQUESTS
+            .with_label_values(&[organization, service, result])
+            .inc();
+
+        match exceeded {
+            Some((limit, retry_after)) => Err(RateLimited {
+                organization: key.0,
+                service: key.1,
+                limit,
+                retry_after,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the quota usage to the quota file if it changed since the last save
+    pub async fn save(&self) -> Result<()> {
+        let path = match &self.settings.quota_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let contents = {
+            let usage = self.usage.lock().unwrap();
+            serde_json::to_vec(&usage.values().collect::<Vec<_>>())?
+        };
+
+        // Write to a temporary file first, so a crash can't leave a truncated file behind
+        let mut tmp_path = PathBuf::from(path);
+        tmp_path.set_extension("tmp");
+
+        let result = async {
+            fs::write(&tmp_path, contents).await?;
+            fs::rename(&tmp_path, path).await
+        }
+        .await;
+
+        if result.is_err() {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+
+        result.with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+/// Periodically writes the quota usage to disk, so it survives restarts
+pub struct QuotaWriter {
+    limiter: Arc<RateLimiter>,
+}
+
+impl QuotaWriter {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        Self { limiter }
+    }
+}
+
+#[async_trait]
+impl Task for QuotaWriter {
+    async fn run(&mut self) -> Result<()> {
+        let mut interval = time::interval(PERSIST_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            self.limiter.save().await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ORGANIZATION: &str = "00000001";
+    const SERVICE: &str = "basisregister";
+
+    fn settings(limits: Limits, quota_file: Option<PathBuf>) -> Arc<InwaySettings> {
+        Arc::new(InwaySettings {
+            rate_limit: limits,
+            quota_file,
+            ..Default::default()
+        })
+    }
+
+    async fn limiter(limits: Limits) -> RateLimiter {
+        RateLimiter::load(settings(limits, None)).await.unwrap()
+    }
+
+    fn check(limiter: &RateLimiter) -> Result<(), (Limit, Duration)> {
+        limiter
+            .check(ORGANIZATION, SERVICE)
+            .map_err(|e| (e.limit, e.retry_after))
+    }
+
+    fn key() -> Key {
+        (ORGANIZATION.to_string(), SERVICE.to_string())
+    }
+
+    /// Moves the last refill of the bucket back in time
+    fn rewind_bucket(limiter: &RateLimiter, by: Duration) {
+        let mut buckets = limiter.buckets.lock().unwrap();
+        let bucket = buckets.get_mut(&key()).unwrap();
+        bucket.updated_at -= by;
+    }
+
+    #[tokio::test]
+    async fn allows_a_burst_and_refills_at_the_rate() {
+        let limiter = limiter(Limits {
+            rate: Some(10.0),
+            burst: Some(3),
+            daily_quota: None,
+        })
+        .await;
+
+        for _ in 0..3 {
+            assert_eq!(check(&limiter), Ok(()));
+        }
+
+        let (limit, retry_after) = check(&limiter).unwrap_err();
+        assert_eq!(limit, Limit::Rate);
+        assert!(
+            retry_after <= Duration::from_millis(100),
+            "{:?}",
+            retry_after
+        );
+        assert!(retry_after > Duration::from_millis(90), "{:?}", retry_after);
+
+        // Two tokens are added in 200ms
+        rewind_bucket(&limiter, Duration::from_millis(200));
+        assert_eq!(check(&limiter), Ok(()));
+        assert_eq!(check(&limiter), Ok(()));
+        assert!(check(&limiter).is_err());
+
+        // The bucket never holds more than the burst
+        rewind_bucket(&limiter, Duration::from_secs(60));
+        for _ in 0..3 {
+            assert_eq!(check(&limiter), Ok(()));
+        }
+        assert!(check(&limiter).is_err());
+    }
+
+    #[test]
+    fn burst_defaults_to_one_second_of_requests() {
+        assert_eq!(Limits::default().burst(2.5), 3.0);
+        assert_eq!(Limits::default().burst(0.1), 1.0);
+
+        let limits = Limits {
+            burst: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(limits.burst(10.0), 1.0);
+    }
+
+    #[tokio::test]
+    async fn rejected_quota_requests_take_no_token() {
+        let limiter = limiter(Limits {
+            rate: Some(1.0),
+            burst: Some(5),
+            daily_quota: Some(2),
+        })
+        .await;
+
+        assert_eq!(check(&limiter), Ok(()));
+        assert_eq!(check(&limiter), Ok(()));
+
+        for _ in 0..10 {
+            let (limit, retry_after) = check(&limiter).unwrap_err();
+            assert_eq!(limit, Limit::Quota);
+            assert!(retry_after <= Duration::from_secs(SECONDS_PER_DAY));
+        }
+
+        let tokens = limiter.buckets.lock().unwrap()[&key()].tokens;
+        assert!((3.0..3.1).contains(&tokens), "{}", tokens);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_requests_are_not_counted() {
+        let limiter = limiter(Limits {
+            rate: Some(1.0),
+            burst: Some(1),
+            daily_quota: Some(10),
+        })
+        .await;
+
+        assert_eq!(check(&limiter), Ok(()));
+        assert_eq!(check(&limiter).unwrap_err().0, Limit::Rate);
+        assert_eq!(limiter.usage.lock().unwrap()[&key()].requests, 1);
+    }
+
+    #[tokio::test]
+    async fn quota_resets_on_a_new_day() {
+        let limiter = limiter(Limits {
+            daily_quota: Some(1),
+            ..Default::default()
+        })
+        .await;
+
+        assert_eq!(check(&limiter), Ok(()));
+        assert_eq!(check(&limiter).unwrap_err().0, Limit::Quota);
+
+        limiter.usage.lock().unwrap().get_mut(&key()).unwrap().day -= 1;
+
+        assert_eq!(check(&limiter), Ok(()));
+        assert_eq!(limiter.usage.lock().unwrap()[&key()].requests, 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_the_quota_usage_of_today_across_restarts() {
+        let path = std::env::temp_dir().join(format!("nlx-quota-{}.json", rand::random::<u64>()));
+        let limits = Limits {
+            daily_quota: Some(3),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::load(settings(limits, Some(path.clone())))
+            .await
+            .unwrap();
+
+        assert_eq!(check(&limiter), Ok(()));
+        assert_eq!(check(&limiter), Ok(()));
+
+        // Usage of earlier days is dropped on load
+        {
+            let mut usage = limiter.usage.lock().unwrap();
+            usage.insert(
+                ("00000002".to_string(), SERVICE.to_string()),
+                Usage {
+                    organization: "00000002".to_string(),
+                    service: SERVICE.to_string(),
+                    day: today().0 - 1,
+                    requests: 3,
+                },
+            );
+        }
+
+        limiter.save().await.unwrap();
+
+        let restored = RateLimiter::load(settings(limits, Some(path.clone())))
+            .await
+            .unwrap();
+        let restored_usage = restored.usage.lock().unwrap().len();
+        let last = check(&restored);
+        let exceeded = check(&restored);
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(restored_usage, 1);
+        assert_eq!(last, Ok(()));
+        assert_eq!(exceeded.unwrap_err().0, Limit::Quota);
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_rates() {
+        for rate in [0.0, -1.0, f64::NAN] {
+            let limits = Limits {
+                rate: Some(rate),
+                ..Default::default()
+            };
+
+            assert!(RateLimiter::load(settings(limits, None)).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use std::{collections::HashMap, sync::Arc};
+
+use http::{
+    header::{HeaderName, InvalidHeaderName},
+    HeaderMap, HeaderValue,
+};
+use serde::Deserialize;
+use warp::reply::Response;
+
+use crate::{
+    path,
+    reverse_proxy::{IntoRequestError, Request},
+    tls::PeerIdentity,
+};
+
+/// Header name that is validated when the settings are loaded
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Name(HeaderName);
+
+impl TryFrom<String> for Name {
+    type Error = InvalidHeaderName;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        HeaderName::try_from(name).map(Self)
+    }
+}
+
+/// Values that can be used in header templates, e.g. `${caller.serial_number}`
+pub struct Variables<'a> {
+    service: &'a str,
+    caller: Option<Arc<PeerIdentity>>,
+}
+
+impl<'a> Variables<'a> {
+    pub fn new(service: &'a str, request: &Request) -> Self {
+        Self {
+            service,
+            caller: request.peer().and_then(|peer| peer.identity.clone()),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        let caller = self.caller.as_deref();
+
+        match name {
+            "service" => Some(self.service),
+            "caller.serial_number" => caller.and_then(|c| c.serial_number.as_deref()),
+            "caller.organization_name" => caller.and_then(|c| c.organization_name.as_deref()),
+            "caller.common_name" => caller.and_then(|c| c.common_name.as_deref()),
+            _ => None,
+        }
+    }
+
+    /// Replaces the variables in the template, unknown variables are kept as is and caller
+    /// values that are missing from the certificate are left empty
+    fn render(&self, template: &str) -> String {
+        let mut output = String::with_capacity(template.len());
+        let mut rest = template;
+
+        while let Some(start) = rest.find("${") {
+            output.push_str(&rest[..start]);
+
+            match rest[start..].find('}') {
+                Some(end) => {
+                    let name = &rest[start + 2..start + end];
+
+                    match self.get(name) {
+                        Some(value) => output.push_str(value),
+                        None if name.starts_with("caller.") => {}
+                        None => output.push_str(&rest[start..=start + end]),
+                    }
+
+                    rest = &rest[start + end + 1..];
+                }
+                None => {
+                    output.push_str(&rest[start..]);
+                    rest = "";
+                }
+            }
+        }
+
+        output.push_str(rest);
+        output
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeaderRules {
+    /// Headers that are removed
+    pub remove: Vec<Name>,
+    /// Headers that are set, existing values are replaced
+    pub set: HashMap<Name, String>,
+    /// Headers that are added, existing values are kept
+    pub add: HashMap<Name, String>,
+}
+
+impl HeaderRules {
+    fn apply(&self, headers: &mut HeaderMap, variables: &Variables) {
+        for Name(name) in &self.remove {
+            headers.remove(name);
+        }
+
+        let values = self
+            .set
+            .iter()
+            .map(|(name, template)| (name, template, true))
+            .chain(
+                self.add
+                    .iter()
+                    .map(|(name, template)| (name, template, false)),
+            );
+
+        for (Name(name), template, replace) in values {
+            let value = match HeaderValue::try_from(variables.render(template)) {
+                Ok(value) => value,
+                Err(e) => {
+                    log::warn!("invalid value for header {}: {}", name, e);
+                    continue;
+                }
+            };
+
+            if replace {
+                headers.insert(name, value);
+            } else {
+                headers.append(name, value);
+            }
+        }
+    }
+}
+
+/// Changes to requests and responses of a service, for backends that expect a different
+/// path or extra headers
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rewrite {
+    /// Prefix that is removed from the request path, requests without it are left alone
+    pub strip_prefix: Option<String>,
+    /// Prefix that is put in front of the (stripped) request path
+    pub add_prefix: Option<String>,
+    pub request_headers: HeaderRules,
+    pub response_headers: HeaderRules,
+}
+
+impl Rewrite {
+    fn rewrite_path(&self, path: &str) -> String {
+        // Prefixes only match complete segments
+        let path = match self.strip_prefix.as_deref().map(|p| p.trim_matches('/')) {
+            Some(prefix) if path == prefix => "",
+            Some(prefix) => match path.strip_prefix(prefix) {
+                Some(stripped) if prefix.is_empty() || stripped.starts_with('/') => {
+                    stripped.trim_start_matches('/')
+                }
+                _ => return path.to_string(),
+            },
+            None => path,
+        };
+
+        match self.add_prefix.as_deref().map(|p| p.trim_matches('/')) {
+            Some(prefix) if !prefix.is_empty() && !path.is_empty() => {
+                format!("{}/{}", prefix, path)
+            }
+            Some(prefix) if !prefix.is_empty() => prefix.to_string(),
+            _ => path.to_string(),
+        }
+    }
+
+    pub fn request(
+        &self,
+        request: &mut Request,
+        variables: &Variables,
+    ) -> Result<(), IntoRequestError> {
+        // Prefixes are matched on the canonical path, so they can't be avoided with tricks
+        // like double slashes
+        let canonical =
+            path::canonicalize(request.path()).map_err(IntoRequestError::InvalidPath)?;
+        request.set_path(self.rewrite_path(&canonical));
+
+        self.request_headers.apply(request.headers_mut(), variables);
+
+        Ok(())
+    }
+
+    pub fn response(&self, response: &mut Response, variables: &Variables) {
+        self.response_headers
+            .apply(response.headers_mut(), variables);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(strip_prefix: Option<&str>, add_prefix: Option<&str>) -> Rewrite {
+        Rewrite {
+            strip_prefix: strip_prefix.map(String::from),
+            add_prefix: add_prefix.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    fn variables(caller: Option<PeerIdentity>) -> Variables<'static> {
+        Variables {
+            service: "basisregister",
+            caller: caller.map(Arc::new),
+        }
+    }
+
+    fn caller() -> PeerIdentity {
+        PeerIdentity {
+            serial_number: Some("00000001".to_string()),
+            organization_name: Some("Gemeente Stijns".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn name(name: &'static str) -> Name {
+        Name(HeaderName::from_static(name))
+    }
+
+    #[test]
+    fn strips_whole_segments() {
+        let rewrite = rewrite(Some("/api/v1/"), None);
+
+        assert_eq!(rewrite.rewrite_path("api/v1/orders/1"), "orders/1");
+        assert_eq!(rewrite.rewrite_path("api/v1"), "");
+        assert_eq!(rewrite.rewrite_path("api/v10/orders"), "api/v10/orders");
+        assert_eq!(rewrite.rewrite_path("other/api/v1"), "other/api/v1");
+    }
+
+    #[test]
+    fn empty_prefixes_change_nothing() {
+        let rewrite = rewrite(Some("/"), Some(""));
+
+        assert_eq!(rewrite.rewrite_path("orders/1"), "orders/1");
+        assert_eq!(rewrite.rewrite_path(""), "");
+    }
+
+    #[test]
+    fn adds_a_prefix() {
+        let rewrite = rewrite(Some("api"), Some("/internal/v2/"));
+
+        assert_eq!(rewrite.rewrite_path("api/orders"), "internal/v2/orders");
+        assert_eq!(rewrite.rewrite_path("api"), "internal/v2");
+        // Paths without the stripped prefix are left alone entirely
+        assert_eq!(rewrite.rewrite_path("orders"), "orders");
+        assert_eq!(rewrite.rewrite_path(""), "");
+
+        let rewrite = self::rewrite(None, Some("/internal/v2/"));
+
+        assert_eq!(rewrite.rewrite_path("orders"), "internal/v2/orders");
+        assert_eq!(rewrite.rewrite_path(""), "internal/v2");
+    }
+
+    #[test]
+    fn renders_variables() {
+        let variables = variables(Some(caller()));
+
+        assert_eq!(
+            variables.render("${service}:${caller.serial_number}"),
+            "basisregister:00000001"
+        );
+        assert_eq!(
+            variables.render("org=${caller.organization_name}"),
+            "org=Gemeente Stijns"
+        );
+        assert_eq!(variables.render("no variables"), "no variables");
+    }
+
+    #[test]
+    fn leaves_missing_caller_values_empty() {
+        let variables = variables(None);
+
+        assert_eq!(variables.render("[${caller.serial_number}]"), "[]");
+        assert_eq!(variables.render("[${caller.unknown}]"), "[]");
+        assert_eq!(
+            self::variables(Some(caller())).render("[${caller.common_name}]"),
+            "[]"
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_variables() {
+        let variables = variables(None);
+
+        assert_eq!(
+            variables.render("${unknown}-${service}"),
+            "${unknown}-basisregister"
+        );
+        assert_eq!(variables.render("${service"), "${service");
+        assert_eq!(variables.render("$service}"), "$service}");
+    }
+
+    #[test]
+    fn applies_header_rules() {
+        let rules = HeaderRules {
+            remove: vec![name("cookie")],
+            set: HashMap::from([(name("x-tenant-id"), "${caller.serial_number}".to_string())]),
+            add: HashMap::from([(name("x-via"), "nlx".to_string())]),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", HeaderValue::from_static("session=1"));
+        headers.insert("x-tenant-id", HeaderValue::from_static("spoofed"));
+        headers.insert("x-via", HeaderValue::from_static("proxy"));
+
+        rules.apply(&mut headers, &variables(Some(caller())));
+
+        assert!(!headers.contains_key("cookie"));
+        assert_eq!(headers["x-tenant-id"], "00000001");
+        assert_eq!(
+            headers.get_all("x-via").iter().collect::<Vec<_>>(),
+            ["proxy", "nlx"]
+        );
+    }
+
+    #[test]
+    fn skips_header_values_that_render_invalid() {
+        let rules = HeaderRules {
+            set: HashMap::from([
+                (
+                    name("x-organization"),
+                    "${caller.organization_name}".to_string(),
+                ),
+                (name("x-service"), "${service}".to_string()),
+            ]),
+            ..Default::default()
+        };
+        let caller = PeerIdentity {
+            organization_name: Some("Stijns\r\nX-Injected: 1".to_string()),
+            ..Default::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert("x-organization", HeaderValue::from_static("kept"));
+
+        rules.apply(&mut headers, &variables(Some(caller)));
+
+        assert_eq!(headers["x-organization"], "kept");
+        assert_eq!(headers["x-service"], "basisregister");
+        assert!(!headers.contains_key("x-injected"));
+    }
+}
@@ -1,36 +1,111 @@
 use std::{net::SocketAddr, sync::Arc};
 
+use anyhow::Context;
 use async_channel::Receiver;
-use hyper::Client;
-use hyper_rustls::{ConfigBuilderExt, HttpsConnectorBuilder};
-
-use rustls::ClientConfig;
+use futures_util::future::join_all;
+use http::{header::AUTHORIZATION, StatusCode};
 use serde::Serialize;
 use tokio::sync::RwLock;
+use tonic::async_trait;
 use warp::Filter;
 
-use crate::{filters::with_request, reverse_proxy, tls::TlsPair};
+use crate::{
+    access_log,
+    admin::Admin,
+    circuit_breaker::CircuitBreakers,
+    client_pool::Protocol,
+    errors::{self, Component, RouteError},
+    faults::{self, Faults},
+    filters::with_request,
+    health::{self, Probe, Readiness},
+    metering::{Costs, Meter, ReportWriter},
+    mirror::Mirror,
+    reverse_proxy, serve,
+    supervisor::{Supervisor, Task},
+    tls::TlsPair,
+    trace::{Kind, Span, TracedConnector},
+    VERSION,
+};
 
-use super::{config::ServiceInwayMap, Config};
+use super::{
+    access, admin,
+    backend_auth::BackendCredentials,
+    backend_tls::{BackendClients, BackendConnector},
+    balancer,
+    config::{Endpoint, Route, ServiceInwayMap},
+    health_check::{self, BackendHealthState, HealthChecker},
+    maintenance::{Maintenance, MaintenanceStatus},
+    rate_limit::{QuotaWriter, RateLimiter},
+    rewrite::Variables,
+    settings::InwaySettings,
+    Config,
+};
 
-type ServiceInwayMapState = Arc<RwLock<ServiceInwayMap>>;
+pub type ServiceInwayMapState = Arc<RwLock<ServiceInwayMap>>;
 
-async fn handle_events(state: ServiceInwayMapState, rx: Receiver<Config>) {
-    loop {
-        match rx.recv().await {
-            Ok(new_config) => {
-                let mut lock = state.write().await;
-                *lock = new_config
-                    .services
-                    .into_iter()
-                    .map(|(name, service)| (name, Arc::new(service.endpoint_url)))
-                    .collect();
+/// Updates the routing table whenever a new config is received
+struct ConfigHandler {
+    state: ServiceInwayMapState,
+    rx: Receiver<Config>,
+    readiness: Readiness,
+    meter: Option<Arc<Meter>>,
+    settings: Arc<InwaySettings>,
+}
 
-                log::info!("inway config updated");
-            }
-            Err(_) => {
-                log::debug!("config channel closed");
-                break;
+#[async_trait]
+impl Task for ConfigHandler {
+    async fn run(&mut self) -> anyhow::Result<()> {
+        loop {
+            match self.rx.recv().await {
+                Ok(new_config) => {
+                    if let Some(meter) = &self.meter {
+                        meter.set_costs(
+                            new_config
+                                .services
+                                .values()
+                                .map(|service| {
+                                    let costs = Costs {
+                                        one_time: service.one_time_costs,
+                                        monthly: service.monthly_costs,
+                                        request: service.request_costs,
+                                    };
+
+                                    (service.name.clone(), costs)
+                                })
+                                .collect(),
+                        );
+                    }
+
+                    let mut lock = self.state.write().await;
+                    *lock = new_config
+                        .services
+                        .into_iter()
+                        .map(|(name, service)| {
+                            // Endpoints in the settings take precedence over the one in management
+                            let endpoints = match self.settings.services.get(&name) {
+                                Some(settings) if !settings.endpoints.is_empty() => {
+                                    settings.endpoints.clone()
+                                }
+                                _ => vec![Endpoint::new(service.endpoint_url)],
+                            };
+
+                            let route = Route {
+                                endpoints,
+                                authorizations: service.authorizations,
+                            };
+
+                            (name, Arc::new(route))
+                        })
+                        .collect();
+
+                    self.readiness.set_config_received();
+
+                    log::info!("inway config updated");
+                }
+                Err(_) => {
+                    log::debug!("config channel closed");
+                    return Ok(());
+                }
             }
         }
     }
@@ -40,90 +115,463 @@ async fn handle_events(state: ServiceInwayMapState, rx: Receiver<Config>) {
 pub struct Health {
     pub healthy: bool,
     pub version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<Probe>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<MaintenanceStatus>,
+    /// Health per endpoint for services with multiple backend endpoints
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub endpoints: Vec<EndpointHealth>,
+}
+
+#[derive(Serialize)]
+pub struct EndpointHealth {
+    pub url: String,
+    pub weight: u32,
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe: Option<Probe>,
+}
+
+/// Returns the health of a backend endpoint, by the periodic health check if there is one
+/// and otherwise by probing it when `probe_backends` is set
+async fn endpoint_health(
+    clients: &BackendClients,
+    backend_health: &BackendHealthState,
+    service: &str,
+    endpoint: &Endpoint,
+    probe_backends: bool,
+) -> EndpointHealth {
+    let checked = {
+        backend_health
+            .read()
+            .unwrap()
+            .get(service)
+            .and_then(|endpoints| endpoints.get(&endpoint.url))
+            .map(|health| (health.healthy, health.last_probe.clone()))
+    };
+    let (healthy, probe) = match checked {
+        Some((healthy, probe)) => (healthy, Some(probe)),
+        None if probe_backends => match endpoint.url.parse() {
+            Ok(uri) => {
+                let probe = health::probe(
+                    &clients.get(service, Protocol::Auto),
+                    uri,
+                    health::PROBE_TIMEOUT,
+                    |status: StatusCode| !status.is_server_error(),
+                )
+                .await;
+
+                (probe.healthy, Some(probe))
+            }
+            Err(e) => {
+                log::warn!("service {} has an invalid endpoint: {}", service, e);
+                (false, None)
+            }
+        },
+        None => (true, None),
+    };
+
+    EndpointHealth {
+        url: endpoint.url.clone(),
+        weight: endpoint.weight,
+        healthy,
+        probe,
+    }
 }
 
 pub struct Server {
     tls_pair: TlsPair,
     rx: Receiver<Config>,
+    supervisor: Supervisor,
+    readiness: Readiness,
+    settings: Arc<InwaySettings>,
+    backend_health: BackendHealthState,
+    probe_backends: bool,
+    admin: Option<Admin>,
 }
 
 impl Server {
-    pub fn new(tls_pair: TlsPair, rx: Receiver<Config>) -> Self {
-        Self { tls_pair, rx }
+    pub fn new(
+        tls_pair: TlsPair,
+        rx: Receiver<Config>,
+        supervisor: Supervisor,
+        readiness: Readiness,
+        settings: Arc<InwaySettings>,
+        backend_health: BackendHealthState,
+        probe_backends: bool,
+    ) -> Self {
+        Self {
+            tls_pair,
+            rx,
+            supervisor,
+            readiness,
+            settings,
+            backend_health,
+            probe_backends,
+            admin: None,
+        }
+    }
+
+    /// Serves the admin API next to the inway
+    pub fn with_admin(mut self, admin: Admin) -> Self {
+        self.admin = Some(admin);
+        self
     }
 
     pub async fn run(self, addr: SocketAddr) -> anyhow::Result<()> {
         let state = ServiceInwayMapState::default();
+        let metering = &self.settings.metering;
+        metering.validate()?;
+        let meter = metering
+            .report_dir
+            .as_ref()
+            .map(|_| Arc::new(Meter::new(Component::Inway)));
 
         // Handle config changes
-        tokio::spawn(handle_events(Arc::clone(&state), self.rx));
+        self.supervisor.spawn(
+            "config_handler",
+            ConfigHandler {
+                state: Arc::clone(&state),
+                rx: self.rx,
+                readiness: self.readiness.clone(),
+                meter: meter.clone(),
+                settings: Arc::clone(&self.settings),
+            },
+        );
+
+        if let Some(meter) = &meter {
+            self.supervisor.spawn(
+                "report_writer",
+                ReportWriter::new(Arc::clone(meter), metering.clone()),
+            );
+        }
 
         // Build warp filters
-        let mut tls_config = ClientConfig::builder()
-            .with_safe_defaults()
-            .with_native_roots()
-            .with_no_client_auth();
-        tls_config.enable_early_data = true;
-        let https = HttpsConnectorBuilder::new()
-            .with_tls_config(tls_config)
-            .https_or_http()
-            .enable_http1()
-            .enable_http2()
-            .build();
-        let client = Client::builder()
-            .retry_canceled_requests(true)
-            .http2_adaptive_window(true)
-            .build(https);
+        let clients = BackendClients::load(Arc::clone(&self.settings)).await?;
+
+        // Check the health of backends
+        if self
+            .settings
+            .services
+            .values()
+            .any(|service| service.health_check.is_some())
+        {
+            self.supervisor.spawn(
+                "health_checker",
+                HealthChecker::new(
+                    clients.clone(),
+                    Arc::clone(&state),
+                    Arc::clone(&self.settings),
+                    Arc::clone(&self.backend_health),
+                ),
+            );
+        }
+
+        let limiter = Arc::new(RateLimiter::load(Arc::clone(&self.settings)).await?);
+
+        if self.settings.quota_file.is_some() {
+            self.supervisor
+                .spawn("quota_writer", QuotaWriter::new(Arc::clone(&limiter)));
+        }
+
+        let credentials = Arc::new(
+            BackendCredentials::load(&self.settings, |service| clients.token_client(service))
+                .await?,
+        );
+        let breakers = CircuitBreakers::new(self.settings.circuit_breaker.clone());
+        let maintenance = Maintenance::new(&self.settings)?;
+
+        for (service, settings) in &self.settings.services {
+            if let Some(mirror) = &settings.mirror {
+                mirror
+                    .validate()
+                    .with_context(|| format!("invalid mirror of service {}", service))?;
+            }
+        }
+        let faults = Faults::new(self.settings.faults.clone())?;
+
+        if let Some(admin) = self.admin {
+            let routes = admin::routes(
+                Arc::clone(&state),
+                breakers.clone(),
+                Arc::clone(&self.backend_health),
+                maintenance.clone(),
+                faults.clone(),
+            );
+            self.supervisor.spawn("admin", admin.server(routes));
+        }
+
+        let settings = Arc::clone(&self.settings);
+        // Organization of the inway, which is the target of every request
+        let provider = self
+            .tls_pair
+            .identity()?
+            .serial_number
+            .map(Arc::<str>::from);
         let with_state = warp::any().map(move || Arc::clone(&state));
-        let with_client = warp::any().map(move || client.clone());
+        let with_clients = warp::any().map(move || clients.clone());
+        let with_breakers = warp::any().map(move || breakers.clone());
+        let with_meter = {
+            let meter = meter.clone();
+            warp::any().map(move || meter.clone())
+        };
+        let with_limiter = {
+            let limiter = Arc::clone(&limiter);
+            warp::any().map(move || Arc::clone(&limiter))
+        };
+        let with_maintenance = {
+            let maintenance = maintenance.clone();
+            warp::any().map(move || maintenance.clone())
+        };
+        let with_credentials = warp::any().map(move || Arc::clone(&credentials));
+        let with_backend_health = {
+            let backend_health = Arc::clone(&self.backend_health);
+            warp::any().map(move || Arc::clone(&backend_health))
+        };
+        let with_settings = warp::any().map(move || Arc::clone(&settings));
 
         // Setup routes
         let proxy = warp::any()
             .and(with_state.clone())
-            .and(with_client)
+            .and(with_clients.clone())
+            .and(with_breakers)
+            .and(with_limiter)
+            .and(with_meter)
+            .and(with_maintenance)
+            .and(with_credentials)
+            .and(with_backend_health.clone())
+            .and(warp::any().map(move || faults.clone()))
+            .and(with_settings)
+            .and(warp::any().map(move || provider.clone()))
             .and(warp::path::param())
             .and(with_request!())
             .and_then(
-                |state: ServiceInwayMapState, client, service: String, request| async move {
-                    let upstream = { state.read().await.get(&service).map(Arc::clone) };
+                |state: ServiceInwayMapState,
+                 clients: BackendClients,
+                 breakers: CircuitBreakers,
+                 limiter: Arc<RateLimiter>,
+                 meter: Option<Arc<Meter>>,
+                 maintenance: Maintenance,
+                 credentials: Arc<BackendCredentials<TracedConnector<BackendConnector>>>,
+                 backend_health: BackendHealthState,
+                 faults: Faults,
+                 settings: Arc<InwaySettings>,
+                 provider: Option<Arc<str>>,
+                 service: String,
+                 request: reverse_proxy::Request| async move {
+                    let mut span = Span::server(
+                        format!("proxy {}", service),
+                        request.method(),
+                        request.headers(),
+                    );
+                    access_log::set_target(provider.as_deref(), &service);
+
+                    let result = span
+                        .scope(async {
+                            let routing = Span::child_of_current("route", Kind::Internal);
+                            let route = { state.read().await.get(&service).map(Arc::clone) };
+                            let route = match route {
+                                Some(route) => route,
+                                None => {
+                                    return Err(warp::reject::custom(RouteError::UnknownService(
+                                        service.clone(),
+                                    )))
+                                }
+                            };
+                            let caller = request.peer().and_then(|peer| peer.identity.clone());
+
+                            access::check(&service, &route, caller.as_deref())?;
+                            maintenance.check(&service)?;
+
+                            let serial_number = caller
+                                .as_ref()
+                                .and_then(|caller| caller.serial_number.as_deref());
+                            let organization = serial_number.unwrap_or("unknown");
+                            let sticky = settings
+                                .services
+                                .get(&service)
+                                .map(|settings| settings.sticky)
+                                .unwrap_or_default();
+                            let endpoint = balancer::select(
+                                &route.endpoints,
+                                |endpoint| {
+                                    health_check::is_endpoint_healthy(
+                                        &backend_health,
+                                        &service,
+                                        &endpoint.url,
+                                    )
+                                },
+                                |endpoint| breakers.is_available(&endpoint.circuit_name(&service)),
+                                serial_number.filter(|_| sticky),
+                            );
+                            let endpoint = match endpoint {
+                                Some(endpoint) => endpoint,
+                                None => {
+                                    return Err(warp::reject::custom(RouteError::NoEndpoints(
+                                        service.clone(),
+                                    )))
+                                }
+                            };
+
+                            let upstream = &endpoint.url;
+
+                            log::debug!("proxy {} to {}: {}", service, upstream, request);
+                            access_log::set_upstream(upstream);
+
+                            limiter.check(organization, &service)?;
+
+                            let timeouts = settings.timeouts(&service);
+                            let client = clients.get(&service, request.protocol());
+                            let circuit = breakers.get(&endpoint.circuit_name(&service));
+
+                            let mut request = request.forwarding(settings.forwarding);
+
+                            if let Some(mirror) = settings
+                                .mirror(&service)
+                                .and_then(|mirror| Mirror::sample(&service, mirror))
+                            {
+                                request = request.mirror(mirror);
+                            }
+
+                            let fault = faults.pick(&service, serial_number);
+
+                            if let Some(result) = faults::intercept(fault.as_ref()) {
+                                return result;
+                            }
+
+                            let rewrite = settings.rewrite(&service);
+                            let variables = Variables::new(&service, &request);
+
+                            if let Some(rewrite) = rewrite {
+                                rewrite.request(&mut request, &variables)?;
+                            }
+
+                            if let Some(value) = credentials.authorization(&service).await? {
+                                request.headers_mut().insert(AUTHORIZATION, value);
+                            }
 
-                    match upstream {
-                        Some(upstream) => {
-                            log::debug!("proxy {}: {}", service, request);
-                            reverse_proxy::handle(client, request, &upstream)
+                            drop(routing);
+
+                            let mut response = faults::inject(fault, async {
+                                reverse_proxy::handle(
+                                    client, request, upstream, &circuit, &timeouts,
+                                )
                                 .await
                                 .map_err(|e| {
                                     log::error!("proxy failed: {:?}", e);
                                     e
                                 })
-                        }
-                        None => Err(warp::reject::not_found()),
-                    }
+                            })
+                            .await?;
+
+                            if let Some(rewrite) = rewrite {
+                                rewrite.response(&mut response, &variables);
+                            }
+
+                            if let Some(meter) = meter {
+                                meter.record(
+                                    organization,
+                                    caller
+                                        .as_ref()
+                                        .and_then(|caller| caller.organization_name.as_deref())
+                                        .unwrap_or_default(),
+                                    &service,
+                                );
+                            }
+
+                            Ok(response)
+                        })
+                        .await;
+
+                    span.set_result(&result);
+                    result
                 },
             );
+        let probe_backends = self.probe_backends;
         let health = warp::get()
             .and(warp::path(".nlx"))
             .and(warp::path("health"))
             .and(with_state)
+            .and(with_clients)
+            .and(with_backend_health)
+            .and(warp::any().map(move || maintenance.clone()))
             .and(warp::path::param())
-            .then(|state: ServiceInwayMapState, service: String| async move {
-                let healthy = { state.read().await.contains_key(&service) };
-
-                warp::reply::json(&Health {
-                    healthy,
-                    version: String::new(),
-                })
-            });
-
-        // Run the server
-        warp::serve(health.or(proxy))
-            .tls()
-            .cert(&self.tls_pair.cert_pem)
-            .key(&self.tls_pair.key_pem)
-            .client_auth_required(self.tls_pair.bundle())
-            .run(addr)
-            .await;
-
-        Ok(())
+            .then(
+                move |state: ServiceInwayMapState,
+                      clients: BackendClients,
+                      backend_health: BackendHealthState,
+                      maintenance: Maintenance,
+                      service: String| async move {
+                    let route = { state.read().await.get(&service).map(Arc::clone) };
+                    let mut endpoints = match route {
+                        Some(route) => {
+                            join_all(route.endpoints.iter().map(|endpoint| {
+                                endpoint_health(
+                                    &clients,
+                                    &backend_health,
+                                    &service,
+                                    endpoint,
+                                    probe_backends,
+                                )
+                            }))
+                            .await
+                        }
+                        None => vec![],
+                    };
+                    let healthy = endpoints.iter().any(|endpoint| endpoint.healthy);
+                    // The probe of a single endpoint is returned as is
+                    let probe = match endpoints.len() {
+                        1 => endpoints.pop().and_then(|endpoint| endpoint.probe),
+                        _ => None,
+                    };
+
+                    // The service stays announced, but consumers should use other inways
+                    let maintenance = maintenance.status(&service);
+                    let healthy = healthy && !matches!(&maintenance, Some(status) if status.active);
+
+                    warp::reply::json(&Health {
+                        healthy,
+                        version: VERSION.to_string(),
+                        probe,
+                        maintenance,
+                        endpoints,
+                    })
+                },
+            );
+        let status = health::routes(self.readiness, self.supervisor.clone());
+
+        // Run the server until the supervisor shuts down
+        let shutdown = self.supervisor.shutdown_token();
+        let routes = status
+            .or(health)
+            .or(proxy)
+            .recover(|rejection| errors::recover(Component::Inway, rejection));
+        let routes = errors::is_grpc().and(routes).map(errors::with_grpc_status);
+        let tls_config = self.tls_pair.server_config()?;
+
+        serve::serve(
+            addr,
+            access_log::service(warp::service(routes)),
+            Some(tls_config),
+            shutdown,
+        )
+        .await?;
+
+        // Keep the quota usage and the usage of the last period, a failure to write one may
+        // not cost the other
+        let saved = limiter.save().await;
+        let reported = match meter {
+            Some(meter) => meter.write_report(&self.settings.metering).await,
+            None => Ok(()),
+        };
+
+        match (saved, reported) {
+            (Err(e), Err(report_error)) => {
+                log::error!("{:#}", report_error);
+                Err(e)
+            }
+            (saved, reported) => saved.and(reported),
+        }
     }
 }
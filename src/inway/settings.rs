@@ -0,0 +1,120 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use serde::Deserialize;
+
+use super::{
+    backend_auth::BackendAuth, backend_tls::BackendTls, config::Endpoint,
+    maintenance::MaintenanceMode, rate_limit::Limits, rewrite::Rewrite,
+};
+use crate::{
+    circuit_breaker::CircuitBreakerSettings, faults::Fault, forwarding::ForwardingSettings,
+    metering::MeteringSettings, mirror::MirrorSettings, timeouts::Timeouts,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnhealthyServices {
+    /// Keep announcing the service, the directory marks it as down through the health endpoint
+    #[default]
+    Announce,
+    /// Leave the service out of the announcement until the backend is healthy again
+    Omit,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InwaySettings {
+    pub unhealthy_services: UnhealthyServices,
+    pub circuit_breaker: CircuitBreakerSettings,
+    pub forwarding: ForwardingSettings,
+    pub metering: MeteringSettings,
+    /// Default timeouts for requests to backends
+    pub timeouts: Timeouts,
+    /// Default limits per consumer organization for every service
+    pub rate_limit: Limits,
+    /// File in which the quota usage is kept across restarts
+    pub quota_file: Option<PathBuf>,
+    pub services: HashMap<String, ServiceSettings>,
+    /// Faults that are injected into requests, for resilience testing
+    pub faults: Vec<Fault>,
+}
+
+impl InwaySettings {
+    /// Returns the effective timeouts for requests to the backend of a service
+    pub fn timeouts(&self, service: &str) -> Timeouts {
+        self.services
+            .get(service)
+            .map(|settings| settings.timeouts)
+            .unwrap_or_default()
+            .or(self.timeouts)
+            .or_default()
+    }
+
+    /// Returns the effective limits per consumer organization for a service
+    pub fn rate_limit(&self, service: &str) -> Limits {
+        self.services
+            .get(service)
+            .map(|settings| settings.rate_limit)
+            .unwrap_or_default()
+            .or(self.rate_limit)
+    }
+
+    pub fn rewrite(&self, service: &str) -> Option<&Rewrite> {
+        self.services
+            .get(service)
+            .and_then(|settings| settings.rewrite.as_ref())
+    }
+
+    pub fn mirror(&self, service: &str) -> Option<&MirrorSettings> {
+        self.services
+            .get(service)
+            .and_then(|settings| settings.mirror.as_ref())
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceSettings {
+    pub health_check: Option<HealthCheck>,
+    pub timeouts: Timeouts,
+    pub rewrite: Option<Rewrite>,
+    pub auth: Option<BackendAuth>,
+    pub tls: Option<BackendTls>,
+    pub rate_limit: Limits,
+    pub maintenance: Option<MaintenanceMode>,
+    /// Endpoints that are used instead of the endpoint URL from NLX Management
+    pub endpoints: Vec<Endpoint>,
+    /// Route every consumer organization to the same endpoint (as long as it is healthy)
+    pub sticky: bool,
+    /// Shadow backend that receives a copy of the requests
+    pub mirror: Option<MirrorSettings>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheck {
+    /// Path relative to the endpoint URL of the service
+    pub path: String,
+    pub expected_status: u16,
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// Number of consecutive successful probes before a backend is considered healthy
+    pub healthy_threshold: u32,
+    /// Number of consecutive failed probes before a backend is considered unhealthy
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        Self {
+            path: String::new(),
+            expected_status: 200,
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(5),
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+}
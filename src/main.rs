@@ -1,25 +1,56 @@
-use std::path::PathBuf;
 use std::{net::SocketAddr, time::Duration};
+use std::{path::PathBuf, sync::Arc};
 
 use anyhow::{Context, Result};
 use async_channel::unbounded;
 use clap::{Parser, ValueEnum};
 use futures_util::TryFutureExt;
+use health::Readiness;
 use pb::{
     directory::directory_client::DirectoryClient, management::management_client::ManagementClient,
 };
+use settings::Settings;
+use supervisor::{RestartConfig, RestartPolicy, Supervisor};
 use tls::TlsPair;
+use tokio::signal;
 use tonic::transport::{Channel, ClientTlsConfig};
+use trace::TracedChannel;
 
-use crate::poller::Poller;
+use crate::{
+    admin::{Admin, ConfigStatus, Controls},
+    errors::Component,
+    poller::Poller,
+};
 
-mod backoff;
+mod access_log;
+mod admin;
+mod circuit_breaker;
+mod client_pool;
+mod errors;
+mod faults;
 mod filters;
+mod forwarding;
+mod grpc;
+mod health;
 mod inway;
+mod metering;
+mod metrics;
+mod mirror;
+mod monitoring;
 mod outway;
+mod path;
 mod poller;
+mod redact;
 mod reverse_proxy;
+mod serve;
+mod settings;
+mod supervisor;
+mod timeouts;
 mod tls;
+mod trace;
+mod tunnel;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub mod pb {
     pub mod management {
@@ -65,6 +96,32 @@ struct Opts {
 
     #[clap(long, env = "MANAGEMENT_API_ADDRESS")]
     management_api_address: String,
+
+    /// Path to a TOML file with gateway specific settings
+    #[clap(long, env = "CONFIG_FILE")]
+    config_file: Option<PathBuf>,
+
+    /// Address to serve metrics and the state of background tasks on
+    #[clap(long, env = "MONITORING_ADDRESS")]
+    monitoring_address: Option<SocketAddr>,
+
+    /// Address to serve the admin API on, clients need a certificate of the internal PKI
+    /// unless it is a loopback address
+    #[clap(long, env = "ADMIN_ADDRESS")]
+    admin_address: Option<SocketAddr>,
+
+    #[clap(long, env = "RESTART_POLICY", value_enum, default_value = "on-failure")]
+    restart_policy: RestartPolicy,
+
+    /// Maximum number of consecutive restarts before a task is marked as failed
+    #[clap(long, env = "RESTART_MAX_RETRIES")]
+    restart_max_retries: Option<usize>,
+
+    #[clap(long, env = "RESTART_INITIAL_BACKOFF", default_value = "500ms", value_parser = humantime::parse_duration)]
+    restart_initial_backoff: Duration,
+
+    #[clap(long, env = "RESTART_MAX_BACKOFF", default_value = "1m", value_parser = humantime::parse_duration)]
+    restart_max_backoff: Duration,
 }
 
 #[derive(Parser)]
@@ -83,6 +140,10 @@ pub struct InwayOpts {
 
     #[clap(long, env = "SELF_ADDRESS")]
     self_address: String,
+
+    /// Actively probe the backend endpoint when the health of a service is requested
+    #[clap(long, env = "HEALTH_PROBE_BACKENDS")]
+    health_probe_backends: bool,
 }
 
 #[derive(Parser)]
@@ -99,65 +160,202 @@ async fn main() -> Result<()> {
     pretty_env_logger::init();
 
     let opts = Opts::parse();
+    let supervisor = Supervisor::new(RestartConfig {
+        policy: opts.restart_policy,
+        max_retries: opts.restart_max_retries,
+        initial_backoff: opts.restart_initial_backoff,
+        max_backoff: opts.restart_max_backoff,
+    });
+
+    if let Some(addr) = opts.monitoring_address {
+        supervisor.spawn(
+            "monitoring",
+            monitoring::Server::new(addr, supervisor.clone()),
+        );
+    }
+
+    tokio::spawn(shutdown_signal(supervisor.clone()));
 
-    let (internal_tls_config, org_tls_pair) = tokio::try_join!(
-        tls::client_config(opts.tls_root_cert, opts.tls_cert, opts.tls_key),
+    let settings = match opts.config_file {
+        Some(path) => Settings::from_file(path).await?,
+        None => Settings::default(),
+    };
+    let component = match opts.cmd {
+        Cmd::Inway(_) => Component::Inway,
+        Cmd::Outway(_) => Component::Outway,
+    };
+
+    redact::init(&settings.redaction)?;
+
+    if let Some(exporter) = trace::init(&settings.tracing, component)? {
+        supervisor.spawn("trace_exporter", exporter);
+    }
+
+    if let Some(writer) = access_log::init(&settings.access_log, component) {
+        supervisor.spawn("access_log", writer);
+    }
+
+    let (internal_tls_pair, org_tls_pair) = tokio::try_join!(
+        TlsPair::from_files(opts.tls_root_cert, opts.tls_cert, opts.tls_key),
         TlsPair::from_files(opts.tls_nlx_root_cert, opts.tls_org_cert, opts.tls_org_key),
     )?;
 
+    let config_status = ConfigStatus::new(match opts.cmd {
+        Cmd::Inway(_) => format!("management API at {}", opts.management_api_address),
+        Cmd::Outway(_) => format!("directory at {}", opts.directory_address),
+    });
+    let controls = Controls::default();
+    let admin = match opts.admin_address {
+        Some(addr) => Some(Admin::new(
+            addr,
+            component,
+            &internal_tls_pair,
+            &org_tls_pair,
+            supervisor.clone(),
+            controls.clone(),
+            config_status.clone(),
+        )?),
+        None => None,
+    };
+
+    // Replay needs no inways, so the outway runs without the directory and management API
+    let standalone = matches!(opts.cmd, Cmd::Outway(_)) && settings.outway.is_replay();
     let (management, directory) = tokio::try_join!(
-        connect(opts.management_api_address, internal_tls_config).map_ok(ManagementClient::new),
-        connect(opts.directory_address, org_tls_pair.client_config()).map_ok(DirectoryClient::new),
+        connect(
+            opts.management_api_address,
+            internal_tls_pair.client_config(),
+            standalone
+        )
+        .map_ok(ManagementClient::new),
+        connect(
+            opts.directory_address,
+            org_tls_pair.client_config(),
+            standalone
+        )
+        .map_ok(DirectoryClient::new),
     )?;
 
+    let readiness = Readiness::new(org_tls_pair.validity()?);
+
     match opts.cmd {
         Cmd::Inway(opts) => {
+            let inway_settings = Arc::new(settings.inway);
+            let backend_health = inway::BackendHealthState::default();
+
             let ((tx, rx), (tx2, rx2)) = (unbounded(), unbounded());
 
-            let mut config_poller = inway::ConfigPoller::new(management.clone(), opts.name.clone());
+            let mut config_poller = inway::ConfigPoller::new(management.clone(), opts.name.clone())
+                .with_status(config_status);
             config_poller.subscribe(tx);
             config_poller.subscribe(tx2);
 
-            let poller = Poller::new(config_poller, Duration::from_secs(10));
-            poller.poll_start();
+            let poller = Poller::new(config_poller, Duration::from_secs(10))
+                .with_trigger(Arc::clone(&controls.refresh_config));
+            supervisor.spawn("config_poller", poller);
 
-            let broadcast =
-                inway::Broadcast::new(management, directory, opts.name, opts.self_address);
-            broadcast.broadcast_start(rx2);
+            let mut broadcast = inway::Broadcast::new(
+                management,
+                directory,
+                opts.name,
+                opts.self_address,
+                rx2,
+                readiness.clone(),
+            )
+            .with_register_trigger(Arc::clone(&controls.register));
+
+            if inway_settings.unhealthy_services == inway::UnhealthyServices::Omit {
+                broadcast = broadcast.omit_unhealthy(Arc::clone(&backend_health));
+            }
+
+            supervisor.spawn("broadcast", broadcast);
 
             log::info!("starting server on {}", opts.listen_address);
 
-            let server = inway::Server::new(org_tls_pair, rx);
+            let mut server = inway::Server::new(
+                org_tls_pair,
+                rx,
+                supervisor.clone(),
+                readiness,
+                inway_settings,
+                backend_health,
+                opts.health_probe_backends,
+            );
+
+            if let Some(admin) = admin {
+                server = server.with_admin(admin);
+            }
+
             server.run(opts.listen_address).await?;
         }
         Cmd::Outway(opts) => {
             let (tx, rx) = unbounded();
 
-            let poller = Poller::new(
-                outway::ConfigPoller::new(directory.clone(), tx),
-                Duration::from_secs(10),
-            );
-            poller.poll_start();
+            if standalone {
+                log::info!("replaying fixtures, the directory and management API are not used");
+                readiness.set_standalone();
+            } else {
+                let poller = Poller::new(
+                    outway::ConfigPoller::new(directory.clone(), tx).with_status(config_status),
+                    Duration::from_secs(10),
+                )
+                .with_trigger(Arc::clone(&controls.refresh_config));
+                supervisor.spawn("config_poller", poller);
+
+                let broadcast = outway::Broadcast::new(
+                    management,
+                    directory,
+                    org_tls_pair.public_key_pem()?,
+                    opts.name,
+                    readiness.clone(),
+                )
+                .with_register_trigger(Arc::clone(&controls.register));
+                supervisor.spawn("broadcast", broadcast);
+            }
 
-            let broadcast = outway::Broadcast::new(
-                management,
-                directory,
-                org_tls_pair.public_key_pem()?,
-                opts.name,
+            log::info!("starting server on {}", opts.listen_address);
+
+            let mut server = outway::Server::new(
+                org_tls_pair,
+                rx,
+                supervisor.clone(),
+                readiness,
+                settings.outway,
             );
-            broadcast.broadcast_start()?;
 
-            log::info!("starting server on {}", opts.listen_address);
+            if let Some(admin) = admin {
+                server = server.with_admin(admin);
+            }
 
-            let server = outway::Server::new(org_tls_pair, rx);
             server.run(opts.listen_address).await?;
         }
     }
 
+    supervisor.shutdown().await;
+
     Ok(())
 }
 
-async fn connect(addr: String, tls_config: ClientTlsConfig) -> Result<Channel> {
+async fn shutdown_signal(supervisor: Supervisor) {
+    let mut terminate = match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            log::error!("failed to listen for SIGTERM: {}", e);
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = signal::ctrl_c() => {},
+        _ = terminate.recv() => {},
+    }
+
+    log::info!("received shutdown signal");
+
+    supervisor.shutdown().await;
+}
+
+/// Connects to a gRPC endpoint, a lazy channel only connects on the first call
+async fn connect(addr: String, tls_config: ClientTlsConfig, lazy: bool) -> Result<TracedChannel> {
     let endpoint = Channel::from_shared(addr)?
         .tls_config(tls_config)
         .with_context(|| "failed to setup TLS config")?
@@ -165,7 +363,11 @@ async fn connect(addr: String, tls_config: ClientTlsConfig) -> Result<Channel> {
         .http2_adaptive_window(true)
         .http2_keep_alive_interval(Duration::from_secs(30));
 
+    if lazy {
+        return Ok(TracedChannel::new(endpoint.connect_lazy()));
+    }
+
     log::debug!("connecting to: {}", endpoint.uri());
 
-    Ok(endpoint.connect().await?)
+    Ok(TracedChannel::new(endpoint.connect().await?))
 }
@@ -0,0 +1,472 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::{fs, time};
+use tonic::async_trait;
+
+use crate::{errors::Component, supervisor::Task};
+
+/// Costs of a service as announced in the directory
+#[derive(Debug, Clone, Copy, Default, Hash, Serialize)]
+pub struct Costs {
+    pub one_time: i32,
+    pub monthly: i32,
+    pub request: i32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl ReportFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Json => "json",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MeteringSettings {
+    /// Directory in which the cost reports are written, requests are not metered when unset
+    pub report_dir: Option<PathBuf>,
+    pub report_format: ReportFormat,
+    /// Length of the period that is covered by a report
+    #[serde(with = "humantime_serde")]
+    pub report_interval: Duration,
+}
+
+impl MeteringSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.report_interval.is_zero() {
+            return Err(anyhow!("report interval must be greater than zero"));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for MeteringSettings {
+    fn default() -> Self {
+        Self {
+            report_dir: None,
+            report_format: ReportFormat::default(),
+            report_interval: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Requests and costs of a service for a single organization, the consumer for the inway
+/// and the provider for the outway
+#[derive(Debug, Serialize)]
+pub struct ReportLine {
+    pub organization: String,
+    pub organization_name: String,
+    pub service: String,
+    pub requests: u64,
+    pub one_time_costs: i32,
+    pub monthly_costs: i32,
+    pub request_costs: i32,
+    /// Number of requests times the costs per request
+    pub total_request_costs: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub component: &'static str,
+    #[serde(serialize_with = "crate::supervisor::serialize_time")]
+    pub period_start: SystemTime,
+    #[serde(serialize_with = "crate::supervisor::serialize_time")]
+    pub period_end: SystemTime,
+    pub lines: Vec<ReportLine>,
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl Report {
+    pub fn to_csv(&self) -> String {
+        let period_start = humantime::format_rfc3339_seconds(self.period_start).to_string();
+        let period_end = humantime::format_rfc3339_seconds(self.period_end).to_string();
+        let mut csv = String::from(
+            "period_start,period_end,organization,organization_name,service,requests,\
+             one_time_costs,monthly_costs,request_costs,total_request_costs\n",
+        );
+
+        for line in &self.lines {
+            let _ = writeln!(
+                csv,
+                "{},{},{},{},{},{},{},{},{},{}",
+                period_start,
+                period_end,
+                csv_field(&line.organization),
+                csv_field(&line.organization_name),
+                csv_field(&line.service),
+                line.requests,
+                line.one_time_costs,
+                line.monthly_costs,
+                line.request_costs,
+                line.total_request_costs
+            );
+        }
+
+        csv
+    }
+}
+
+struct Usage {
+    organization_name: String,
+    requests: u64,
+}
+
+struct Period {
+    start: SystemTime,
+    usage: BTreeMap<(String, String), Usage>,
+}
+
+/// Counts requests per organization and service
+pub struct Meter {
+    component: Component,
+    period: Mutex<Period>,
+    costs: RwLock<HashMap<String, Costs>>,
+}
+
+impl Meter {
+    pub fn new(component: Component) -> Self {
+        Self {
+            component,
+            period: Mutex::new(Period {
+                start: SystemTime::now(),
+                usage: BTreeMap::new(),
+            }),
+            costs: RwLock::default(),
+        }
+    }
+
+    /// Replaces the costs per service (inway) or `<serial number>/<service>` (outway) with
+    /// the figures of the latest config
+    pub fn set_costs(&self, costs: HashMap<String, Costs>) {
+        *self.costs.write().unwrap() = costs;
+    }
+
+    pub fn record(&self, organization: &str, organization_name: &str, service: &str) {
+        let mut period = self.period.lock().unwrap();
+        let usage = period
+            .usage
+            .entry((organization.to_string(), service.to_string()))
+            .or_insert_with(|| Usage {
+                organization_name: String::new(),
+                requests: 0,
+            });
+
+        usage.requests += 1;
+
+        if usage.organization_name.is_empty() {
+            usage.organization_name = organization_name.to_string();
+        }
+    }
+
+    /// Ends the current period and starts a new one
+    fn take_period(&self, now: SystemTime) -> Period {
+        std::mem::replace(
+            &mut *self.period.lock().unwrap(),
+            Period {
+                start: now,
+                usage: BTreeMap::new(),
+            },
+        )
+    }
+
+    /// Merges the usage of a period of which the report could not be written back into the
+    /// current period, so it is part of the next report
+    fn restore(&self, restored: Period) {
+        let mut period = self.period.lock().unwrap();
+        period.start = period.start.min(restored.start);
+
+        for (key, restored) in restored.usage {
+            let usage = period.usage.entry(key).or_insert_with(|| Usage {
+                organization_name: String::new(),
+                requests: 0,
+            });
+
+            usage.requests += restored.requests;
+
+            if usage.organization_name.is_empty() {
+                usage.organization_name = restored.organization_name;
+            }
+        }
+    }
+
+    fn to_report(&self, period: &Period, end: SystemTime) -> Report {
+        let costs = self.costs.read().unwrap();
+
+        let lines = period
+            .usage
+            .iter()
+            .map(|((organization, service), usage)| {
+                let key = match self.component {
+                    Component::Inway => service.clone(),
+                    Component::Outway => format!("{}/{}", organization, service),
+                };
+                let costs = costs.get(&key).copied().unwrap_or_default();
+
+                ReportLine {
+                    organization: organization.clone(),
+                    organization_name: usage.organization_name.clone(),
+                    service: service.clone(),
+                    requests: usage.requests,
+                    one_time_costs: costs.one_time,
+                    monthly_costs: costs.monthly,
+                    request_costs: costs.request,
+                    total_request_costs: usage.requests as i64 * costs.request as i64,
+                }
+            })
+            .collect();
+
+        Report {
+            component: self.component.as_str(),
+            period_start: period.start,
+            period_end: end,
+            lines,
+        }
+    }
+
+    /// Writes the report of the current period to the report directory and starts a new
+    /// period. When the report can't be written its usage is kept for the next report.
+    pub async fn write_report(&self, settings: &MeteringSettings) -> Result<()> {
+        let dir = match &settings.report_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        let now = SystemTime::now();
+        let period = self.take_period(now);
+        let report = self.to_report(&period, now);
+
+        match write(dir, settings.report_format, &report).await {
+            Ok(path) => {
+                log::info!("cost report written to {}", path.display());
+                Ok(())
+            }
+            Err(e) => {
+                self.restore(period);
+                Err(e)
+            }
+        }
+    }
+}
+
+async fn write(dir: &Path, format: ReportFormat, report: &Report) -> Result<PathBuf> {
+    let contents = match format {
+        ReportFormat::Csv => report.to_csv(),
+        ReportFormat::Json => serde_json::to_string_pretty(report)?,
+    };
+    let period_start = humantime::format_rfc3339_seconds(report.period_start)
+        .to_string()
+        .replace(':', "-");
+    let path = dir.join(format!(
+        "{}-usage-{}.{}",
+        report.component,
+        period_start,
+        format.extension()
+    ));
+
+    fs::write(&path, contents)
+        .await
+        .with_context(|| format!("failed to write cost report to {}", path.display()))?;
+
+    Ok(path)
+}
+
+/// Periodically writes a cost report
+pub struct ReportWriter {
+    meter: Arc<Meter>,
+    settings: MeteringSettings,
+}
+
+impl ReportWriter {
+    pub fn new(meter: Arc<Meter>, settings: MeteringSettings) -> Self {
+        Self { meter, settings }
+    }
+}
+
+#[async_trait]
+impl Task for ReportWriter {
+    async fn run(&mut self) -> Result<()> {
+        let start = time::Instant::now() + self.settings.report_interval;
+        let mut interval = time::interval_at(start, self.settings.report_interval);
+
+        loop {
+            interval.tick().await;
+            self.meter.write_report(&self.settings).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    fn costs(request: i32) -> Costs {
+        Costs {
+            one_time: 100,
+            monthly: 10,
+            request,
+        }
+    }
+
+    #[test]
+    fn reports_requests_with_costs_per_service() {
+        let meter = Meter::new(Component::Inway);
+        meter.set_costs(HashMap::from([("basisregister".to_string(), costs(2))]));
+
+        meter.record("00000001", "Gemeente Stijns", "basisregister");
+        meter.record("00000001", "", "basisregister");
+        meter.record("00000002", "RvRD", "other");
+
+        let now = SystemTime::now();
+        let report = meter.to_report(&meter.take_period(now), now);
+
+        assert_eq!(report.component, "nlx-inway");
+        assert_eq!(report.lines.len(), 2);
+
+        let line = &report.lines[0];
+        assert_eq!(
+            (line.organization.as_str(), line.organization_name.as_str()),
+            ("00000001", "Gemeente Stijns")
+        );
+        assert_eq!((line.requests, line.total_request_costs), (2, 4));
+        assert_eq!((line.one_time_costs, line.monthly_costs), (100, 10));
+
+        // Services without known costs are reported at zero costs
+        let line = &report.lines[1];
+        assert_eq!((line.requests, line.request_costs), (1, 0));
+
+        // The next period starts empty
+        assert!(meter
+            .to_report(&meter.take_period(now), now)
+            .lines
+            .is_empty());
+    }
+
+    #[test]
+    fn outway_costs_are_keyed_on_organization_and_service() {
+        let meter = Meter::new(Component::Outway);
+        meter.set_costs(HashMap::from([(
+            "00000001/basisregister".to_string(),
+            costs(3),
+        )]));
+
+        meter.record("00000001", "", "basisregister");
+        meter.record("00000002", "", "basisregister");
+
+        let now = SystemTime::now();
+        let report = meter.to_report(&meter.take_period(now), now);
+
+        assert_eq!(report.lines[0].total_request_costs, 3);
+        assert_eq!(report.lines[1].total_request_costs, 0);
+    }
+
+    #[test]
+    fn writes_csv_with_quoted_fields() {
+        let report = Report {
+            component: "nlx-inway",
+            period_start: UNIX_EPOCH,
+            period_end: UNIX_EPOCH + Duration::from_secs(86400),
+            lines: vec![ReportLine {
+                organization: "00000001".to_string(),
+                organization_name: "Stijns, \"de\" gemeente".to_string(),
+                service: "basisregister".to_string(),
+                requests: 5,
+                one_time_costs: 100,
+                monthly_costs: 10,
+                request_costs: 2,
+                total_request_costs: 10,
+            }],
+        };
+
+        assert_eq!(
+            report.to_csv(),
+            "period_start,period_end,organization,organization_name,service,requests,\
+             one_time_costs,monthly_costs,request_costs,total_request_costs\n\
+             1970-01-01T00:00:00Z,1970-01-02T00:00:00Z,00000001,\
+             \"Stijns, \"\"de\"\" gemeente\",basisregister,5,100,10,2,10\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_usage_when_the_report_fails() {
+        let meter = Meter::new(Component::Inway);
+        let settings = MeteringSettings {
+            report_dir: Some(PathBuf::from("/nonexistent/reports")),
+            ..Default::default()
+        };
+
+        meter.record("00000001", "Gemeente Stijns", "basisregister");
+        let start = meter.period.lock().unwrap().start;
+
+        assert!(meter.write_report(&settings).await.is_err());
+
+        meter.record("00000001", "", "basisregister");
+
+        let now = SystemTime::now();
+        let period = meter.take_period(now);
+        let report = meter.to_report(&period, now);
+
+        assert_eq!(period.start, start);
+        assert_eq!(report.lines[0].requests, 2);
+        assert_eq!(report.lines[0].organization_name, "Gemeente Stijns");
+    }
+
+    #[tokio::test]
+    async fn writes_the_report_and_starts_a_new_period() {
+        let dir = std::env::temp_dir().join(format!("nlx-metering-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let meter = Meter::new(Component::Outway);
+        let settings = MeteringSettings {
+            report_dir: Some(dir.clone()),
+            report_format: ReportFormat::Json,
+            ..Default::default()
+        };
+
+        meter.record("00000001", "", "basisregister");
+        meter.write_report(&settings).await.unwrap();
+
+        let files = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(files, 1);
+        assert!(meter.period.lock().unwrap().usage.is_empty());
+    }
+
+    #[test]
+    fn rejects_a_zero_report_interval() {
+        let settings = MeteringSettings {
+            report_interval: Duration::ZERO,
+            ..Default::default()
+        };
+
+        assert!(settings.validate().is_err());
+        assert!(MeteringSettings::default().validate().is_ok());
+    }
+}
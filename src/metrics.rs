@@ -0,0 +1,158 @@
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder,
+    HistogramVec, IntCounterVec, IntGaugeVec, TextEncoder,
+};
+
+pub static TASK_STATE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nlx_task_state",
+        "Current state of a background task (1 if the task is in the given state)",
+        &["task", "state"]
+    )
+    .expect("failed to register nlx_task_state")
+});
+
+pub static TASK_RESTARTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nlx_task_restarts_total",
+        "Number of times a background task was restarted",
+        &["task"]
+    )
+    .expect("failed to register nlx_task_restarts_total")
+});
+
+pub static BACKEND_UP: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nlx_backend_up",
+        "Whether the backend of a service is considered healthy",
+        &["service"]
+    )
+    .expect("failed to register nlx_backend_up")
+});
+
+pub static BACKEND_PROBES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nlx_backend_probes_total",
+        "Number of health probes sent to the backend of a service",
+        &["service", "result"]
+    )
+    .expect("failed to register nlx_backend_probes_total")
+});
+
+pub static BACKEND_PROBE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "nlx_backend_probe_duration_seconds",
+        "Latency of health probes sent to the backend of a service",
+        &["service"]
+    )
+    .expect("failed to register nlx_backend_probe_duration_seconds")
+});
+
+pub static INWAY_UP: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nlx_inway_up",
+        "Whether a remote inway reports a service as healthy when probed by the outway",
+        &["organization", "service", "address"]
+    )
+    .expect("failed to register nlx_inway_up")
+});
+
+pub static INWAY_PROBES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nlx_inway_probes_total",
+        "Number of health probes sent to remote inways",
+        &["organization", "service", "result"]
+    )
+    .expect("failed to register nlx_inway_probes_total")
+});
+
+pub static INWAY_PROBE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "nlx_inway_probe_duration_seconds",
+        "Latency of health probes sent to remote inways",
+        &["organization", "service"]
+    )
+    .expect("failed to register nlx_inway_probe_duration_seconds")
+});
+
+pub static CIRCUIT_STATE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nlx_circuit_state",
+        "State of the circuit breaker of an upstream (0 = closed, 1 = open, 2 = half-open)",
+        &["upstream"]
+    )
+    .expect("failed to register nlx_circuit_state")
+});
+
+pub static CIRCUIT_TRANSITIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nlx_circuit_transitions_total",
+        "Number of state changes of the circuit breaker of an upstream",
+        &["upstream", "state"]
+    )
+    .expect("failed to register nlx_circuit_transitions_total")
+});
+
+pub static CIRCUIT_REJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nlx_circuit_rejected_total",
+        "Number of requests rejected because the circuit of the upstream is open",
+        &["upstream"]
+    )
+    .expect("failed to register nlx_circuit_rejected_total")
+});
+
+pub static RATE_LIMIT_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nlx_rate_limit_requests_total",
+        "Number of requests checked against the rate limits of a consumer organization",
+        &["organization", "service", "result"]
+    )
+    .expect("failed to register nlx_rate_limit_requests_total")
+});
+
+pub static QUOTA_USED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "nlx_quota_used",
+        "Number of requests a consumer organization made today, counted against its daily quota",
+        &["organization", "service"]
+    )
+    .expect("failed to register nlx_quota_used")
+});
+
+pub static MIRROR_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nlx_mirror_requests_total",
+        "Number of requests copied to the mirror of a service, by how its response compared",
+        &["service", "result"]
+    )
+    .expect("failed to register nlx_mirror_requests_total")
+});
+
+pub static MIRROR_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "nlx_mirror_duration_seconds",
+        "Latency of mirrored requests on the primary backend and on the mirror",
+        &["service", "target"]
+    )
+    .expect("failed to register nlx_mirror_duration_seconds")
+});
+
+pub static FAULTS_INJECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "nlx_faults_injected_total",
+        "Number of requests into which a fault was injected",
+        &["service", "fault"]
+    )
+    .expect("failed to register nlx_faults_injected_total")
+});
+
+/// Encodes all registered metrics in the Prometheus text format
+pub fn encode() -> Result<String> {
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+
+    Ok(String::from_utf8(buffer)?)
+}
@@ -0,0 +1,223 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http::{HeaderMap, Method, StatusCode, Uri};
+use hyper::{body::HttpBody, client::HttpConnector, Body, Client};
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use rustls::ClientConfig;
+use serde::Deserialize;
+use tokio::{
+    sync::{oneshot, Semaphore},
+    time,
+};
+
+use crate::{metrics, redact};
+
+/// Mirrored requests that may be in flight at the same time over all services, requests
+/// are not mirrored while the limit is reached
+const MAX_IN_FLIGHT: usize = 256;
+
+static IN_FLIGHT: Lazy<Arc<Semaphore>> = Lazy::new(|| Arc::new(Semaphore::new(MAX_IN_FLIGHT)));
+
+/// Client of the mirrors, which never gets the client certificate or the TLS settings of
+/// the primary backend
+static CLIENT: Lazy<Client<HttpsConnector<HttpConnector>>> = Lazy::new(|| {
+    let tls_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_native_roots()
+        .with_no_client_auth();
+    let https = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build();
+
+    Client::builder().build(https)
+});
+
+fn default_percentage() -> f64 {
+    100.0
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorSettings {
+    /// Base URL of the shadow backend, requests are sent to the same path as on the primary
+    pub url: String,
+    /// Percentage of the requests that is mirrored
+    #[serde(default = "default_percentage")]
+    pub percentage: f64,
+    /// Mirrored requests are abandoned after this long
+    #[serde(default = "default_timeout", with = "humantime_serde")]
+    pub timeout: Duration,
+}
+
+impl MirrorSettings {
+    pub fn validate(&self) -> Result<()> {
+        let uri = self
+            .url
+            .parse::<Uri>()
+            .map_err(|e| anyhow!("invalid mirror URL {}: {}", self.url, e))?;
+
+        if uri.scheme().is_none() || uri.authority().is_none() {
+            return Err(anyhow!("mirror URL {} must be absolute", self.url));
+        }
+
+        if !(0.0..=100.0).contains(&self.percentage) {
+            return Err(anyhow!("mirror percentage must be between 0 and 100"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Status of a response or the reason there was none
+type Outcome = Result<StatusCode, String>;
+
+/// Shadow upstream that receives a copy of a sampled request
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    name: String,
+    url: String,
+    timeout: Duration,
+}
+
+impl Mirror {
+    /// Returns the mirror for the configured percentage of the requests
+    pub fn sample(name: &str, settings: &MirrorSettings) -> Option<Self> {
+        if rand::thread_rng().gen::<f64>() * 100.0 >= settings.percentage {
+            return None;
+        }
+
+        Some(Self {
+            name: name.to_string(),
+            url: settings.url.clone(),
+            timeout: settings.timeout,
+        })
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Sends the copy of the request in the background, the response is discarded and only
+    /// compared with the response of the primary upstream once it is passed to the shadow.
+    /// Credentials and the other sensitive headers of the primary request are left out.
+    /// Returns None when too many mirrored requests are in flight already.
+    pub fn spawn(
+        self,
+        method: Method,
+        uri: Uri,
+        mut headers: HeaderMap,
+        body: Bytes,
+    ) -> Option<Shadow> {
+        let permit = match Arc::clone(&IN_FLIGHT).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::debug!("skipped mirror of {}: too many in flight", self.name);
+                metrics::MIRROR_REQUESTS
+                    .with_label_values(&[&self.name, "skipped"])
+                    .inc();
+                return None;
+            }
+        };
+        let (tx, rx) = oneshot::channel();
+
+        redact::remove_headers(&mut headers);
+
+        let mut request = hyper::Request::new(Body::from(body));
+        *request.method_mut() = method;
+        *request.uri_mut() = uri;
+        *request.headers_mut() = headers;
+
+        tokio::spawn(async move {
+            let start = Instant::now();
+            let mirrored = match time::timeout(self.timeout, CLIENT.request(request)).await {
+                Ok(Ok(response)) => {
+                    let status = response.status();
+                    let latency = start.elapsed();
+                    let mut body = response.into_body();
+
+                    // Drain the body so the connection can be reused
+                    let _ = time::timeout(self.timeout.saturating_sub(latency), async {
+                        while let Some(Ok(_)) = body.data().await {}
+                    })
+                    .await;
+
+                    (Ok(status), latency)
+                }
+                Ok(Err(e)) => (Err(e.to_string()), start.elapsed()),
+                Err(_) => (Err("timed out".to_string()), start.elapsed()),
+            };
+
+            drop(permit);
+
+            // The primary request is dropped when it failed before it was sent
+            if let Ok(primary) = rx.await {
+                self.compare(primary, mirrored);
+            }
+        });
+
+        Some(Shadow { tx })
+    }
+
+    fn compare(&self, primary: (Outcome, Duration), mirrored: (Outcome, Duration)) {
+        let (primary, primary_latency) = primary;
+        let (mirrored, mirrored_latency) = mirrored;
+        let result = match (&primary, &mirrored) {
+            (Ok(a), Ok(b)) if a == b => "match",
+            (_, Err(_)) => "error",
+            _ => "mismatch",
+        };
+        let describe = |outcome: &Outcome| match outcome {
+            Ok(status) => status.to_string(),
+            Err(e) => e.clone(),
+        };
+
+        metrics::MIRROR_REQUESTS
+            .with_label_values(&[&self.name, result])
+            .inc();
+
+        for (target, latency) in [("primary", primary_latency), ("mirror", mirrored_latency)] {
+            metrics::MIRROR_DURATION
+                .with_label_values(&[&self.name, target])
+                .observe(latency.as_secs_f64());
+        }
+
+        let message = format!(
+            "mirror of {} returned {} in {}ms, primary returned {} in {}ms",
+            self.name,
+            describe(&mirrored),
+            mirrored_latency.as_millis(),
+            describe(&primary),
+            primary_latency.as_millis()
+        );
+
+        match result {
+            "match" => log::debug!("{}", message),
+            _ => log::info!("{}", message),
+        }
+    }
+}
+
+/// Handle of a mirrored request that is waiting for the response of the primary upstream
+pub struct Shadow {
+    tx: oneshot::Sender<(Outcome, Duration)>,
+}
+
+impl Shadow {
+    pub fn complete(self, primary: Result<StatusCode, String>, latency: Duration) {
+        let _ = self.tx.send((primary, latency));
+    }
+}
@@ -0,0 +1,69 @@
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use anyhow::Result;
+use serde::Serialize;
+use tonic::async_trait;
+use warp::{http::StatusCode, Filter};
+
+use crate::{
+    metrics,
+    supervisor::{Supervisor, Task, TaskState},
+};
+
+#[derive(Serialize)]
+pub struct Health {
+    pub healthy: bool,
+    pub tasks: BTreeMap<&'static str, TaskState>,
+}
+
+/// Serves metrics and the state of the background tasks
+pub struct Server {
+    addr: SocketAddr,
+    supervisor: Supervisor,
+}
+
+impl Server {
+    pub fn new(addr: SocketAddr, supervisor: Supervisor) -> Self {
+        Self { addr, supervisor }
+    }
+}
+
+#[async_trait]
+impl Task for Server {
+    async fn run(&mut self) -> Result<()> {
+        let supervisor = self.supervisor.clone();
+        let health = warp::get()
+            .and(warp::path("health"))
+            .and(warp::path::end())
+            .map(move || {
+                let healthy = supervisor.is_healthy();
+                let reply = warp::reply::json(&Health {
+                    healthy,
+                    tasks: supervisor.states(),
+                });
+
+                if healthy {
+                    warp::reply::with_status(reply, StatusCode::OK)
+                } else {
+                    warp::reply::with_status(reply, StatusCode::SERVICE_UNAVAILABLE)
+                }
+            });
+        let metrics = warp::get()
+            .and(warp::path("metrics"))
+            .and(warp::path::end())
+            .map(|| match metrics::encode() {
+                Ok(body) => warp::reply::with_status(body, StatusCode::OK),
+                Err(e) => {
+                    log::error!("failed to encode metrics: {}", e);
+                    warp::reply::with_status(String::new(), StatusCode::INTERNAL_SERVER_ERROR)
+                }
+            });
+
+        log::info!("starting monitoring server on {}", self.addr);
+
+        let (_, server) = warp::serve(health.or(metrics)).try_bind_ephemeral(self.addr)?;
+        server.await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use warp::{filters::BoxedFilter, reply::Response, Filter, Reply};
+
+use crate::{
+    circuit_breaker::{CircuitBreakers, CircuitState},
+    errors::Component,
+    faults::{self, Faults},
+};
+
+use super::{
+    config::State,
+    inway_probe::{InwayHealth, InwayHealthState},
+    server::ServiceInwaysState,
+};
+
+#[derive(Serialize)]
+struct Inway {
+    address: String,
+    endpoint: String,
+    directory_state: State,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    observed: Option<InwayHealth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    circuit: Option<CircuitState>,
+}
+
+#[derive(Serialize)]
+struct Route {
+    organization_name: String,
+    inways: Vec<Inway>,
+}
+
+/// Routes per OIN and service
+type RoutingTable = BTreeMap<String, BTreeMap<String, Route>>;
+
+async fn routing_table(
+    state: &ServiceInwaysState,
+    breakers: &CircuitBreakers,
+    inway_health: &InwayHealthState,
+) -> RoutingTable {
+    let routes = state.read().await;
+    let inway_health = inway_health.read().unwrap();
+
+    routes
+        .iter()
+        .map(|(oin, services)| {
+            let services = services
+                .iter()
+                .map(|(name, route)| {
+                    let inways = route
+                        .inways
+                        .iter()
+                        .map(|inway| Inway {
+                            address: inway.address.clone(),
+                            endpoint: inway.endpoint.to_string(),
+                            directory_state: inway.state.clone(),
+                            observed: inway_health.get(inway.endpoint.as_str()).cloned(),
+                            circuit: breakers.state(&inway.endpoint),
+                        })
+                        .collect();
+                    let route = Route {
+                        organization_name: route.organization_name.clone(),
+                        inways,
+                    };
+
+                    (name.clone(), route)
+                })
+                .collect();
+
+            (oin.clone(), services)
+        })
+        .collect()
+}
+
+/// Admin routes to inspect the routing table and inject faults
+pub fn routes(
+    state: ServiceInwaysState,
+    breakers: CircuitBreakers,
+    inway_health: InwayHealthState,
+    faults: Faults,
+) -> BoxedFilter<(Response,)> {
+    let routing = warp::get()
+        .and(warp::path("routes"))
+        .and(warp::path::end())
+        .and(warp::any().map(move || state.clone()))
+        .and(warp::any().map(move || breakers.clone()))
+        .and(warp::any().map(move || inway_health.clone()))
+        .then(
+            |state: ServiceInwaysState,
+             breakers: CircuitBreakers,
+             inway_health: InwayHealthState| async move {
+                let table = routing_table(&state, &breakers, &inway_health).await;
+                warp::reply::json(&table).into_response()
+            },
+        );
+
+    routing
+        .or(faults::routes(faults, Component::Outway))
+        .unify()
+        .boxed()
+}
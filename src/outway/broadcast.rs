@@ -1,42 +1,55 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
-use tokio::{task::JoinHandle, time};
-use tonic::transport::Channel;
+use tokio::{sync::Notify, time};
+use tonic::async_trait;
 
 use crate::{
-    backoff::retry_backoff,
+    health::Readiness,
     pb::{
         directory::{self, directory_client::DirectoryClient},
         management::{self, management_client::ManagementClient},
     },
+    supervisor::Task,
+    trace::TracedChannel,
+    VERSION,
 };
 
 const REGISTRATION_INTERVAL: Duration = Duration::from_secs(10);
-const VERSION: &str = env!("CARGO_PKG_VERSION");
 
 pub struct Broadcast {
     outway_name: String,
     public_key_pem: String,
-    management: ManagementClient<Channel>,
-    directory: DirectoryClient<Channel>,
+    management: ManagementClient<TracedChannel>,
+    directory: DirectoryClient<TracedChannel>,
+    readiness: Readiness,
+    register: Arc<Notify>,
 }
 
 impl Broadcast {
     pub fn new(
-        management: ManagementClient<Channel>,
-        directory: DirectoryClient<Channel>,
+        management: ManagementClient<TracedChannel>,
+        directory: DirectoryClient<TracedChannel>,
         public_key_pem: String,
         outway_name: String,
+        readiness: Readiness,
     ) -> Self {
         Self {
             management,
             directory,
             public_key_pem,
             outway_name,
+            readiness,
+            register: Arc::default(),
         }
     }
 
+    /// Registers the outway right away whenever the trigger is notified
+    pub fn with_register_trigger(mut self, trigger: Arc<Notify>) -> Self {
+        self.register = trigger;
+        self
+    }
+
     async fn announce(&mut self) -> Result<()> {
         log::trace!("announcing outway");
 
@@ -64,20 +77,21 @@ impl Broadcast {
         let mut announce_interval = time::interval(REGISTRATION_INTERVAL);
 
         loop {
-            announce_interval.tick().await;
+            tokio::select! {
+                _ = announce_interval.tick() => {}
+                _ = self.register.notified() => announce_interval.reset(),
+            }
+
             self.announce().await?;
+            self.readiness.set_registered();
         }
     }
+}
 
-    pub fn broadcast_start(mut self) -> Result<JoinHandle<()>> {
+#[async_trait]
+impl Task for Broadcast {
+    async fn run(&mut self) -> Result<()> {
         log::info!("start broadcasting");
-
-        Ok(tokio::spawn(async move {
-            retry_backoff!(self.broadcast(), |err, duration: Duration| log::warn!(
-                "broadcast failed: {:?}, retrying in {}s",
-                err,
-                duration.as_secs()
-            ));
-        }))
+        self.broadcast().await
     }
 }
@@ -1,8 +1,12 @@
 use std::{collections::HashMap, hash::Hash, sync::Arc};
 
+use serde::Serialize;
 use wyhash2::WyHash;
 
-#[derive(Debug, Clone, Hash, PartialEq)]
+pub use crate::metering::Costs;
+
+#[derive(Debug, Clone, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum State {
     Unknown = 0,
     Up = 1,
@@ -25,13 +29,6 @@ pub struct Inway {
     pub state: State,
 }
 
-#[derive(Debug, Clone, Hash)]
-pub struct Costs {
-    pub one_time: i32,
-    pub monthly: i32,
-    pub request: i32,
-}
-
 #[derive(Debug, Clone, Hash)]
 pub struct Organization {
     pub name: String,
@@ -64,5 +61,19 @@ impl Hash for Config {
     }
 }
 
-/// Maps an OIN to services to an Inway endpoint
-pub type ServiceInways = HashMap<String, HashMap<String, Option<Arc<String>>, WyHash>, WyHash>;
+#[derive(Debug, Clone)]
+pub struct RouteInway {
+    pub address: String,
+    /// Base URL of the service on this inway
+    pub endpoint: Arc<String>,
+    pub state: State,
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub organization_name: String,
+    pub inways: Vec<RouteInway>,
+}
+
+/// Maps an OIN to services to the Inways offering them
+pub type ServiceInways = HashMap<String, HashMap<String, Route, WyHash>, WyHash>;
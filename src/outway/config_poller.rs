@@ -6,11 +6,13 @@ use std::{
 use anyhow::Result;
 use async_channel::Sender;
 use itertools::Itertools;
-use tonic::{async_trait, transport::Channel};
+use tonic::async_trait;
 
 use crate::{
+    admin::ConfigStatus,
     pb::directory::{directory_client::DirectoryClient, ListServicesRequest, ListServicesResponse},
     poller::Poll,
+    trace::TracedChannel,
 };
 
 use super::{
@@ -89,17 +91,25 @@ fn map_config(response: ListServicesResponse) -> Config {
 pub struct ConfigPoller {
     tx: Sender<Config>,
     config_hash: Option<u64>,
-    directory: DirectoryClient<Channel>,
+    status: Option<ConfigStatus>,
+    directory: DirectoryClient<TracedChannel>,
 }
 
 impl ConfigPoller {
-    pub fn new(directory: DirectoryClient<Channel>, tx: Sender<Config>) -> Self {
+    pub fn new(directory: DirectoryClient<TracedChannel>, tx: Sender<Config>) -> Self {
         Self {
             tx,
             config_hash: None,
+            status: None,
             directory,
         }
     }
+
+    /// Keeps the version of the config up to date in `status`
+    pub fn with_status(mut self, status: ConfigStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
 }
 
 #[async_trait]
@@ -118,6 +128,10 @@ impl Poll for ConfigPoller {
             log::debug!("config changed");
             self.tx.send(config).await?;
             self.config_hash = Some(new_hash);
+
+            if let Some(status) = &self.status {
+                status.set_version(new_hash);
+            }
         }
 
         Ok(())
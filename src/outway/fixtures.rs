@@ -0,0 +1,469 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use http::{HeaderMap, StatusCode};
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, sync::Mutex};
+use warp::{
+    reject::{self, Reject},
+    reply::Response,
+    Rejection,
+};
+
+use crate::reverse_proxy::{self, ProxyError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FixtureMode {
+    /// Proxy requests to inways and write the responses to the fixture files
+    Record,
+    /// Answer requests from the fixture files, inways are never contacted
+    Replay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchOn {
+    Method,
+    Path,
+    Query,
+    /// Hash of the request body
+    Body,
+}
+
+fn default_match_on() -> Vec<MatchOn> {
+    vec![
+        MatchOn::Method,
+        MatchOn::Path,
+        MatchOn::Query,
+        MatchOn::Body,
+    ]
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureSettings {
+    pub mode: FixtureMode,
+    /// Directory with a fixture file per service, `<dir>/<serial number>/<service>.json`
+    pub dir: PathBuf,
+    /// Properties of a request that must be equal to those of a recorded request
+    #[serde(default = "default_match_on")]
+    pub match_on: Vec<MatchOn>,
+}
+
+#[derive(Debug)]
+pub enum FixtureError {
+    /// No response was recorded for the request
+    NotFound(String),
+    Invalid(String),
+}
+
+impl Reject for FixtureError {}
+
+impl Display for FixtureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(request) => write!(f, "no recorded response for {}", request),
+            Self::Invalid(e) => write!(f, "invalid fixture: {}", e),
+        }
+    }
+}
+
+/// Properties of a request that are compared with the recorded requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    method: String,
+    /// Path relative to the service, without a leading slash
+    path: String,
+    query: String,
+    body_hash: String,
+}
+
+impl RecordedRequest {
+    pub fn new(request: &reverse_proxy::Request, body: &[u8]) -> Self {
+        Self {
+            method: request.method().to_string(),
+            path: request.path().to_string(),
+            query: request.query().to_string(),
+            body_hash: format!("{:016x}", wyhash2::wyhash_single(body, 0)),
+        }
+    }
+
+    fn matches(&self, other: &Self, match_on: &[MatchOn]) -> bool {
+        match_on.iter().all(|field| match field {
+            MatchOn::Method => self.method == other.method,
+            MatchOn::Path => self.path == other.path,
+            MatchOn::Query => self.query == other.query,
+            MatchOn::Body => self.body_hash == other.body_hash,
+        })
+    }
+}
+
+impl Display for RecordedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} /{}", self.method, self.path)?;
+
+        if !self.query.is_empty() {
+            write!(f, "?{}", self.query)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Recorded response, text bodies are kept as is so fixtures can be edited by hand
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_base64: Option<String>,
+}
+
+impl RecordedResponse {
+    fn new(status: StatusCode, headers: &HeaderMap, body: &[u8]) -> Self {
+        let (body, body_base64) = match std::str::from_utf8(body) {
+            Ok(text) => (Some(text.to_string()), None),
+            Err(_) => (None, Some(base64::encode(body))),
+        };
+
+        Self {
+            status: status.as_u16(),
+            headers: headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect(),
+            body,
+            body_base64,
+        }
+    }
+
+    fn to_response(&self) -> Result<Response> {
+        let body = match (&self.body, &self.body_base64) {
+            (_, Some(encoded)) => base64::decode(encoded)?,
+            (Some(text), None) => text.clone().into_bytes(),
+            (None, None) => vec![],
+        };
+        let mut builder = http::Response::builder().status(self.status);
+
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+
+        Ok(builder.body(Body::from(body))?)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Exchange {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+/// Records responses of inways to fixture files or replays them
+pub struct Fixtures {
+    settings: FixtureSettings,
+    /// Exchanges per fixture file, read on first use
+    exchanges: Mutex<HashMap<PathBuf, Vec<Exchange>>>,
+}
+
+impl Fixtures {
+    pub fn new(settings: FixtureSettings) -> Self {
+        Self {
+            settings,
+            exchanges: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn mode(&self) -> FixtureMode {
+        self.settings.mode
+    }
+
+    /// Returns the fixture file of a service, names that would escape the directory are refused
+    fn path(&self, oin: &str, service: &str) -> Option<PathBuf> {
+        let is_safe = |name: &str| {
+            !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+        };
+
+        if !is_safe(oin) || !is_safe(service) {
+            return None;
+        }
+
+        Some(
+            self.settings
+                .dir
+                .join(oin)
+                .join(format!("{}.json", service)),
+        )
+    }
+
+    async fn load<'a>(
+        exchanges: &'a mut HashMap<PathBuf, Vec<Exchange>>,
+        path: &Path,
+    ) -> Result<&'a mut Vec<Exchange>> {
+        if !exchanges.contains_key(path) {
+            let recorded = match fs::read(path).await {
+                Ok(contents) => serde_json::from_slice(&contents)
+                    .with_context(|| format!("invalid fixture file {}", path.display()))?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to read {}", path.display()))
+                }
+            };
+
+            exchanges.insert(path.to_path_buf(), recorded);
+        }
+
+        Ok(exchanges.get_mut(path).expect("fixtures are loaded"))
+    }
+
+    /// Returns the first recorded response of which the request matches
+    pub async fn replay(
+        &self,
+        oin: &str,
+        service: &str,
+        request: &RecordedRequest,
+    ) -> Result<Response, FixtureError> {
+        let not_found = || FixtureError::NotFound(format!("{} of {}/{}", request, oin, service));
+        let path = self.path(oin, service).ok_or_else(not_found)?;
+        let mut exchanges = self.exchanges.lock().await;
+        let exchanges = Self::load(&mut exchanges, &path)
+            .await
+            .map_err(|e| FixtureError::Invalid(format!("{:#}", e)))?;
+
+        let exchange = exchanges
+            .iter()
+            .find(|exchange| exchange.request.matches(request, &self.settings.match_on))
+            .ok_or_else(not_found)?;
+
+        log::debug!("replaying {} of {}/{}", request, oin, service);
+
+        exchange
+            .response
+            .to_response()
+            .map_err(|e| FixtureError::Invalid(format!("{} in {}", e, path.display())))
+    }
+
+    /// Writes the response to the fixture file of the service, a previous recording of the
+    /// same request is replaced. The response is buffered to do so.
+    pub async fn record(
+        &self,
+        oin: &str,
+        service: &str,
+        request: RecordedRequest,
+        response: Response,
+    ) -> Result<Response, Rejection> {
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(|e| reject::custom(ProxyError::Hyper(e)))?;
+
+        if let Some(path) = self.path(oin, service) {
+            let exchange = Exchange {
+                response: RecordedResponse::new(parts.status, &parts.headers, &body),
+                request,
+            };
+
+            if let Err(e) = self.write(&path, exchange).await {
+                log::error!("failed to record response of {}/{}: {:#}", oin, service, e);
+            }
+        }
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    async fn write(&self, path: &Path, exchange: Exchange) -> Result<()> {
+        let mut exchanges = self.exchanges.lock().await;
+        let exchanges = Self::load(&mut exchanges, path).await?;
+
+        // Every field is compared, so a fixture is only replaced by the same request
+        match exchanges
+            .iter_mut()
+            .find(|recorded| recorded.request == exchange.request)
+        {
+            Some(recorded) => *recorded = exchange,
+            None => exchanges.push(exchange),
+        }
+
+        let contents = serde_json::to_vec_pretty(&exchanges)?;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .await
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+
+        // Write to a temporary file first, so a crash can't leave a truncated file behind
+        let mut tmp_path = PathBuf::from(path);
+        tmp_path.set_extension("tmp");
+
+        async {
+            fs::write(&tmp_path, contents).await?;
+            fs::rename(&tmp_path, path).await
+        }
+        .await
+        .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, query: &str, body: &[u8]) -> RecordedRequest {
+        RecordedRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            query: query.to_string(),
+            body_hash: format!("{:016x}", wyhash2::wyhash_single(body, 0)),
+        }
+    }
+
+    fn fixtures(mode: FixtureMode, match_on: Vec<MatchOn>) -> (Fixtures, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("nlx-fixtures-{}", rand::random::<u64>()));
+        let fixtures = Fixtures::new(FixtureSettings {
+            mode,
+            dir: dir.clone(),
+            match_on,
+        });
+
+        (fixtures, dir)
+    }
+
+    #[test]
+    fn matches_on_the_selected_properties() {
+        let recorded = request("POST", "orders", "page=1", b"{}");
+        let cases = [
+            (request("GET", "orders", "page=1", b"{}"), MatchOn::Method),
+            (request("POST", "invoices", "page=1", b"{}"), MatchOn::Path),
+            (request("POST", "orders", "page=2", b"{}"), MatchOn::Query),
+            (request("POST", "orders", "page=1", b"[]"), MatchOn::Body),
+        ];
+
+        assert!(recorded.matches(&recorded, &default_match_on()));
+
+        for (request, differs_on) in cases {
+            let others = default_match_on()
+                .into_iter()
+                .filter(|field| *field != differs_on)
+                .collect::<Vec<_>>();
+
+            assert!(
+                !request.matches(&recorded, &default_match_on()),
+                "{}",
+                request
+            );
+            assert!(!request.matches(&recorded, &[differs_on]), "{}", request);
+            assert!(request.matches(&recorded, &others), "{}", request);
+        }
+
+        // Without properties to compare every request matches
+        assert!(request("GET", "", "", b"").matches(&recorded, &[]));
+    }
+
+    #[test]
+    fn refuses_names_that_escape_the_directory() {
+        let (fixtures, dir) = fixtures(FixtureMode::Replay, default_match_on());
+
+        assert_eq!(
+            fixtures.path("00000001", "basisregister"),
+            Some(dir.join("00000001").join("basisregister.json"))
+        );
+
+        for (oin, service) in [
+            ("..", "basisregister"),
+            (".", "basisregister"),
+            ("", "basisregister"),
+            ("00000001", ".."),
+            ("00000001", "../../etc/passwd"),
+            ("../00000001", "basisregister"),
+            ("00000001", "a\\b"),
+            ("/etc", "basisregister"),
+        ] {
+            assert_eq!(fixtures.path(oin, service), None, "{}/{}", oin, service);
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_recorded_responses() {
+        let (fixtures, dir) = fixtures(FixtureMode::Record, vec![MatchOn::Method, MatchOn::Path]);
+        let response = http::Response::builder()
+            .status(StatusCode::CREATED)
+            .header("content-type", "application/json")
+            .body(Body::from("{\"id\":1}"))
+            .unwrap();
+
+        let recorded = request("POST", "orders", "", b"{}");
+        let response = fixtures
+            .record("00000001", "basisregister", recorded.clone(), response)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+
+        // Recording the same request again replaces the earlier recording
+        let response = http::Response::new(Body::from(vec![0xff, 0xfe]));
+        fixtures
+            .record("00000001", "basisregister", recorded.clone(), response)
+            .await
+            .unwrap();
+
+        // A fresh instance reads the file that was written
+        let fixtures = Fixtures::new(FixtureSettings {
+            mode: FixtureMode::Replay,
+            dir: dir.clone(),
+            match_on: vec![MatchOn::Method, MatchOn::Path],
+        });
+        let replayed = fixtures
+            .replay(
+                "00000001",
+                "basisregister",
+                &request("POST", "orders", "x=1", b""),
+            )
+            .await
+            .unwrap();
+        let body = hyper::body::to_bytes(replayed.into_body()).await.unwrap();
+
+        assert_eq!(&body[..], &[0xff, 0xfe]);
+
+        let missing = fixtures
+            .replay(
+                "00000001",
+                "basisregister",
+                &request("GET", "orders", "", b""),
+            )
+            .await;
+        let hostile = fixtures
+            .replay("..", "basisregister", &request("POST", "orders", "", b""))
+            .await;
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(missing, Err(FixtureError::NotFound(_))));
+        assert!(matches!(hostile, Err(FixtureError::NotFound(_))));
+    }
+
+    #[tokio::test]
+    async fn reports_invalid_fixture_files() {
+        let (fixtures, dir) = fixtures(FixtureMode::Replay, default_match_on());
+        std::fs::create_dir_all(dir.join("00000001")).unwrap();
+        std::fs::write(dir.join("00000001").join("basisregister.json"), "{").unwrap();
+
+        let result = fixtures
+            .replay("00000001", "basisregister", &request("GET", "", "", b""))
+            .await;
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(result, Err(FixtureError::Invalid(_))));
+    }
+}
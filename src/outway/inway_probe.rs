@@ -0,0 +1,227 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use futures_util::future::join_all;
+use hyper::{client::connect::Connect, Body, Client};
+use serde::{Deserialize, Serialize};
+use tokio::time;
+use tonic::async_trait;
+
+use crate::{metrics, supervisor::Task};
+
+use super::{
+    config::{Route, RouteInway, State},
+    server::ServiceInwaysState,
+    settings::OutwaySettings,
+};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InwayHealth {
+    pub healthy: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub latency_ms: u64,
+}
+
+/// Response of the health endpoint of an inway
+#[derive(Deserialize)]
+struct RemoteHealth {
+    healthy: bool,
+    #[serde(default)]
+    version: String,
+}
+
+/// Maps the endpoint of a service on an inway to the observed health
+pub type InwayHealthState = Arc<RwLock<HashMap<String, InwayHealth>>>;
+
+/// Set of (OIN, service) pairs that were requested through this outway
+pub type UsedServices = Arc<RwLock<HashSet<(String, String)>>>;
+
+pub fn mark_used(used: &UsedServices, oin: &str, service: &str) {
+    let key = (oin.to_string(), service.to_string());
+
+    if !used.read().unwrap().contains(&key) {
+        used.write().unwrap().insert(key);
+    }
+}
+
+/// Selects the inway to route to, preferring inways that were observed to be healthy
+/// and falling back on the state in the directory for inways that weren't probed yet.
+pub fn select_inway(route: &Route, health: &InwayHealthState) -> Option<Arc<String>> {
+    let health = health.read().unwrap();
+
+    route
+        .inways
+        .iter()
+        .min_by_key(|inway| match health.get(inway.endpoint.as_str()) {
+            Some(observed) if observed.healthy => 0,
+            None if inway.state == State::Up => 1,
+            None if inway.state == State::Unknown => 2,
+            None => 3,
+            Some(_) => 4,
+        })
+        .map(|inway| Arc::clone(&inway.endpoint))
+}
+
+/// Periodically calls the health endpoint of the inways offering services that are in use
+pub struct InwayProber<C> {
+    http: Client<C>,
+    routes: ServiceInwaysState,
+    used: UsedServices,
+    state: InwayHealthState,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl<C> InwayProber<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    pub fn new(
+        http: Client<C>,
+        routes: ServiceInwaysState,
+        used: UsedServices,
+        state: InwayHealthState,
+        settings: &OutwaySettings,
+    ) -> Self {
+        Self {
+            http,
+            routes,
+            used,
+            state,
+            interval: settings.inway_probe_interval,
+            timeout: settings.inway_probe_timeout,
+        }
+    }
+
+    async fn request(&self, inway: &RouteInway, service: &str) -> Result<RemoteHealth> {
+        let request = hyper::Request::get(format!("{}.nlx/health/{}", inway.address, service))
+            .body(Body::empty())?;
+        let response = self.http.request(request).await?;
+
+        if !response.status().is_success() {
+            return Err(anyhow!("unexpected status {}", response.status()));
+        }
+
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    async fn probe(&self, oin: &str, service: &str, inway: &RouteInway) -> InwayHealth {
+        log::trace!("probing inway {} for {}/{}", inway.address, oin, service);
+
+        let start = Instant::now();
+        let result = time::timeout(self.timeout, self.request(inway, service)).await;
+        let latency_ms = start.elapsed().as_millis() as u64;
+
+        let health = match result {
+            Ok(Ok(remote)) => InwayHealth {
+                healthy: remote.healthy,
+                version: Some(remote.version).filter(|version| !version.is_empty()),
+                error: None,
+                latency_ms,
+            },
+            Ok(Err(e)) => InwayHealth {
+                healthy: false,
+                version: None,
+                error: Some(e.to_string()),
+                latency_ms,
+            },
+            Err(_) => InwayHealth {
+                healthy: false,
+                version: None,
+                error: Some("timeout".to_string()),
+                latency_ms,
+            },
+        };
+
+        metrics::INWAY_PROBE_DURATION
+            .with_label_values(&[oin, service])
+            .observe(latency_ms as f64 / 1000.0);
+        metrics::INWAY_PROBES
+            .with_label_values(&[
+                oin,
+                service,
+                if health.healthy { "success" } else { "failure" },
+            ])
+            .inc();
+        metrics::INWAY_UP
+            .with_label_values(&[oin, service, &inway.address])
+            .set(health.healthy as i64);
+
+        health
+    }
+
+    async fn tick(&self) {
+        let targets = {
+            let used = self.used.read().unwrap().clone();
+            let routes = self.routes.read().await;
+
+            used.into_iter()
+                .filter_map(|(oin, service)| {
+                    let route = routes.get(&oin)?.get(&service)?;
+                    Some((oin, service, route.inways.clone()))
+                })
+                .flat_map(|(oin, service, inways)| {
+                    inways
+                        .into_iter()
+                        .map(move |inway| (oin.clone(), service.clone(), inway))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let results = join_all(
+            targets
+                .iter()
+                .map(|(oin, service, inway)| self.probe(oin, service, inway)),
+        )
+        .await;
+
+        let mut state = self.state.write().unwrap();
+        let previous = std::mem::take(&mut *state);
+
+        for ((oin, service, inway), health) in targets.into_iter().zip(results) {
+            let was_healthy = previous
+                .get(inway.endpoint.as_str())
+                .map(|previous| previous.healthy);
+
+            match (was_healthy, health.healthy) {
+                (Some(false), true) => {
+                    log::info!("inway {} is healthy for {}/{}", inway.address, oin, service)
+                }
+                (Some(true) | None, false) => log::warn!(
+                    "inway {} is unhealthy for {}/{}: {}",
+                    inway.address,
+                    oin,
+                    service,
+                    health.error.as_deref().unwrap_or("reported unhealthy")
+                ),
+                _ => {}
+            }
+
+            state.insert(inway.endpoint.to_string(), health);
+        }
+    }
+}
+
+#[async_trait]
+impl<C> Task for InwayProber<C>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    async fn run(&mut self) -> Result<()> {
+        let mut interval = time::interval(self.interval);
+
+        loop {
+            interval.tick().await;
+            self.tick().await;
+        }
+    }
+}
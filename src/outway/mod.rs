@@ -1,9 +1,15 @@
+mod admin;
 mod broadcast;
 mod config;
 mod config_poller;
+mod fixtures;
+mod inway_probe;
 mod server;
+mod settings;
 
 pub use broadcast::Broadcast;
 pub use config::Config;
 pub use config_poller::ConfigPoller;
+pub use fixtures::FixtureError;
 pub use server::Server;
+pub use settings::OutwaySettings;
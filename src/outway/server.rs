@@ -1,70 +1,227 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use async_channel::Receiver;
-use hyper::Client;
-use hyper_rustls::HttpsConnectorBuilder;
+use hyper::{client::HttpConnector, Client};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use rustls::{Certificate, ClientConfig, PrivateKey, RootCertStore};
+use serde::Serialize;
 use tokio::sync::RwLock;
+use tonic::async_trait;
 use warp::Filter;
 
-use crate::{filters::with_request, reverse_proxy, tls::TlsPair};
-
-use super::{config::ServiceInways, Config};
-
-type ServiceInwaysState = Arc<RwLock<ServiceInways>>;
-
-async fn handle_events(state: ServiceInwaysState, rx: Receiver<Config>) {
-    loop {
-        match rx.recv().await {
-            Ok(new_config) => {
-                let mut lock = state.write().await;
-                *lock = new_config
-                    .services
-                    .into_iter()
-                    .map(|(oin, services)| {
-                        (
-                            oin,
-                            services
-                                .into_iter()
-                                .map(|service| {
-                                    let services = service
-                                        .inways
-                                        .first()
-                                        .map(|inway| format!("{}{}/", inway.address, service.name))
-                                        .map(Arc::new);
-
-                                    (service.name, services)
+use crate::{
+    access_log,
+    admin::Admin,
+    circuit_breaker::CircuitBreakers,
+    client_pool::{ClientKey, ClientPool, Protocol},
+    errors::{self, Component, RouteError},
+    faults::{self, Faults},
+    filters::with_request,
+    health::{self, Readiness},
+    metering::{Meter, ReportWriter},
+    reverse_proxy, serve,
+    supervisor::{Supervisor, Task},
+    tls::TlsPair,
+    trace::{Kind, Span, TracedConnector},
+};
+
+use super::{
+    admin,
+    config::{Route, RouteInway, ServiceInways, State},
+    fixtures::{FixtureError, FixtureMode, Fixtures, RecordedRequest},
+    inway_probe::{self, InwayHealth, InwayHealthState, InwayProber, UsedServices},
+    settings::OutwaySettings,
+    Config,
+};
+
+pub type ServiceInwaysState = Arc<RwLock<ServiceInways>>;
+
+type Clients = ClientPool<ClientKey, TracedConnector<HttpsConnector<HttpConnector>>>;
+
+/// Updates the routing table whenever a new config is received
+struct ConfigHandler {
+    state: ServiceInwaysState,
+    rx: Receiver<Config>,
+    readiness: Readiness,
+    meter: Option<Arc<Meter>>,
+}
+
+#[async_trait]
+impl Task for ConfigHandler {
+    async fn run(&mut self) -> anyhow::Result<()> {
+        loop {
+            match self.rx.recv().await {
+                Ok(new_config) => {
+                    if let Some(meter) = &self.meter {
+                        meter.set_costs(
+                            new_config
+                                .services
+                                .iter()
+                                .flat_map(|(oin, services)| {
+                                    services.iter().map(move |service| {
+                                        (format!("{}/{}", oin, service.name), service.costs)
+                                    })
                                 })
                                 .collect(),
-                        )
-                    })
-                    .collect();
+                        );
+                    }
 
-                log::info!("outway config updated");
-            }
-            Err(_) => {
-                log::debug!("config channel closed");
-                break;
+                    let mut lock = self.state.write().await;
+                    *lock = new_config
+                        .services
+                        .into_iter()
+                        .map(|(oin, services)| {
+                            (
+                                oin,
+                                services
+                                    .into_iter()
+                                    .map(|service| {
+                                        let inways = service
+                                            .inways
+                                            .into_iter()
+                                            .map(|inway| RouteInway {
+                                                endpoint: Arc::new(format!(
+                                                    "{}{}/",
+                                                    inway.address, service.name
+                                                )),
+                                                address: inway.address,
+                                                state: inway.state,
+                                            })
+                                            .collect();
+
+                                        (
+                                            service.name,
+                                            Route {
+                                                organization_name: service.organization.name,
+                                                inways,
+                                            },
+                                        )
+                                    })
+                                    .collect(),
+                            )
+                        })
+                        .collect();
+
+                    self.readiness.set_config_received();
+
+                    log::info!("outway config updated");
+                }
+                Err(_) => {
+                    log::debug!("config channel closed");
+                    return Ok(());
+                }
             }
         }
     }
 }
 
+#[derive(Serialize)]
+pub struct CatalogOrganization {
+    pub serial_number: String,
+    pub name: String,
+}
+
+#[derive(Serialize)]
+pub struct CatalogInway {
+    pub address: String,
+    pub directory_state: State,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub observed: Option<InwayHealth>,
+}
+
+#[derive(Serialize)]
+pub struct CatalogService {
+    pub organization: CatalogOrganization,
+    pub name: String,
+    pub inways: Vec<CatalogInway>,
+}
+
+async fn catalog(state: &ServiceInwaysState, health: &InwayHealthState) -> Vec<CatalogService> {
+    let routes = state.read().await;
+    let health = health.read().unwrap();
+
+    routes
+        .iter()
+        .flat_map(|(oin, services)| {
+            services.iter().map(|(name, route)| CatalogService {
+                organization: CatalogOrganization {
+                    serial_number: oin.clone(),
+                    name: route.organization_name.clone(),
+                },
+                name: name.clone(),
+                inways: route
+                    .inways
+                    .iter()
+                    .map(|inway| CatalogInway {
+                        address: inway.address.clone(),
+                        directory_state: inway.state.clone(),
+                        observed: health.get(inway.endpoint.as_str()).cloned(),
+                    })
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
 pub struct Server {
     tls_pair: TlsPair,
     rx: Receiver<Config>,
+    supervisor: Supervisor,
+    readiness: Readiness,
+    settings: OutwaySettings,
+    admin: Option<Admin>,
 }
 
 impl Server {
-    pub fn new(tls_pair: TlsPair, rx: Receiver<Config>) -> Self {
-        Self { tls_pair, rx }
+    pub fn new(
+        tls_pair: TlsPair,
+        rx: Receiver<Config>,
+        supervisor: Supervisor,
+        readiness: Readiness,
+        settings: OutwaySettings,
+    ) -> Self {
+        Self {
+            tls_pair,
+            rx,
+            supervisor,
+            readiness,
+            settings,
+            admin: None,
+        }
+    }
+
+    /// Serves the admin API next to the outway
+    pub fn with_admin(mut self, admin: Admin) -> Self {
+        self.admin = Some(admin);
+        self
     }
 
     pub async fn run(self, addr: SocketAddr) -> anyhow::Result<()> {
         let config = ServiceInwaysState::default();
+        let metering = self.settings.metering.clone();
+        metering.validate()?;
+        let meter = metering
+            .report_dir
+            .as_ref()
+            .map(|_| Arc::new(Meter::new(Component::Outway)));
 
         // Handle config changes
-        tokio::spawn(handle_events(Arc::clone(&config), self.rx));
+        self.supervisor.spawn(
+            "config_handler",
+            ConfigHandler {
+                state: Arc::clone(&config),
+                rx: self.rx,
+                readiness: self.readiness.clone(),
+                meter: meter.clone(),
+            },
+        );
+
+        if let Some(meter) = &meter {
+            self.supervisor.spawn(
+                "report_writer",
+                ReportWriter::new(Arc::clone(meter), metering.clone()),
+            );
+        }
 
         let cert_bundle_der = pem::parse_many(self.tls_pair.bundle())?
             .into_iter()
@@ -83,62 +240,271 @@ impl Server {
             .with_root_certificates(store)
             .with_single_cert(cert_bundle_der, PrivateKey(key_der))?;
         tls_config.enable_early_data = true;
-        let https = HttpsConnectorBuilder::new()
-            .with_tls_config(tls_config)
-            .https_only()
-            .enable_http2()
-            .build();
-        let client = Client::builder()
-            .http2_adaptive_window(true)
-            .http2_only(true)
-            .retry_canceled_requests(true)
-            .build(https);
+        let clients = Clients::new(move |key: &ClientKey| {
+            let mut http = HttpConnector::new();
+            http.enforce_http(false);
+            http.set_connect_timeout(key.connect_timeout);
+
+            let https = HttpsConnectorBuilder::new()
+                .with_tls_config(tls_config.clone())
+                .https_only();
+
+            // Upgrades are not possible over HTTP/2 so these connections use HTTP/1.1
+            if key.protocol == Protocol::Http1 {
+                return Client::builder().retry_canceled_requests(true).build(
+                    TracedConnector::new(https.enable_http1().wrap_connector(http)),
+                );
+            }
+
+            Client::builder()
+                .http2_adaptive_window(true)
+                .http2_only(true)
+                .retry_canceled_requests(true)
+                .build(TracedConnector::new(
+                    https.enable_http2().wrap_connector(http),
+                ))
+        });
+        let client = clients.get(&ClientKey {
+            connect_timeout: self.settings.timeouts.or_default().connect,
+            protocol: Protocol::Http2,
+        });
+
+        // Probe the inways of services that are in use
+        let used = UsedServices::default();
+        let inway_health = InwayHealthState::default();
+
+        self.supervisor.spawn(
+            "inway_prober",
+            InwayProber::new(
+                client.clone(),
+                Arc::clone(&config),
+                Arc::clone(&used),
+                Arc::clone(&inway_health),
+                &self.settings,
+            ),
+        );
+
+        let breakers = CircuitBreakers::new(self.settings.circuit_breaker.clone());
+        let faults = Faults::new(self.settings.faults.clone())?;
+
+        if let Some(admin) = self.admin {
+            let routes = admin::routes(
+                Arc::clone(&config),
+                breakers.clone(),
+                Arc::clone(&inway_health),
+                faults.clone(),
+            );
+            self.supervisor.spawn("admin", admin.server(routes));
+        }
+
+        let fixtures = self.settings.fixtures.clone().map(|settings| {
+            match settings.mode {
+                FixtureMode::Record => {
+                    log::info!("recording responses to {}", settings.dir.display())
+                }
+                FixtureMode::Replay => log::warn!(
+                    "replaying responses from {}, inways are not contacted",
+                    settings.dir.display()
+                ),
+            }
+
+            Arc::new(Fixtures::new(settings))
+        });
+        let settings = Arc::new(self.settings);
         let with_config = warp::any().map(move || Arc::clone(&config));
-        let with_client = warp::any().map(move || client.clone());
-        let route =
-            warp::any()
-                .and(with_config)
-                .and(with_client)
-                .and(warp::path::param())
-                .and(warp::path::param())
-                .and(with_request!())
-                .and_then(
-                    |state: ServiceInwaysState,
-                     client,
-                     oin: String,
-                     service: String,
-                     request| async move {
-                        let upstream = {
-                            let lock = state.read().await;
-                            lock.get(&oin).and_then(|services| {
-                                services
-                                    .get(&service)
-                                    .map(|endpoint| endpoint.as_ref().map(Arc::clone))
-                            })
-                        };
+        let with_clients = warp::any().map(move || clients.clone());
+        let with_settings = warp::any().map(move || Arc::clone(&settings));
+        let with_inway_health = warp::any().map(move || Arc::clone(&inway_health));
+        let catalog = warp::get()
+            .and(warp::path(".nlx"))
+            .and(warp::path("catalog"))
+            .and(warp::path::end())
+            .and(with_config.clone())
+            .and(with_inway_health.clone())
+            .then(
+                |state: ServiceInwaysState, inway_health: InwayHealthState| async move {
+                    warp::reply::json(&catalog(&state, &inway_health).await)
+                },
+            );
+        let route = warp::any()
+            .and(with_config)
+            .and(with_clients)
+            .and(with_settings)
+            .and(with_inway_health)
+            .and(warp::any().map(move || Arc::clone(&used)))
+            .and(warp::any().map(move || breakers.clone()))
+            .and({
+                let meter = meter.clone();
+                warp::any().map(move || meter.clone())
+            })
+            .and(warp::any().map(move || fixtures.clone()))
+            .and(warp::any().map(move || faults.clone()))
+            .and(warp::path::param())
+            .and(warp::path::param())
+            .and(with_request!())
+            .and_then(
+                |state: ServiceInwaysState,
+                 clients: Clients,
+                 settings: Arc<OutwaySettings>,
+                 inway_health: InwayHealthState,
+                 used: UsedServices,
+                 breakers: CircuitBreakers,
+                 meter: Option<Arc<Meter>>,
+                 fixtures: Option<Arc<Fixtures>>,
+                 faults: Faults,
+                 oin: String,
+                 service: String,
+                 mut request: reverse_proxy::Request| async move {
+                    let mut span = Span::server(
+                        format!("proxy {}/{}", oin, service),
+                        request.method(),
+                        request.headers(),
+                    );
+                    access_log::set_target(Some(&oin), &service);
+
+                    let result = span
+                        .scope(async {
+                            // Upgrades and streaming gRPC calls are never recorded
+                            let recorded = match &fixtures {
+                                Some(_) if !request.is_upgrade() && !request.is_grpc() => {
+                                    let body = request.buffer_body().await?;
+                                    Some(RecordedRequest::new(&request, &body))
+                                }
+                                _ => None,
+                            };
+
+                            if let Some(fixtures) = &fixtures {
+                                if fixtures.mode() == FixtureMode::Replay {
+                                    let response = match &recorded {
+                                        Some(recorded) => {
+                                            fixtures.replay(&oin, &service, recorded).await?
+                                        }
+                                        None => {
+                                            return Err(warp::reject::custom(
+                                                FixtureError::NotFound(format!(
+                                                    "{} of {}/{}",
+                                                    request, oin, service
+                                                )),
+                                            ))
+                                        }
+                                    };
 
-                        match upstream {
-                            Some(Some(upstream)) => {
-                                log::debug!("proxy {}: {}", service, request);
+                                    return Ok(response);
+                                }
+                            }
 
-                                reverse_proxy::handle(client, request, &upstream)
-                                    .await
-                                    .map_err(|e| {
-                                        log::error!("proxy failed: {:?}", e);
-                                        e
+                            let routing = Span::child_of_current("route", Kind::Internal);
+                            let upstream = {
+                                let lock = state.read().await;
+                                lock.get(&oin).and_then(|services| {
+                                    services.get(&service).map(|route| {
+                                        (
+                                            route.organization_name.clone(),
+                                            inway_probe::select_inway(route, &inway_health),
+                                        )
                                     })
+                                })
+                            };
+
+                            if upstream.is_some() {
+                                inway_probe::mark_used(&used, &oin, &service);
+                            }
+
+                            let (organization_name, upstream) = match upstream {
+                                Some((organization_name, Some(upstream))) => {
+                                    (organization_name, upstream)
+                                }
+                                Some((_, None)) => {
+                                    log::warn!("service {} has no inways", service);
+                                    return Err(warp::reject::custom(RouteError::NoInways(
+                                        format!("{}/{}", oin, service),
+                                    )));
+                                }
+                                None => {
+                                    return Err(warp::reject::custom(RouteError::UnknownService(
+                                        format!("{}/{}", oin, service),
+                                    )))
+                                }
+                            };
+
+                            log::debug!("proxy {}: {}", service, request);
+                            access_log::set_upstream(&upstream);
+
+                            let timeouts = settings.timeouts(&oin, &service);
+                            // Inways are always reached over HTTP/2, except for upgrades
+                            let protocol = match request.protocol() {
+                                Protocol::Http1 => Protocol::Http1,
+                                _ => Protocol::Http2,
+                            };
+                            let client = clients.get(&ClientKey {
+                                connect_timeout: timeouts.connect,
+                                protocol,
+                            });
+                            let circuit = breakers.get(&upstream);
+
+                            let request = request.forwarding(settings.forwarding);
+
+                            let fault = faults.pick(&service, Some(&oin));
+
+                            if let Some(result) = faults::intercept(fault.as_ref()) {
+                                return result;
                             }
-                            Some(None) => {
-                                log::warn!("service {} has an invalid endpoint", service);
-                                Err(warp::reject::not_found())
+
+                            drop(routing);
+
+                            let response = faults::inject(fault, async {
+                                reverse_proxy::handle(
+                                    client, request, &upstream, &circuit, &timeouts,
+                                )
+                                .await
+                                .map_err(|e| {
+                                    log::error!("proxy failed: {:?}", e);
+                                    e
+                                })
+                            })
+                            .await?;
+
+                            if let Some(meter) = meter {
+                                meter.record(&oin, &organization_name, &service);
                             }
-                            None => Err(warp::reject::not_found()),
-                        }
-                    },
-                );
 
-        warp::serve(route).run(addr).await;
+                            let response = match (fixtures, recorded) {
+                                (Some(fixtures), Some(recorded)) => {
+                                    fixtures.record(&oin, &service, recorded, response).await?
+                                }
+                                _ => response,
+                            };
+
+                            Ok(response)
+                        })
+                        .await;
 
-        Ok(())
+                    span.set_result(&result);
+                    result
+                },
+            );
+
+        // Run the server until the supervisor shuts down
+        let shutdown = self.supervisor.shutdown_token();
+        let health = health::routes(self.readiness, self.supervisor.clone());
+        let routes = health
+            .or(catalog)
+            .or(route)
+            .recover(|rejection| errors::recover(Component::Outway, rejection));
+        let routes = errors::is_grpc().and(routes).map(errors::with_grpc_status);
+
+        serve::serve(
+            addr,
+            access_log::service(warp::service(routes)),
+            None,
+            shutdown,
+        )
+        .await?;
+
+        // Keep the usage of the last period
+        match meter {
+            Some(meter) => meter.write_report(&metering).await,
+            None => Ok(()),
+        }
     }
 }
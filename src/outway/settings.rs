@@ -0,0 +1,74 @@
+use std::{collections::HashMap, time::Duration};
+
+use serde::Deserialize;
+
+use super::fixtures::{FixtureMode, FixtureSettings};
+use crate::{
+    circuit_breaker::CircuitBreakerSettings, faults::Fault, forwarding::ForwardingSettings,
+    metering::MeteringSettings, timeouts::Timeouts,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutwaySettings {
+    /// How often the inways of services in use are probed
+    #[serde(with = "humantime_serde")]
+    pub inway_probe_interval: Duration,
+    #[serde(with = "humantime_serde")]
+    pub inway_probe_timeout: Duration,
+    pub circuit_breaker: CircuitBreakerSettings,
+    pub forwarding: ForwardingSettings,
+    pub metering: MeteringSettings,
+    /// Default timeouts for requests to inways
+    pub timeouts: Timeouts,
+    /// Settings per organization (`<serial number>`) or service (`<serial number>/<service>`)
+    pub services: HashMap<String, ServiceSettings>,
+    /// Records responses to fixture files or replays them, meant for testing
+    pub fixtures: Option<FixtureSettings>,
+    /// Faults that are injected into requests, for resilience testing
+    pub faults: Vec<Fault>,
+}
+
+impl OutwaySettings {
+    fn service(&self, oin: &str, service: &str) -> Option<&ServiceSettings> {
+        self.services
+            .get(&format!("{}/{}", oin, service))
+            .or_else(|| self.services.get(oin))
+    }
+
+    /// Returns true when requests are answered from fixtures, inways are never contacted then
+    pub fn is_replay(&self) -> bool {
+        matches!(&self.fixtures, Some(fixtures) if fixtures.mode == FixtureMode::Replay)
+    }
+
+    /// Returns the effective timeouts for requests to a service of an organization
+    pub fn timeouts(&self, oin: &str, service: &str) -> Timeouts {
+        self.service(oin, service)
+            .map(|settings| settings.timeouts)
+            .unwrap_or_default()
+            .or(self.timeouts)
+            .or_default()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceSettings {
+    pub timeouts: Timeouts,
+}
+
+impl Default for OutwaySettings {
+    fn default() -> Self {
+        Self {
+            inway_probe_interval: Duration::from_secs(10),
+            inway_probe_timeout: Duration::from_secs(5),
+            circuit_breaker: CircuitBreakerSettings::default(),
+            forwarding: ForwardingSettings::default(),
+            metering: MeteringSettings::default(),
+            timeouts: Timeouts::default(),
+            services: HashMap::new(),
+            fixtures: None,
+            faults: vec![],
+        }
+    }
+}
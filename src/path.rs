@@ -0,0 +1,222 @@
+use std::fmt::{self, Display};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    /// A `..` segment, which could reach paths outside of the service
+    Traversal,
+    /// A percent-encoded character that backends may or may not decode before routing,
+    /// e.g. `%2F` or `%2E`
+    AmbiguousEncoding,
+    /// A `%` that is not followed by two hexadecimal digits
+    InvalidEncoding,
+    /// Control characters, backslashes or bytes that don't decode to valid UTF-8
+    InvalidCharacter,
+}
+
+impl Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Traversal => write!(f, "path traversal is not allowed"),
+            Self::AmbiguousEncoding => write!(f, "path contains an ambiguous encoding"),
+            Self::InvalidEncoding => write!(f, "path contains an invalid percent-encoding"),
+            Self::InvalidCharacter => write!(f, "path contains an invalid character"),
+        }
+    }
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    match byte {
+        b'0'..=b'9' => Some(byte - b'0'),
+        b'a'..=b'f' => Some(byte - b'a' + 10),
+        b'A'..=b'F' => Some(byte - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Checks the characters and percent-encoding of a segment and returns its decoded form
+fn decode_segment(segment: &str) -> Result<Vec<u8>, PathError> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let (high, low) = match (bytes.get(i + 1), bytes.get(i + 2)) {
+                    (Some(high), Some(low)) => (*high, *low),
+                    _ => return Err(PathError::InvalidEncoding),
+                };
+                let byte = match (hex_value(high), hex_value(low)) {
+                    (Some(high), Some(low)) => high << 4 | low,
+                    _ => return Err(PathError::InvalidEncoding),
+                };
+
+                match byte {
+                    // Separators and dots change the meaning of the path when decoded
+                    b'/' | b'\\' | b'.' => return Err(PathError::AmbiguousEncoding),
+                    byte if byte.is_ascii_control() => return Err(PathError::InvalidCharacter),
+                    byte => decoded.push(byte),
+                }
+
+                i += 3;
+            }
+            b'\\' => return Err(PathError::InvalidCharacter),
+            byte if byte.is_ascii_control() => return Err(PathError::InvalidCharacter),
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+
+    // Double encoding, e.g. `%252e` is `%2e` after decoding once
+    if decoded.windows(3).any(|window| {
+        window[0] == b'%' && hex_value(window[1]).is_some() && hex_value(window[2]).is_some()
+    }) {
+        return Err(PathError::AmbiguousEncoding);
+    }
+
+    // Rejects overlong encodings such as `%C0%AE` which some decoders turn into a dot
+    std::str::from_utf8(&decoded).map_err(|_| PathError::InvalidCharacter)?;
+
+    Ok(decoded)
+}
+
+/// Normalizes the (percent-encoded) path of a request relative to the endpoint of a
+/// service. Empty and `.` segments are removed, traversal and ambiguous encodings are
+/// rejected. The encoding of the remaining segments is kept as is.
+pub fn canonicalize(path: &str) -> Result<String, PathError> {
+    let mut segments = Vec::new();
+
+    for segment in path.split('/') {
+        let decoded = decode_segment(segment)?;
+
+        // Some servers ignore path parameters, `..;x` is the same as `..` for them
+        let name = decoded
+            .split(|byte| *byte == b';')
+            .next()
+            .unwrap_or_default();
+
+        match name {
+            b".." => return Err(PathError::Traversal),
+            b"." if decoded.len() > 1 => return Err(PathError::AmbiguousEncoding),
+            b"." | b"" => continue,
+            _ => segments.push(segment),
+        }
+    }
+
+    let mut canonical = segments.join("/");
+
+    // A trailing slash is significant to most backends, like RFC 3986 a trailing dot
+    // segment results in a trailing slash
+    if (path.ends_with('/') || path.ends_with("/.")) && !canonical.is_empty() {
+        canonical.push('/');
+    }
+
+    Ok(canonical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_regular_paths() {
+        assert_eq!(canonicalize(""), Ok("".to_string()));
+        assert_eq!(canonicalize("users"), Ok("users".to_string()));
+        assert_eq!(canonicalize("users/1/"), Ok("users/1/".to_string()));
+        assert_eq!(
+            canonicalize("a%20b/c%C3%A9"),
+            Ok("a%20b/c%C3%A9".to_string())
+        );
+        assert_eq!(canonicalize("file.tar.gz"), Ok("file.tar.gz".to_string()));
+        assert_eq!(canonicalize("...config"), Ok("...config".to_string()));
+        assert_eq!(canonicalize("a;b=c"), Ok("a;b=c".to_string()));
+    }
+
+    #[test]
+    fn removes_empty_and_dot_segments() {
+        assert_eq!(canonicalize("a//b"), Ok("a/b".to_string()));
+        assert_eq!(canonicalize("//a"), Ok("a".to_string()));
+        assert_eq!(canonicalize("a/./b"), Ok("a/b".to_string()));
+        assert_eq!(canonicalize("./a/."), Ok("a/".to_string()));
+        assert_eq!(canonicalize("a/./"), Ok("a/".to_string()));
+        assert_eq!(canonicalize("/"), Ok("".to_string()));
+        assert_eq!(canonicalize("./"), Ok("".to_string()));
+    }
+
+    #[test]
+    fn rejects_traversal() {
+        for path in [
+            "..",
+            "../etc/passwd",
+            "a/../../b",
+            "a/..",
+            "a/../b",
+            "a//../b",
+            "..;/admin",
+            "a/..;x=y/b",
+        ] {
+            assert_eq!(canonicalize(path), Err(PathError::Traversal), "{}", path);
+        }
+    }
+
+    #[test]
+    fn rejects_encoded_traversal() {
+        for path in [
+            "%2e%2e/etc/passwd",
+            "%2E%2E/etc/passwd",
+            ".%2e/admin",
+            "%2e./admin",
+            "a/%2e/b",
+            "a%2f..%2fb",
+            "a%2F%2E%2E%2Fb",
+            "a%5c..%5cb",
+            "%252e%252e/admin",
+            "%25%32%65%25%32%65/admin",
+            "a/%252F/b",
+            ".;x/admin",
+        ] {
+            assert_eq!(
+                canonicalize(path),
+                Err(PathError::AmbiguousEncoding),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_encoding() {
+        for path in ["%", "a%2", "a%zz", "%%32%65", "a/%g0/b"] {
+            assert_eq!(
+                canonicalize(path),
+                Err(PathError::InvalidEncoding),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_characters() {
+        for path in [
+            "a\\..\\b",
+            "a\\b",
+            "a%00b",
+            "a%0d%0aHost:evil",
+            "a%7fb",
+            "%c0%ae%c0%ae/admin",
+            "%e0%80%ae/admin",
+            "a%ffb",
+            "a\tb",
+        ] {
+            assert_eq!(
+                canonicalize(path),
+                Err(PathError::InvalidCharacter),
+                "{}",
+                path
+            );
+        }
+    }
+}
@@ -1,10 +1,10 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
-use tokio::{task::JoinHandle, time};
+use tokio::{sync::Notify, time};
 use tonic::async_trait;
 
-use crate::backoff::retry_backoff;
+use crate::supervisor::Task;
 
 #[async_trait]
 pub trait Poll {
@@ -14,31 +14,42 @@ pub trait Poll {
 pub struct Poller<T: Poll> {
     poll: T,
     duration: Duration,
+    trigger: Arc<Notify>,
 }
 
 impl<T: Poll + Sync + Send + 'static> Poller<T> {
     pub fn new(poll: T, duration: Duration) -> Self {
-        Self { poll, duration }
+        Self {
+            poll,
+            duration,
+            trigger: Arc::default(),
+        }
+    }
+
+    /// Polls right away whenever the trigger is notified
+    pub fn with_trigger(mut self, trigger: Arc<Notify>) -> Self {
+        self.trigger = trigger;
+        self
     }
 
     pub async fn poll(&mut self) -> Result<()> {
         let mut interval = time::interval(self.duration);
 
         loop {
-            interval.tick().await;
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.trigger.notified() => interval.reset(),
+            }
+
             self.poll.poll().await?;
         }
     }
+}
 
-    pub fn poll_start(mut self) -> JoinHandle<()> {
+#[async_trait]
+impl<T: Poll + Sync + Send + 'static> Task for Poller<T> {
+    async fn run(&mut self) -> Result<()> {
         log::info!("start polling for changes");
-
-        tokio::spawn(async move {
-            retry_backoff!(self.poll(), |err, duration: Duration| log::warn!(
-                "failed to poll: {}, retrying in {:?}s",
-                err,
-                duration.as_secs()
-            ));
-        })
+        self.poll().await
     }
 }
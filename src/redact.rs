@@ -0,0 +1,327 @@
+use std::{
+    borrow::Cow,
+    fmt::{self, Debug},
+};
+
+use anyhow::{Context, Result};
+use http::{
+    header::{HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION},
+    HeaderMap, HeaderValue, Request, Response, Uri,
+};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// Headers and query parameters of which the values are masked in logs, the defaults are
+/// replaced when a list is set
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedactionSettings {
+    pub headers: Vec<String>,
+    /// Names of query parameters, compared case-insensitively
+    pub query_params: Vec<String>,
+}
+
+impl Default for RedactionSettings {
+    fn default() -> Self {
+        Self {
+            headers: [
+                "authorization",
+                "proxy-authorization",
+                "cookie",
+                "set-cookie",
+                "x-api-key",
+            ]
+            .map(String::from)
+            .to_vec(),
+            query_params: [
+                "access_token",
+                "api_key",
+                "apikey",
+                "token",
+                "password",
+                "secret",
+                "client_secret",
+                "bsn",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+}
+
+struct Redactor {
+    headers: Vec<HeaderName>,
+    query_params: Vec<String>,
+}
+
+impl Redactor {
+    fn new(settings: &RedactionSettings) -> Result<Self> {
+        let headers = settings
+            .headers
+            .iter()
+            .map(|name| {
+                HeaderName::try_from(name.as_str())
+                    .with_context(|| format!("invalid header name to redact: {}", name))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self {
+            headers,
+            query_params: settings
+                .query_params
+                .iter()
+                .map(|name| name.to_lowercase())
+                .collect(),
+        })
+    }
+
+    fn headers(&self, headers: &HeaderMap) -> HeaderMap {
+        let mut headers = headers.clone();
+
+        for name in &self.headers {
+            if let http::header::Entry::Occupied(mut entry) = headers.entry(name) {
+                for value in entry.iter_mut() {
+                    *value = HeaderValue::from_static(REDACTED);
+                }
+            }
+        }
+
+        headers
+    }
+
+    fn remove_headers(&self, headers: &mut HeaderMap) {
+        for name in [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE]
+            .iter()
+            .chain(&self.headers)
+        {
+            headers.remove(name);
+        }
+    }
+
+    fn query<'a>(&self, query: &'a str) -> Cow<'a, str> {
+        let is_sensitive = |pair: &str| {
+            let name = pair.split_once('=').map_or(pair, |(name, _)| name);
+            // Names are compared as the backend decodes them, so `b%73n` is `bsn`
+            let name = form_urlencoded::parse(name.as_bytes())
+                .next()
+                .map(|(name, _)| name)
+                .unwrap_or_default();
+
+            self.query_params
+                .iter()
+                .any(|param| param.eq_ignore_ascii_case(&name))
+        };
+
+        if !query.split('&').any(is_sensitive) {
+            return Cow::Borrowed(query);
+        }
+
+        let redacted = query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) if is_sensitive(pair) => {
+                    Cow::Owned(format!("{}={}", name, REDACTED))
+                }
+                _ => Cow::Borrowed(pair),
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+
+        Cow::Owned(redacted)
+    }
+}
+
+static REDACTOR: OnceCell<Redactor> = OnceCell::new();
+
+fn redactor() -> &'static Redactor {
+    REDACTOR.get_or_init(|| {
+        Redactor::new(&RedactionSettings::default()).expect("valid default redaction settings")
+    })
+}
+
+/// Installs the redaction settings, which are used by every log from then on
+pub fn init(settings: &RedactionSettings) -> Result<()> {
+    let redactor = Redactor::new(settings)?;
+    let _ = REDACTOR.set(redactor);
+
+    Ok(())
+}
+
+/// Returns a copy of the headers with the values of sensitive headers masked
+pub fn headers(headers: &HeaderMap) -> HeaderMap {
+    redactor().headers(headers)
+}
+
+/// Removes sensitive headers, from copies of requests that are sent to another host. The
+/// credentials headers are always removed, even when they're not in the redaction list.
+pub fn remove_headers(headers: &mut HeaderMap) {
+    redactor().remove_headers(headers)
+}
+
+/// Masks the values of sensitive parameters in a query string
+pub fn query(query: &str) -> Cow<'_, str> {
+    redactor().query(query)
+}
+
+/// Returns the URI with the values of sensitive query parameters masked
+pub fn uri(uri: &Uri) -> String {
+    let uri = uri.to_string();
+
+    match uri.split_once('?') {
+        Some((base, query)) => format!("{}?{}", base, self::query(query)),
+        None => uri,
+    }
+}
+
+/// Formats requests and responses like their `Debug` implementation, but without the
+/// body and with sensitive values masked
+pub struct Redacted<'a, T>(pub &'a T);
+
+impl<B> Debug for Redacted<'_, Request<B>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Request")
+            .field("method", self.0.method())
+            .field("uri", &format_args!("{}", uri(self.0.uri())))
+            .field("version", &self.0.version())
+            .field("headers", &headers(self.0.headers()))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B> Debug for Redacted<'_, Response<B>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Response")
+            .field("status", &self.0.status())
+            .field("version", &self.0.version())
+            .field("headers", &headers(self.0.headers()))
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::header::SET_COOKIE;
+
+    use super::*;
+
+    fn custom() -> Redactor {
+        Redactor::new(&RedactionSettings {
+            headers: vec!["x-secret".to_string()],
+            query_params: vec!["Session".to_string()],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn masks_default_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer abc"));
+        headers.insert("x-api-key", HeaderValue::from_static("abc"));
+        headers.insert("accept", HeaderValue::from_static("text/plain"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("a=1"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("b=2"));
+
+        let redacted = Redactor::new(&RedactionSettings::default())
+            .unwrap()
+            .headers(&headers);
+
+        assert_eq!(redacted[AUTHORIZATION], REDACTED);
+        assert_eq!(redacted["x-api-key"], REDACTED);
+        assert_eq!(redacted["accept"], "text/plain");
+        assert_eq!(
+            redacted.get_all(SET_COOKIE).iter().collect::<Vec<_>>(),
+            [REDACTED, REDACTED]
+        );
+    }
+
+    #[test]
+    fn custom_lists_replace_the_defaults() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer abc"));
+        headers.insert("x-secret", HeaderValue::from_static("abc"));
+
+        let redactor = custom();
+        let redacted = redactor.headers(&headers);
+
+        assert_eq!(redacted[AUTHORIZATION], "Bearer abc");
+        assert_eq!(redacted["x-secret"], REDACTED);
+        assert_eq!(
+            redactor.query("session=abc&token=def"),
+            "session=[REDACTED]&token=def"
+        );
+
+        // Credentials never leave with a copy of the request, whatever the list says
+        redactor.remove_headers(&mut headers);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_header_names() {
+        let settings = RedactionSettings {
+            headers: vec!["invalid header".to_string()],
+            query_params: vec![],
+        };
+
+        assert!(Redactor::new(&settings).is_err());
+    }
+
+    #[test]
+    fn masks_default_query_params() {
+        let redactor = Redactor::new(&RedactionSettings::default()).unwrap();
+
+        assert_eq!(redactor.query("page=1&sort=name"), "page=1&sort=name");
+        assert!(matches!(redactor.query("page=1"), Cow::Borrowed(_)));
+        assert_eq!(
+            redactor.query("bsn=123456782&page=1&API_KEY=abc&token"),
+            "bsn=[REDACTED]&page=1&API_KEY=[REDACTED]&token"
+        );
+    }
+
+    #[test]
+    fn masks_encoded_query_param_names() {
+        let redactor = Redactor::new(&RedactionSettings::default()).unwrap();
+
+        assert_eq!(
+            redactor.query("b%73n=123456782&access%5Ftoken=abc&%42SN=1"),
+            "b%73n=[REDACTED]&access%5Ftoken=[REDACTED]&%42SN=[REDACTED]"
+        );
+
+        let redactor = Redactor::new(&RedactionSettings {
+            headers: vec![],
+            query_params: vec!["api key".to_string()],
+        })
+        .unwrap();
+
+        assert_eq!(redactor.query("api+key=abc"), "api+key=[REDACTED]");
+    }
+
+    #[test]
+    fn masks_uris_and_debug_output() {
+        let uri = "https://example.com/orders?bsn=123456782&page=1"
+            .parse::<Uri>()
+            .unwrap();
+
+        assert_eq!(
+            super::uri(&uri),
+            "https://example.com/orders?bsn=[REDACTED]&page=1"
+        );
+        assert_eq!(super::uri(&"/orders".parse().unwrap()), "/orders");
+
+        let request = Request::builder()
+            .uri(uri)
+            .header(AUTHORIZATION, "Bearer secret")
+            .body(())
+            .unwrap();
+        let response = Response::builder()
+            .header(SET_COOKIE, "session=secret")
+            .body(())
+            .unwrap();
+        let output = format!("{:?} {:?}", Redacted(&request), Redacted(&response));
+
+        assert!(!output.contains("secret"), "{}", output);
+        assert!(!output.contains("123456782"), "{}", output);
+        assert!(output.contains(REDACTED), "{}", output);
+    }
+}
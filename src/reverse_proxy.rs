@@ -1,23 +1,45 @@
 use std::{
     error::Error,
     fmt::{self, Display},
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
-use http::{header::HeaderName, uri::InvalidUri, HeaderMap, Method, Uri};
-use hyper::{client::connect::Connect, Body, Client};
+use http::{
+    header::{HeaderName, CONNECTION, TE, UPGRADE},
+    uri::InvalidUri,
+    HeaderMap, HeaderValue, Method, StatusCode, Uri,
+};
+use hyper::{body::HttpBody, client::connect::Connect, Body, Client};
+use tokio::time;
 use warp::{
-    path::Tail,
     reject::{self, Reject},
     reply::Response,
     Rejection,
 };
 
+use crate::{
+    circuit_breaker::Circuit,
+    client_pool::Protocol,
+    forwarding::{self, ForwardingSettings},
+    grpc,
+    mirror::{Mirror, Shadow},
+    path::{self, PathError},
+    redact::Redacted,
+    serve::{Peer, Upgrade},
+    timeouts::Timeouts,
+    trace::{Kind, Span, TRACEPARENT},
+    tunnel,
+};
+
 const MAX_RETRIES: usize = 3;
 
-static HOP_HEADERS: [HeaderName; 8] = [
+static HOP_HEADERS: [HeaderName; 9] = [
     HeaderName::from_static("connection"),
     HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
     HeaderName::from_static("proxy-authenticate"),
     HeaderName::from_static("proxy-authorization"),
     HeaderName::from_static("te"),
@@ -26,13 +48,34 @@ static HOP_HEADERS: [HeaderName; 8] = [
     HeaderName::from_static("upgrade"),
 ];
 
-#[inline(always)]
+/// Removes the headers that only apply to a single connection (RFC 9110 section 7.6.1)
 fn remove_hop_headers(headers: &mut HeaderMap) {
+    // Any header can be marked as hop-by-hop by listing it in the Connection header
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    for header in listed {
+        headers.remove(header);
+    }
+
     for header in HOP_HEADERS.iter() {
         headers.remove(header);
     }
 }
 
+/// Restores the headers needed for an upgrade after the hop headers are removed
+fn keep_upgrade(headers: &mut HeaderMap, protocol: Option<HeaderValue>) {
+    if let Some(protocol) = protocol {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(UPGRADE, protocol);
+    }
+}
+
 #[inline]
 fn copy_headers(headers: HeaderMap, dest: &mut HeaderMap) {
     *dest = headers;
@@ -42,32 +85,174 @@ fn copy_headers(headers: HeaderMap, dest: &mut HeaderMap) {
 #[derive(Debug)]
 pub enum IntoRequestError {
     InvalidUri(InvalidUri),
+    InvalidPath(PathError),
+    Body(hyper::Error),
 }
 
 impl Reject for IntoRequestError {}
 
+impl Display for IntoRequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUri(e) => write!(f, "invalid URI: {}", e),
+            Self::InvalidPath(e) => write!(f, "invalid path: {}", e),
+            Self::Body(e) => write!(f, "failed to read request body: {}", e),
+        }
+    }
+}
+
 pub struct Request {
     method: Method,
-    path: Tail,
+    path: String,
     query: String,
     headers: HeaderMap,
-    body: Bytes,
+    body: Body,
+    upgrade: Option<Upgrade>,
+    peer: Option<Peer>,
+    forwarding: Option<ForwardingSettings>,
+    mirror: Option<Mirror>,
 }
 
 impl Request {
-    pub fn new(method: Method, path: Tail, query: String, headers: HeaderMap, body: Bytes) -> Self {
+    pub fn new(
+        method: Method,
+        path: String,
+        query: String,
+        headers: HeaderMap,
+        body: Body,
+        upgrade: Option<Upgrade>,
+        peer: Option<Peer>,
+    ) -> Self {
+        // Hyper also allows the connection to be upgraded for CONNECT requests
+        let upgrade = upgrade.filter(|_| headers.contains_key(UPGRADE));
+
         Self {
             method,
             path,
             query,
             headers,
             body,
+            upgrade,
+            peer,
+            forwarding: None,
+            mirror: None,
+        }
+    }
+
+    /// Adds forwarding headers to the proxied request
+    pub fn forwarding(mut self, settings: ForwardingSettings) -> Self {
+        self.forwarding = Some(settings);
+        self
+    }
+
+    /// Sends a copy of the request to the mirror, upgrades and streaming gRPC calls are not
+    /// mirrored
+    pub fn mirror(mut self, mirror: Mirror) -> Self {
+        self.mirror = Some(mirror);
+        self
+    }
+
+    /// Path relative to the upstream, without a leading slash
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn set_path(&mut self, path: String) {
+        self.path = path;
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Reads the complete body, it is still sent upstream afterwards
+    pub async fn buffer_body(&mut self) -> Result<Bytes, IntoRequestError> {
+        let body = std::mem::take(&mut self.body);
+        let bytes = hyper::body::to_bytes(body)
+            .await
+            .map_err(IntoRequestError::Body)?;
+
+        self.body = Body::from(bytes.clone());
+        Ok(bytes)
+    }
+
+    pub fn method(&self) -> &Method {
+        &self.method
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    pub fn peer(&self) -> Option<&Peer> {
+        self.peer.as_ref()
+    }
+
+    /// Returns true if the client wants to switch to another protocol (e.g. WebSocket)
+    pub fn is_upgrade(&self) -> bool {
+        self.upgrade.is_some()
+    }
+
+    pub fn is_grpc(&self) -> bool {
+        grpc::is_grpc(&self.headers)
+    }
+
+    /// Returns the HTTP version(s) that can be used to send the request upstream
+    pub fn protocol(&self) -> Protocol {
+        if self.is_upgrade() {
+            Protocol::Http1
+        } else if self.is_grpc() {
+            Protocol::Http2
+        } else {
+            Protocol::Auto
         }
     }
 }
 
-fn build_uri(req: &Request, upstream: &str) -> Result<Uri, InvalidUri> {
-    let request_path = req.path.as_str();
+/// Body of the proxied request, gRPC calls can be long-lived streams and are therefore not
+/// buffered. This means that they can only be sent once.
+enum RequestBody {
+    Buffered(Bytes),
+    Streaming(Body),
+}
+
+impl RequestBody {
+    async fn new(request: &mut Request) -> Result<Self, IntoRequestError> {
+        let body = std::mem::take(&mut request.body);
+
+        if request.is_grpc() {
+            return Ok(Self::Streaming(body));
+        }
+
+        hyper::body::to_bytes(body)
+            .await
+            .map(Self::Buffered)
+            .map_err(IntoRequestError::Body)
+    }
+
+    fn can_retry(&self) -> bool {
+        matches!(self, Self::Buffered(_))
+    }
+
+    /// Returns the body for the next attempt
+    fn next(&mut self) -> Body {
+        match self {
+            Self::Buffered(bytes) => bytes.clone().into(),
+            Self::Streaming(body) => std::mem::take(body),
+        }
+    }
+}
+
+fn build_uri(req: &Request, upstream: &str) -> Result<Uri, IntoRequestError> {
+    // The upstream is the boundary of the service, the path may not escape it
+    let request_path = path::canonicalize(&req.path).map_err(|e| {
+        log::warn!("rejected path /{}: {}", req.path, e);
+        IntoRequestError::InvalidPath(e)
+    })?;
     let mut url = String::with_capacity(
         upstream.len()
             + request_path.len()
@@ -79,14 +264,14 @@ fn build_uri(req: &Request, upstream: &str) -> Result<Uri, InvalidUri> {
     );
 
     url.push_str(upstream);
-    url.push_str(request_path);
+    url.push_str(&request_path);
 
     if !req.query.is_empty() {
         url.push('?');
         url.push_str(&req.query);
     }
 
-    Uri::try_from(url)
+    Uri::try_from(url).map_err(IntoRequestError::InvalidUri)
 }
 
 fn prepare_headers(req: &Request) -> HeaderMap {
@@ -95,6 +280,20 @@ fn prepare_headers(req: &Request) -> HeaderMap {
 
     // Remove the host header as it will be set automatically
     headers.remove("host");
+
+    if req.is_upgrade() {
+        keep_upgrade(&mut headers, req.headers.get(UPGRADE).cloned());
+    }
+
+    if let Some(settings) = &req.forwarding {
+        forwarding::add_headers(&mut headers, req.peer.as_ref(), settings);
+    }
+
+    // The only value of TE that is allowed in HTTP/2, required for gRPC
+    if grpc::accepts_trailers(&req.headers) {
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+    }
+
     headers
 }
 
@@ -102,30 +301,94 @@ fn create_proxied_request(
     method: Method,
     uri: Uri,
     headers: HeaderMap,
-    body: Bytes,
+    body: Body,
 ) -> Result<hyper::Request<Body>, Rejection> {
-    let mut out = hyper::Request::new(body.into());
+    let mut out = hyper::Request::new(body);
 
     *out.headers_mut() = headers;
     *out.method_mut() = method;
     *out.uri_mut() = uri;
 
-    log::trace!("proxy request (request={:#?})", out);
+    log::trace!("proxy request (request={:#?})", Redacted(&out));
 
     Ok(out)
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum TimeoutKind {
+    Connect,
+    ResponseHeaders,
+    Total,
+}
+
+impl Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Connect => write!(f, "connect"),
+            Self::ResponseHeaders => write!(f, "response headers"),
+            Self::Total => write!(f, "total"),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ProxyError {
     Hyper(hyper::Error),
     MaxRetries(hyper::Error),
+    CircuitOpen(String),
+    Timeout {
+        upstream: String,
+        kind: TimeoutKind,
+        duration: Duration,
+    },
+}
+
+impl ProxyError {
+    fn timeout(upstream: &str, kind: TimeoutKind, duration: Duration) -> Self {
+        Self::Timeout {
+            upstream: upstream.to_string(),
+            kind,
+            duration,
+        }
+    }
+
+    /// Returns true if the upstream actively refused the connection
+    pub fn is_connection_refused(&self) -> bool {
+        match self {
+            Self::Hyper(e) | Self::MaxRetries(e) => {
+                io_error_kind(e) == Some(io::ErrorKind::ConnectionRefused)
+            }
+            _ => false,
+        }
+    }
 }
 
 impl Reject for ProxyError {}
 
+impl Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Hyper(e) => write!(f, "{}", e),
+            Self::MaxRetries(e) => write!(f, "max retries exceeded: {}", e),
+            Self::CircuitOpen(upstream) => write!(f, "circuit for {} is open", upstream),
+            Self::Timeout {
+                upstream,
+                kind,
+                duration,
+            } => write!(
+                f,
+                "{} did not respond within {} ({} timeout)",
+                upstream,
+                humantime::format_duration(*duration),
+                kind
+            ),
+        }
+    }
+}
+
 impl Display for Request {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} /{}", self.method, self.path.as_str())
+        write!(f, "{} /{}", self.method, self.path)
     }
 }
 
@@ -136,10 +399,139 @@ fn is_h2_goaway_no_error(e: &hyper::Error) -> bool {
         .and_then(|source| source.downcast_ref::<h2::Error>()), Some(e) if e.is_go_away() && e.is_remote() && e.reason() == Some(h2::Reason::NO_ERROR))
 }
 
+/// Returns true if the response indicates that the upstream itself is failing
+#[inline(always)]
+fn is_upstream_failure(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Returns the kind of the first IO error in the source chain
+fn io_error_kind(e: &hyper::Error) -> Option<io::ErrorKind> {
+    let mut source = e.source();
+
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            return Some(err.kind());
+        }
+
+        source = err.source();
+    }
+
+    None
+}
+
+/// Forwards the body to a new body until the deadline is reached, trailers are kept
+fn with_deadline(mut body: Body, deadline: Instant, upstream: String) -> Body {
+    let (mut tx, rx) = Body::channel();
+
+    tokio::spawn(async move {
+        let pipe = async {
+            while let Some(chunk) = body.data().await {
+                tx.send_data(chunk?).await?;
+            }
+
+            if let Some(trailers) = body.trailers().await? {
+                tx.send_trailers(trailers).await?;
+            }
+
+            Ok::<_, hyper::Error>(())
+        };
+
+        match time::timeout_at(deadline.into(), pipe).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => log::debug!("failed to stream response of {}: {}", upstream, e),
+            Err(_) => {
+                log::warn!("response of {} exceeded the total timeout", upstream);
+                tx.abort();
+            }
+        }
+    });
+
+    rx
+}
+
 pub async fn handle<C>(
     http: Client<C>,
-    request: Request,
+    mut request: Request,
     upstream: &str,
+    circuit: &Arc<Circuit>,
+    timeouts: &Timeouts,
+) -> Result<Response, Rejection>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    let permit = circuit
+        .try_acquire()
+        .ok_or_else(|| reject::custom(ProxyError::CircuitOpen(circuit.name().to_string())))?;
+
+    // Covers every attempt until the response head is received, the body is streamed after
+    let mut span = Span::child_of_current("send to upstream", Kind::Client);
+    span.set_attribute("http.method", request.method.as_str());
+    span.set_attribute("nlx.upstream", circuit.name());
+
+    // The upstream continues the trace as a child of this span
+    if let Some(context) = span.context() {
+        request.headers.insert(&TRACEPARENT, context.traceparent());
+    }
+
+    let start = Instant::now();
+    let mut shadow = None;
+    let result = send(
+        http,
+        request,
+        upstream,
+        circuit.name(),
+        timeouts,
+        start,
+        &mut shadow,
+    )
+    .await;
+
+    // The mirrored request was sent alongside, it only waits for the outcome of this one
+    if let Some(shadow) = shadow {
+        let outcome = match &result {
+            Ok(response) => Ok(response.status()),
+            Err(rejection) => Err(rejection
+                .find::<ProxyError>()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| "invalid request".to_string())),
+        };
+
+        shadow.complete(outcome, start.elapsed());
+    }
+
+    match &result {
+        Ok(response) => span.set_attribute("http.status_code", response.status().as_u16()),
+        Err(rejection) => {
+            if let Some(e) = rejection.find::<ProxyError>() {
+                span.set_error(e);
+            }
+        }
+    }
+
+    match &result {
+        Ok(response) => permit.record(!is_upstream_failure(response.status()), start.elapsed()),
+        Err(rejection) if rejection.find::<ProxyError>().is_some() => {
+            permit.record(false, start.elapsed())
+        }
+        // Errors caused by the request itself say nothing about the upstream
+        Err(_) => drop(permit),
+    }
+
+    result
+}
+
+async fn send<C>(
+    http: Client<C>,
+    mut request: Request,
+    upstream: &str,
+    name: &str,
+    timeouts: &Timeouts,
+    start: Instant,
+    shadow: &mut Option<Shadow>,
 ) -> Result<Response, Rejection>
 where
     C: Connect + Clone + Send + Sync + 'static,
@@ -147,25 +539,82 @@ where
     let mut retries = MAX_RETRIES;
 
     // Prepare the request once to avoid doing more work in case of a retry
-    let uri = build_uri(&request, upstream).map_err(IntoRequestError::InvalidUri)?;
+    let uri = build_uri(&request, upstream)?;
     let headers = prepare_headers(&request);
-    let (method, body) = (request.method, request.body);
+    let mut body = RequestBody::new(&mut request).await?;
+    let (method, upgrade) = (request.method.clone(), request.upgrade.take());
+
+    if let (Some(mirror), RequestBody::Buffered(bytes), None) =
+        (request.mirror.take(), &body, &upgrade)
+    {
+        // The mirror may never affect the primary request
+        match build_uri(&request, mirror.url()) {
+            Ok(uri) => *shadow = mirror.spawn(method.clone(), uri, headers.clone(), bytes.clone()),
+            Err(e) => log::warn!("skipped mirror of {}: {}", name, e),
+        }
+    }
+
+    // The response headers should arrive before the total timeout expires as well
+    let timeout = |kind, duration| reject::custom(ProxyError::timeout(name, kind, duration));
+    let total_deadline = timeouts.total.map(|total| start + total);
+    let headers_deadline = [
+        timeouts
+            .response_headers
+            .map(|duration| (start + duration, TimeoutKind::ResponseHeaders, duration)),
+        timeouts
+            .total
+            .map(|duration| (start + duration, TimeoutKind::Total, duration)),
+    ]
+    .into_iter()
+    .flatten()
+    .min_by_key(|(deadline, _, _)| *deadline);
 
     loop {
         let request =
-            create_proxied_request(method.clone(), uri.clone(), headers.clone(), body.clone())?;
+            create_proxied_request(method.clone(), uri.clone(), headers.clone(), body.next())?;
 
-        match http.request(request).await {
+        let result = match headers_deadline {
+            Some((deadline, kind, duration)) => {
+                time::timeout_at(deadline.into(), http.request(request))
+                    .await
+                    .map_err(|_| timeout(kind, duration))?
+            }
+            None => http.request(request).await,
+        };
+
+        match result {
             Ok(mut response) => {
-                log::trace!("proxy response (response={:#?})", response);
+                log::trace!("proxy response (response={:#?})", Redacted(&response));
 
+                let protocol = response.headers().get(UPGRADE).cloned();
                 remove_hop_headers(response.headers_mut());
 
+                if response.status() == StatusCode::SWITCHING_PROTOCOLS {
+                    if let Some(client) = upgrade.as_ref().and_then(Upgrade::take) {
+                        keep_upgrade(response.headers_mut(), protocol);
+
+                        let upstream = hyper::upgrade::on(&mut response);
+                        tokio::spawn(tunnel::splice(
+                            client,
+                            upstream,
+                            name.to_string(),
+                            timeouts.idle,
+                        ));
+
+                        return Ok(response);
+                    }
+                }
+
+                if let Some(deadline) = total_deadline {
+                    let body = std::mem::take(response.body_mut());
+                    *response.body_mut() = with_deadline(body, deadline, name.to_string());
+                }
+
                 return Ok(response);
             }
             Err(e) => {
                 // fixes: https://github.com/hyperium/hyper/issues/2500
-                if is_h2_goaway_no_error(&e) {
+                if is_h2_goaway_no_error(&e) && body.can_retry() {
                     retries -= 1;
 
                     if retries == 0 {
@@ -177,6 +626,12 @@ where
                     continue;
                 }
 
+                if io_error_kind(&e) == Some(io::ErrorKind::TimedOut) {
+                    if let Some(duration) = timeouts.connect {
+                        return Err(timeout(TimeoutKind::Connect, duration));
+                    }
+                }
+
                 return Err(reject::custom(ProxyError::Hyper(e)));
             }
         }
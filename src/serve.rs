@@ -0,0 +1,219 @@
+use std::{
+    convert::Infallible,
+    error::Error as StdError,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use http::{header::HOST, Version};
+use hyper::{
+    body::HttpBody, server::conn::Http, service::Service, upgrade::OnUpgrade, Body, Request,
+    Response,
+};
+use rustls::ServerConfig;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+
+use crate::tls::PeerIdentity;
+
+/// Connections of which the TLS handshake doesn't complete in time are closed
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Pending upgrade of the connection, hyper puts it in the request extensions but warp
+/// only hands out extensions that can be cloned.
+#[derive(Clone)]
+pub struct Upgrade(Arc<Mutex<Option<OnUpgrade>>>);
+
+impl Upgrade {
+    pub fn take(&self) -> Option<OnUpgrade> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+/// Peer that sent the request and how it was received, warp only knows the address when
+/// it runs the server itself
+#[derive(Debug, Clone)]
+pub struct Peer {
+    pub addr: SocketAddr,
+    pub tls: bool,
+    /// Identity from the client certificate of the peer (mTLS only)
+    pub identity: Option<Arc<PeerIdentity>>,
+    pub version: Version,
+    /// Host as requested by the peer
+    pub host: Option<String>,
+}
+
+impl Peer {
+    fn new(addr: SocketAddr, tls: bool, identity: Option<PeerIdentity>) -> Self {
+        Self {
+            addr,
+            tls,
+            identity: identity.map(Arc::new),
+            version: Version::default(),
+            host: None,
+        }
+    }
+
+    fn for_request(&self, request: &Request<Body>) -> Self {
+        let host = request
+            .uri()
+            .authority()
+            .map(|authority| authority.as_str())
+            .or_else(|| {
+                request
+                    .headers()
+                    .get(HOST)
+                    .and_then(|host| host.to_str().ok())
+            })
+            .map(String::from);
+
+        Self {
+            version: request.version(),
+            host,
+            ..self.clone()
+        }
+    }
+}
+
+/// Serves (warp) services on `addr` until the shutdown token is cancelled, the connections
+/// that are still open at that moment are closed gracefully. Unlike `warp::serve` this
+/// makes the peer and the connection upgrade available to filters using `warp::ext`.
+pub async fn serve<S, B>(
+    addr: SocketAddr,
+    service: S,
+    tls_config: Option<ServerConfig>,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    S: Service<Request<Body>, Response = Response<B>, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let acceptor = tls_config.map(|config| TlsAcceptor::from(Arc::new(config)));
+
+    // Every connection holds a sender, the channel closes when all of them are done
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+
+    log::info!("listening on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, remote_addr) = tokio::select! {
+            result = listener.accept() => match result {
+                Ok(conn) => conn,
+                Err(e) => {
+                    log::warn!("failed to accept connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown.cancelled() => break,
+        };
+
+        let service = service.clone();
+        let acceptor = acceptor.clone();
+        let shutdown = shutdown.clone();
+        let done_tx = done_tx.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = accept(stream, remote_addr, acceptor, service, shutdown).await {
+                log::debug!("connection from {} failed: {}", remote_addr, e);
+            }
+
+            drop(done_tx);
+        });
+    }
+
+    drop(done_tx);
+    done_rx.recv().await;
+
+    Ok(())
+}
+
+async fn accept<S, B>(
+    stream: TcpStream,
+    remote_addr: SocketAddr,
+    acceptor: Option<TlsAcceptor>,
+    service: S,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    S: Service<Request<Body>, Response = Response<B>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    stream.set_nodelay(true)?;
+
+    match acceptor {
+        Some(acceptor) => {
+            // A client that never completes the handshake may not hold up the shutdown
+            let stream = tokio::select! {
+                result = time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)) => {
+                    result.map_err(|_| anyhow!("TLS handshake timed out"))??
+                }
+                _ = shutdown.cancelled() => return Ok(()),
+            };
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| PeerIdentity::from_der(&cert.0).ok());
+            let peer = Peer::new(remote_addr, true, identity);
+
+            serve_connection(stream, peer, service, shutdown).await
+        }
+        None => {
+            let peer = Peer::new(remote_addr, false, None);
+            serve_connection(stream, peer, service, shutdown).await
+        }
+    }
+}
+
+async fn serve_connection<I, S, B>(
+    io: I,
+    peer: Peer,
+    mut service: S,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    I: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    S: Service<Request<Body>, Response = Response<B>, Error = Infallible> + Send + 'static,
+    S::Future: Send + 'static,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    let service = hyper::service::service_fn(move |mut request: Request<Body>| {
+        let peer = peer.for_request(&request);
+        let extensions = request.extensions_mut();
+        extensions.insert(peer);
+
+        if let Some(on_upgrade) = extensions.remove::<OnUpgrade>() {
+            extensions.insert(Upgrade(Arc::new(Mutex::new(Some(on_upgrade)))));
+        }
+
+        service.call(request)
+    });
+
+    let conn = Http::new().serve_connection(io, service).with_upgrades();
+    tokio::pin!(conn);
+
+    tokio::select! {
+        result = conn.as_mut() => return Ok(result?),
+        _ = shutdown.cancelled() => conn.as_mut().graceful_shutdown(),
+    }
+
+    Ok(conn.await?)
+}
@@ -0,0 +1,33 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use tokio::fs;
+
+use crate::{
+    access_log::AccessLogSettings, inway::InwaySettings, outway::OutwaySettings,
+    redact::RedactionSettings, trace::TracingSettings,
+};
+
+/// Gateway specific settings which can't be configured in NLX Management
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub inway: InwaySettings,
+    pub outway: OutwaySettings,
+    pub tracing: TracingSettings,
+    pub access_log: AccessLogSettings,
+    pub redaction: RedactionSettings,
+}
+
+impl Settings {
+    pub async fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("failed to read settings from {}", path.display()))?;
+
+        toml::from_str(&contents)
+            .with_context(|| format!("failed to parse settings from {}", path.display()))
+    }
+}
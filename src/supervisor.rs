@@ -0,0 +1,254 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Result;
+use backoff::{backoff::Backoff, ExponentialBackoff};
+use clap::ValueEnum;
+use futures_util::future::join_all;
+use serde::{Serialize, Serializer};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tonic::async_trait;
+
+use crate::metrics;
+
+/// A long running background task which is (re)started by the [`Supervisor`]
+#[async_trait]
+pub trait Task: Send + 'static {
+    async fn run(&mut self) -> Result<()>;
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Restart tasks when they fail or exit
+    Always,
+    /// Only restart tasks when they fail
+    OnFailure,
+    /// Never restart tasks
+    Never,
+}
+
+#[derive(Debug, Clone)]
+pub struct RestartConfig {
+    pub policy: RestartPolicy,
+    pub max_retries: Option<usize>,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RestartConfig {
+    fn backoff(&self) -> ExponentialBackoff {
+        ExponentialBackoff {
+            initial_interval: self.initial_backoff,
+            current_interval: self.initial_backoff,
+            max_interval: self.max_backoff,
+            // Never give up, the number of retries is bounded by `max_retries` instead
+            max_elapsed_time: None,
+            ..ExponentialBackoff::default()
+        }
+    }
+}
+
+pub fn serialize_time<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&humantime::format_rfc3339_seconds(*time))
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum TaskState {
+    Running {
+        #[serde(serialize_with = "serialize_time")]
+        since: SystemTime,
+    },
+    Retrying {
+        #[serde(serialize_with = "serialize_time")]
+        since: SystemTime,
+        attempt: usize,
+        error: String,
+    },
+    Failed {
+        #[serde(serialize_with = "serialize_time")]
+        since: SystemTime,
+        error: String,
+    },
+    Stopped {
+        #[serde(serialize_with = "serialize_time")]
+        since: SystemTime,
+    },
+}
+
+impl TaskState {
+    const NAMES: [&'static str; 4] = ["running", "retrying", "failed", "stopped"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Running { .. } => "running",
+            Self::Retrying { .. } => "retrying",
+            Self::Failed { .. } => "failed",
+            Self::Stopped { .. } => "stopped",
+        }
+    }
+}
+
+struct Inner {
+    config: RestartConfig,
+    states: RwLock<BTreeMap<&'static str, TaskState>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    shutdown: CancellationToken,
+}
+
+/// Owns all background tasks, restarts them according to the restart policy and keeps
+/// track of their state
+#[derive(Clone)]
+pub struct Supervisor {
+    inner: Arc<Inner>,
+}
+
+impl Supervisor {
+    pub fn new(config: RestartConfig) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                states: RwLock::default(),
+                handles: Mutex::default(),
+                shutdown: CancellationToken::new(),
+            }),
+        }
+    }
+
+    fn set_state(&self, name: &'static str, state: TaskState) {
+        for state_name in TaskState::NAMES {
+            metrics::TASK_STATE
+                .with_label_values(&[name, state_name])
+                .set((state_name == state.name()) as i64);
+        }
+
+        self.inner.states.write().unwrap().insert(name, state);
+    }
+
+    pub fn spawn(&self, name: &'static str, task: impl Task) {
+        log::info!("starting task: {}", name);
+
+        let supervisor = self.clone();
+        let handle = tokio::spawn(supervisor.supervise(name, task));
+
+        self.inner.handles.lock().unwrap().push(handle);
+    }
+
+    async fn supervise(self, name: &'static str, mut task: impl Task) {
+        let config = &self.inner.config;
+        let shutdown = &self.inner.shutdown;
+        let mut backoff = config.backoff();
+        let mut attempt = 0;
+
+        loop {
+            self.set_state(
+                name,
+                TaskState::Running {
+                    since: SystemTime::now(),
+                },
+            );
+
+            let started_at = Instant::now();
+            let result = tokio::select! {
+                result = task.run() => result,
+                _ = shutdown.cancelled() => break,
+            };
+
+            // A task that ran for a while before failing shouldn't be punished for earlier failures
+            if started_at.elapsed() > config.max_backoff {
+                backoff.reset();
+                attempt = 0;
+            }
+
+            let error = match result {
+                Ok(_) if config.policy != RestartPolicy::Always => {
+                    log::info!("task {} stopped", name);
+                    break;
+                }
+                Ok(_) => "task exited".to_string(),
+                Err(e) => format!("{:?}", e),
+            };
+
+            attempt += 1;
+
+            if config.policy == RestartPolicy::Never
+                || matches!(config.max_retries, Some(max_retries) if attempt > max_retries)
+            {
+                log::error!("task {} failed: {}", name, error);
+                self.set_state(
+                    name,
+                    TaskState::Failed {
+                        since: SystemTime::now(),
+                        error,
+                    },
+                );
+                return;
+            }
+
+            let duration = backoff.next_backoff().unwrap_or(config.max_backoff);
+
+            log::warn!(
+                "task {} failed: {}, retrying in {}s",
+                name,
+                error,
+                duration.as_secs()
+            );
+            self.set_state(
+                name,
+                TaskState::Retrying {
+                    since: SystemTime::now(),
+                    attempt,
+                    error,
+                },
+            );
+
+            tokio::select! {
+                _ = tokio::time::sleep(duration) => {},
+                _ = shutdown.cancelled() => break,
+            }
+
+            metrics::TASK_RESTARTS.with_label_values(&[name]).inc();
+        }
+
+        self.set_state(
+            name,
+            TaskState::Stopped {
+                since: SystemTime::now(),
+            },
+        );
+    }
+
+    /// Returns a snapshot of the state of all tasks
+    pub fn states(&self) -> BTreeMap<&'static str, TaskState> {
+        self.inner.states.read().unwrap().clone()
+    }
+
+    /// Returns true if none of the tasks has failed permanently
+    pub fn is_healthy(&self) -> bool {
+        self.inner
+            .states
+            .read()
+            .unwrap()
+            .values()
+            .all(|state| !matches!(state, TaskState::Failed { .. }))
+    }
+
+    /// Returns a token which is cancelled when the supervisor shuts down
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.inner.shutdown.clone()
+    }
+
+    /// Stops all tasks and waits for them to finish
+    pub async fn shutdown(&self) {
+        log::info!("stopping all tasks");
+
+        self.inner.shutdown.cancel();
+
+        let handles = std::mem::take(&mut *self.inner.handles.lock().unwrap());
+        join_all(handles).await;
+    }
+}
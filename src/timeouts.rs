@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+const DEFAULT_CONNECT: Duration = Duration::from_secs(10);
+const DEFAULT_RESPONSE_HEADERS: Duration = Duration::from_secs(60);
+const DEFAULT_IDLE: Duration = Duration::from_secs(300);
+
+/// Timeouts for proxied requests, unset values fall back on a less specific level
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Time to establish a connection with the upstream
+    #[serde(with = "humantime_serde")]
+    pub connect: Option<Duration>,
+    /// Time until the response headers are received
+    #[serde(with = "humantime_serde")]
+    pub response_headers: Option<Duration>,
+    /// Time until the response body is fully received
+    #[serde(with = "humantime_serde")]
+    pub total: Option<Duration>,
+    /// Time an upgraded (e.g. WebSocket) connection may be idle before it is closed
+    #[serde(with = "humantime_serde")]
+    pub idle: Option<Duration>,
+}
+
+impl Timeouts {
+    /// Fills in the unset values from `other`
+    pub fn or(self, other: Timeouts) -> Timeouts {
+        Timeouts {
+            connect: self.connect.or(other.connect),
+            response_headers: self.response_headers.or(other.response_headers),
+            total: self.total.or(other.total),
+            idle: self.idle.or(other.idle),
+        }
+    }
+
+    /// Fills in the unset values with the built-in defaults, there is no default total timeout
+    /// as long running (streaming) responses are legitimate.
+    pub fn or_default(self) -> Timeouts {
+        self.or(Timeouts {
+            connect: Some(DEFAULT_CONNECT),
+            response_headers: Some(DEFAULT_RESPONSE_HEADERS),
+            total: None,
+            idle: Some(DEFAULT_IDLE),
+        })
+    }
+}
@@ -1,10 +1,78 @@
-use std::{io::Cursor, path::Path};
+use std::{
+    io::Cursor,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use rustls::{
+    server::AllowAnyAuthenticatedClient, Certificate as RustlsCertificate, PrivateKey,
+    RootCertStore, ServerConfig,
+};
 use tokio::fs;
 use tonic::transport::{Certificate, ClientTlsConfig, Identity};
 
 use anyhow::Result;
-use x509_parser::prelude::Pem;
+use ring::digest;
+use x509_parser::{
+    oid_registry::OID_X509_SERIALNUMBER,
+    prelude::{FromDer, Pem, X509Certificate, X509Name},
+};
+
+fn to_system_time(time: x509_parser::time::ASN1Time) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(time.timestamp().max(0) as u64)
+}
+
+#[derive(Debug, Clone)]
+pub struct Validity {
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+}
+
+impl Validity {
+    pub fn is_valid(&self) -> bool {
+        let now = SystemTime::now();
+        now >= self.not_before && now <= self.not_after
+    }
+}
+
+/// Identity of an organization as stated in the subject of its certificate
+#[derive(Debug, Clone, Default)]
+pub struct PeerIdentity {
+    /// The OIN of the organization
+    pub serial_number: Option<String>,
+    pub organization_name: Option<String>,
+    pub common_name: Option<String>,
+    /// Base64 encoded SHA-256 hash of the public key (SubjectPublicKeyInfo) of the certificate,
+    /// as NLX Management identifies the key of an outway in an access grant
+    pub public_key_hash: Option<String>,
+}
+
+fn first_value<'a>(
+    mut values: impl Iterator<Item = &'a x509_parser::x509::AttributeTypeAndValue<'a>>,
+) -> Option<String> {
+    values
+        .next()
+        .and_then(|value| value.as_str().ok())
+        .map(String::from)
+}
+
+fn public_key_hash(spki: &[u8]) -> String {
+    base64::encode(digest::digest(&digest::SHA256, spki))
+}
+
+impl PeerIdentity {
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let (_, cert) = X509Certificate::from_der(der)?;
+        let subject: &X509Name = cert.subject();
+
+        Ok(Self {
+            serial_number: first_value(subject.iter_by_oid(&OID_X509_SERIALNUMBER)),
+            organization_name: first_value(subject.iter_organization()),
+            common_name: first_value(subject.iter_common_name()),
+            public_key_hash: Some(public_key_hash(cert.public_key().raw)),
+        })
+    }
+}
 
 // @TODO: do some validation
 pub struct TlsPair {
@@ -33,6 +101,29 @@ impl TlsPair {
         ClientTlsConfig::new().ca_certificate(root).identity(client)
     }
 
+    /// Server config which requires clients to present a certificate signed by the root
+    pub fn server_config(&self) -> Result<ServerConfig> {
+        let cert_chain = pem::parse_many(&self.cert_pem)?
+            .into_iter()
+            .map(|pem| RustlsCertificate(pem.contents))
+            .collect();
+        let key = PrivateKey(pem::parse(&self.key_pem)?.contents);
+
+        let mut store = RootCertStore::empty();
+
+        for pem in pem::parse_many(&self.root_pem)? {
+            store.add(&RustlsCertificate(pem.contents))?;
+        }
+
+        let mut config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(store))
+            .with_single_cert(cert_chain, key)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(config)
+    }
+
     pub async fn from_files(
         root: impl AsRef<Path>,
         cert: impl AsRef<Path>,
@@ -55,15 +146,23 @@ impl TlsPair {
             contents: public_key.raw.to_vec(),
         }))
     }
-}
 
-pub async fn client_config(
-    root: impl AsRef<Path>,
-    client_cert: impl AsRef<Path>,
-    client_key: impl AsRef<Path>,
-) -> Result<ClientTlsConfig> {
-    let pair = TlsPair::from_files(root, client_cert, client_key).await?;
-    Ok(pair.client_config())
+    /// Returns the identity in the certificate
+    pub fn identity(&self) -> Result<PeerIdentity> {
+        PeerIdentity::from_der(&pem::parse(&self.cert_pem)?.contents)
+    }
+
+    /// Returns the validity period of the certificate
+    pub fn validity(&self) -> Result<Validity> {
+        let (pem, _) = Pem::read(Cursor::new(&self.cert_pem))?;
+        let cert = pem.parse_x509()?;
+        let validity = cert.validity();
+
+        Ok(Validity {
+            not_before: to_system_time(validity.not_before),
+            not_after: to_system_time(validity.not_after),
+        })
+    }
 }
 
 pub fn pem_bundle(item1: &[u8], item2: &[u8]) -> Vec<u8> {
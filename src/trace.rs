@@ -0,0 +1,666 @@
+use std::{
+    borrow::Cow,
+    fmt::Write as _,
+    future::Future,
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use http::{
+    header::{HeaderName, CONTENT_TYPE},
+    HeaderMap, HeaderValue, Method, Uri,
+};
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
+use once_cell::sync::OnceCell;
+use rand::Rng;
+use rustls::ClientConfig;
+use serde::Deserialize;
+use serde_json::{json, Value as Json};
+use tokio::{sync::mpsc, time};
+use tonic::{
+    async_trait,
+    body::BoxBody,
+    codegen::Service,
+    transport::{Channel, Error},
+};
+use warp::{reply::Response, Rejection};
+
+use crate::{errors::Component, supervisor::Task, VERSION};
+
+pub static TRACEPARENT: HeaderName = HeaderName::from_static("traceparent");
+
+const QUEUE_SIZE: usize = 4096;
+const MAX_BATCH_SIZE: usize = 512;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TracingSettings {
+    /// OTLP/HTTP endpoint of the collector, e.g. `http://localhost:4318/v1/traces`, tracing
+    /// is disabled when unset
+    pub otlp_endpoint: Option<String>,
+    /// Fraction of new traces (0.0 - 1.0) that is recorded, the decision of the caller is
+    /// followed for traces that started elsewhere
+    pub sample_ratio: f64,
+}
+
+impl Default for TracingSettings {
+    fn default() -> Self {
+        Self {
+            otlp_endpoint: None,
+            sample_ratio: 1.0,
+        }
+    }
+}
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// Position in a trace as propagated in the W3C `traceparent` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    sampled: bool,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut output, byte| {
+        let _ = write!(output, "{:02x}", byte);
+        output
+    })
+}
+
+fn parse_hex<const N: usize>(value: &str) -> Option<[u8; N]> {
+    if value.len() != N * 2 || value.bytes().any(|b| b.is_ascii_uppercase()) {
+        return None;
+    }
+
+    let mut bytes = [0; N];
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&value[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    // All zeros is an invalid ID
+    if bytes.iter().all(|byte| *byte == 0) {
+        return None;
+    }
+
+    Some(bytes)
+}
+
+impl TraceContext {
+    /// Parses the `traceparent` header, invalid values are ignored as the spec requires
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let value = headers.get(&TRACEPARENT)?.to_str().ok()?;
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let (trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?);
+
+        // Later versions may add fields, but version 00 has exactly four
+        if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+
+        let flags = parse_hex::<1>(flags).unwrap_or([0]);
+
+        Some(Self {
+            trace_id: parse_hex(trace_id)?,
+            span_id: parse_hex(span_id)?,
+            sampled: flags[0] & 1 == 1,
+        })
+    }
+
+    /// Returns the context of the span in which the current task runs
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|context| *context).ok()
+    }
+
+    pub fn traceparent(&self) -> HeaderValue {
+        let value = format!(
+            "00-{}-{}-{:02x}",
+            hex(&self.trace_id),
+            hex(&self.span_id),
+            self.sampled as u8
+        );
+
+        HeaderValue::try_from(value).expect("traceparent is a valid header value")
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(String),
+    Int(i64),
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Self::String(value.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Self::String(value)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Self {
+        Self::Int(value as i64)
+    }
+}
+
+struct SpanData {
+    name: Cow<'static, str>,
+    kind: Kind,
+    context: TraceContext,
+    parent_span_id: Option<[u8; 8]>,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+/// A unit of work in a trace which is exported when dropped, spans are no-ops when tracing
+/// is disabled or the trace isn't sampled
+pub struct Span(Option<Box<SpanData>>);
+
+impl Span {
+    /// Starts a span as a child of `parent` or as the root of a new trace
+    pub fn start(
+        name: impl Into<Cow<'static, str>>,
+        kind: Kind,
+        parent: Option<TraceContext>,
+    ) -> Self {
+        let tracer = match TRACER.get() {
+            Some(tracer) => tracer,
+            None => return Self(None),
+        };
+
+        let mut rng = rand::thread_rng();
+        let (trace_id, parent_span_id, sampled) = match parent {
+            Some(parent) => (parent.trace_id, Some(parent.span_id), parent.sampled),
+            None => (rng.gen(), None, rng.gen::<f64>() < tracer.sample_ratio),
+        };
+
+        if !sampled {
+            return Self(None);
+        }
+
+        let now = SystemTime::now();
+
+        Self(Some(Box::new(SpanData {
+            name: name.into(),
+            kind,
+            context: TraceContext {
+                trace_id,
+                span_id: rng.gen(),
+                sampled,
+            },
+            parent_span_id,
+            start: now,
+            end: now,
+            attributes: vec![],
+            error: None,
+        })))
+    }
+
+    /// Starts a span as a child of the span in which the current task runs
+    pub fn child_of_current(name: impl Into<Cow<'static, str>>, kind: Kind) -> Self {
+        Self::start(name, kind, TraceContext::current())
+    }
+
+    pub fn context(&self) -> Option<TraceContext> {
+        self.0.as_ref().map(|data| data.context)
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        if let Some(data) = &mut self.0 {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn set_error(&mut self, message: impl ToString) {
+        if let Some(data) = &mut self.0 {
+            data.error = Some(message.to_string());
+        }
+    }
+
+    /// Starts the span of a request received from a client, which continues the trace of
+    /// the client if it sent a `traceparent` header
+    pub fn server(
+        name: impl Into<Cow<'static, str>>,
+        method: &Method,
+        headers: &HeaderMap,
+    ) -> Self {
+        let mut span = Self::start(name, Kind::Server, TraceContext::from_headers(headers));
+        span.set_attribute("http.method", method.as_str());
+        span
+    }
+
+    /// Records the outcome of the request that was handled in this span
+    pub fn set_result(&mut self, result: &Result<Response, Rejection>) {
+        match result {
+            Ok(response) => {
+                self.set_attribute("http.status_code", response.status().as_u16());
+
+                if response.status().is_server_error() {
+                    self.set_error(response.status());
+                }
+            }
+            Err(rejection) => self.set_error(format!("{:?}", rejection)),
+        }
+    }
+
+    /// Runs the future with this span as the current span, so spans started in it (e.g. by
+    /// connectors) become children of this span
+    pub async fn scope<F: Future>(&self, future: F) -> F::Output {
+        match self.context() {
+            Some(context) => CURRENT.scope(context, future).await,
+            None => future.await,
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let (Some(mut data), Some(tracer)) = (self.0.take(), TRACER.get()) {
+            data.end = SystemTime::now();
+
+            if tracer.tx.try_send(data).is_err() {
+                log::debug!("span dropped as the export queue is full");
+            }
+        }
+    }
+}
+
+struct Tracer {
+    tx: mpsc::Sender<Box<SpanData>>,
+    sample_ratio: f64,
+}
+
+static TRACER: OnceCell<Tracer> = OnceCell::new();
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn to_json(span: &SpanData) -> Json {
+    let attributes = span
+        .attributes
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::String(value) => json!({ "stringValue": value }),
+                Value::Int(value) => json!({ "intValue": value.to_string() }),
+            };
+
+            json!({ "key": key, "value": value })
+        })
+        .collect::<Vec<_>>();
+    let status = match &span.error {
+        Some(message) => json!({ "code": 2, "message": message }),
+        None => json!({ "code": 0 }),
+    };
+
+    json!({
+        "traceId": hex(&span.context.trace_id),
+        "spanId": hex(&span.context.span_id),
+        "parentSpanId": span.parent_span_id.map(|id| hex(&id)).unwrap_or_default(),
+        "name": span.name,
+        "kind": span.kind as u8,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": attributes,
+        "status": status,
+    })
+}
+
+/// Sends finished spans in batches to the collector using OTLP/HTTP with JSON encoding. The
+/// protocol is implemented here instead of with the OpenTelemetry SDK, which would pull in a
+/// second tracing stack for the handful of fields that are exported.
+pub struct Exporter {
+    http: Client<HttpsConnector<HttpConnector>>,
+    endpoint: Uri,
+    service_name: &'static str,
+    rx: mpsc::Receiver<Box<SpanData>>,
+}
+
+/// Returns the body of an OTLP/HTTP export request, `ExportTraceServiceRequest` in JSON
+fn payload(service_name: &str, spans: &[SpanData]) -> Json {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    { "key": "service.name", "value": { "stringValue": service_name } },
+                    { "key": "service.version", "value": { "stringValue": VERSION } },
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "nlx-gateway", "version": VERSION },
+                "spans": spans.iter().map(to_json).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+impl Exporter {
+    async fn export(&self, spans: Vec<SpanData>) {
+        let body = payload(self.service_name, &spans);
+
+        let request = hyper::Request::builder()
+            .method(Method::POST)
+            .uri(self.endpoint.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .expect("valid export request");
+
+        match time::timeout(EXPORT_TIMEOUT, self.http.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() => {
+                log::trace!("exported {} spans", spans.len());
+            }
+            Ok(Ok(response)) => log::warn!(
+                "failed to export {} spans: collector responded with {}",
+                spans.len(),
+                response.status()
+            ),
+            Ok(Err(e)) => log::warn!("failed to export {} spans: {}", spans.len(), e),
+            Err(_) => log::warn!("failed to export {} spans: timeout", spans.len()),
+        }
+    }
+}
+
+#[async_trait]
+impl Task for Exporter {
+    async fn run(&mut self) -> Result<()> {
+        let mut batch = Vec::with_capacity(MAX_BATCH_SIZE);
+        let mut interval = time::interval(EXPORT_INTERVAL);
+
+        loop {
+            tokio::select! {
+                span = self.rx.recv() => match span {
+                    Some(span) => {
+                        batch.push(*span);
+
+                        if batch.len() >= MAX_BATCH_SIZE {
+                            self.export(mem::take(&mut batch)).await;
+                        }
+                    }
+                    None => return Ok(()),
+                },
+                _ = interval.tick() => {
+                    if !batch.is_empty() {
+                        self.export(mem::take(&mut batch)).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Installs the global tracer, returns the exporter task if tracing is enabled
+pub fn init(settings: &TracingSettings, component: Component) -> Result<Option<Exporter>> {
+    let endpoint = match &settings.otlp_endpoint {
+        Some(endpoint) => endpoint.parse::<Uri>()?,
+        None => return Ok(None),
+    };
+
+    let tls_config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_native_roots()
+        .with_no_client_auth();
+    let https = HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1()
+        .build();
+
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    let tracer = Tracer {
+        tx,
+        sample_ratio: settings.sample_ratio,
+    };
+
+    if TRACER.set(tracer).is_err() {
+        return Ok(None);
+    }
+
+    Ok(Some(Exporter {
+        http: Client::builder().build(https),
+        endpoint,
+        service_name: component.as_str(),
+        rx,
+    }))
+}
+
+/// Traces the gRPC calls to the management API and directory
+#[derive(Debug, Clone)]
+pub struct TracedChannel {
+    inner: Channel,
+}
+
+impl TracedChannel {
+    pub fn new(inner: Channel) -> Self {
+        Self { inner }
+    }
+}
+
+impl Service<http::Request<BoxBody>> for TracedChannel {
+    type Response = http::Response<Body>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: http::Request<BoxBody>) -> Self::Future {
+        // The path is `/<package>.<service>/<method>`
+        let name = request.uri().path().trim_start_matches('/').to_string();
+        let mut span = Span::child_of_current(name, Kind::Client);
+        span.set_attribute("rpc.system", "grpc");
+
+        if let Some(authority) = request.uri().authority() {
+            span.set_attribute("net.peer.name", authority.host());
+        }
+
+        if let Some(context) = span.context() {
+            request
+                .headers_mut()
+                .insert(&TRACEPARENT, context.traceparent());
+        }
+
+        let response = self.inner.call(request);
+
+        Box::pin(async move {
+            let result = response.await;
+
+            match &result {
+                // Failures are returned in the headers when there is no response body
+                Ok(response) => {
+                    if let Some(status) = response.headers().get("grpc-status") {
+                        if status != "0" {
+                            span.set_error(format!(
+                                "grpc-status {}",
+                                status.to_str().unwrap_or_default()
+                            ));
+                        }
+                    }
+                }
+                Err(e) => span.set_error(e),
+            }
+
+            result
+        })
+    }
+}
+
+/// Adds a span for every new connection made by the wrapped connector
+#[derive(Debug, Clone)]
+pub struct TracedConnector<C> {
+    inner: C,
+}
+
+impl<C> TracedConnector<C> {
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+}
+
+impl<C> Service<Uri> for TracedConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+    C::Error: std::fmt::Display,
+{
+    type Response = C::Response;
+    type Error = C::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let mut span = Span::child_of_current("upstream connect", Kind::Internal);
+
+        if let Some(authority) = uri.authority() {
+            span.set_attribute("net.peer.name", authority.host());
+
+            if let Some(port) = authority.port_u16() {
+                span.set_attribute("net.peer.port", port);
+            }
+        }
+
+        let connecting = self.inner.call(uri);
+
+        Box::pin(async move {
+            let result = connecting.await;
+
+            if let Err(e) = &result {
+                span.set_error(e);
+            }
+
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT_VALUE: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    fn parse(value: &'static str) -> Option<TraceContext> {
+        let mut headers = HeaderMap::new();
+        headers.insert(&TRACEPARENT, HeaderValue::from_static(value));
+
+        TraceContext::from_headers(&headers)
+    }
+
+    #[test]
+    fn parses_traceparent() {
+        let context = parse(TRACEPARENT_VALUE).unwrap();
+
+        assert_eq!(hex(&context.trace_id), "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(hex(&context.span_id), "b7ad6b7169203331");
+        assert!(context.sampled);
+        assert_eq!(context.traceparent(), TRACEPARENT_VALUE);
+    }
+
+    #[test]
+    fn parses_unsampled_traceparent() {
+        let context = parse("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00").unwrap();
+
+        assert!(!context.sampled);
+    }
+
+    #[test]
+    fn parses_traceparent_of_later_versions() {
+        let context = parse("cc-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra");
+
+        assert_eq!(context, parse(TRACEPARENT_VALUE));
+    }
+
+    #[test]
+    fn ignores_invalid_traceparent() {
+        for value in [
+            "",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "0-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c8031-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b71692033-01",
+            "00-0af7651916cd43dd8448eb211c80319g-b7ad6b7169203331-01",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+        ] {
+            assert_eq!(parse(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn exports_spans_as_otlp_json() {
+        let context = parse(TRACEPARENT_VALUE).unwrap();
+        let span = SpanData {
+            name: "proxy basisregister".into(),
+            kind: Kind::Server,
+            context,
+            parent_span_id: Some([1, 2, 3, 4, 5, 6, 7, 8]),
+            start: UNIX_EPOCH + Duration::from_millis(1_500),
+            end: UNIX_EPOCH + Duration::from_secs(2),
+            attributes: vec![
+                ("http.method", Value::from("GET")),
+                ("http.status_code", Value::from(502)),
+            ],
+            error: Some("502 Bad Gateway".to_string()),
+        };
+
+        let payload = payload("nlx-inway", &[span]);
+        let resource = &payload["resourceSpans"][0];
+
+        assert_eq!(
+            resource["resource"]["attributes"][0],
+            json!({ "key": "service.name", "value": { "stringValue": "nlx-inway" } })
+        );
+        assert_eq!(
+            resource["scopeSpans"][0]["spans"],
+            json!([{
+                "traceId": "0af7651916cd43dd8448eb211c80319c",
+                "spanId": "b7ad6b7169203331",
+                "parentSpanId": "0102030405060708",
+                "name": "proxy basisregister",
+                "kind": 2,
+                "startTimeUnixNano": "1500000000",
+                "endTimeUnixNano": "2000000000",
+                "attributes": [
+                    { "key": "http.method", "value": { "stringValue": "GET" } },
+                    { "key": "http.status_code", "value": { "intValue": "502" } },
+                ],
+                "status": { "code": 2, "message": "502 Bad Gateway" },
+            }])
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use std::{io, time::Duration};
+
+use hyper::upgrade::{OnUpgrade, Upgraded};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time,
+};
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Default)]
+struct Transferred {
+    sent: u64,
+    received: u64,
+}
+
+/// Copies data in both directions until both sides closed their end of the connection
+/// or nothing was transferred for `idle_timeout`
+async fn copy(
+    client: Upgraded,
+    upstream: Upgraded,
+    idle_timeout: Option<Duration>,
+    transferred: &mut Transferred,
+) -> io::Result<()> {
+    let (mut client_rx, mut client_tx) = tokio::io::split(client);
+    let (mut upstream_rx, mut upstream_tx) = tokio::io::split(upstream);
+    let (mut client_buf, mut upstream_buf) = ([0; BUFFER_SIZE], [0; BUFFER_SIZE]);
+    let (mut client_open, mut upstream_open) = (true, true);
+
+    while client_open || upstream_open {
+        let idle = async {
+            match idle_timeout {
+                Some(timeout) => time::sleep(timeout).await,
+                None => futures_util::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            n = client_rx.read(&mut client_buf), if client_open => match n? {
+                0 => {
+                    client_open = false;
+                    upstream_tx.shutdown().await?;
+                }
+                n => {
+                    upstream_tx.write_all(&client_buf[..n]).await?;
+                    transferred.sent += n as u64;
+                }
+            },
+            n = upstream_rx.read(&mut upstream_buf), if upstream_open => match n? {
+                0 => {
+                    upstream_open = false;
+                    client_tx.shutdown().await?;
+                }
+                n => {
+                    client_tx.write_all(&upstream_buf[..n]).await?;
+                    transferred.received += n as u64;
+                }
+            },
+            _ = idle => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "connection was idle for too long"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Waits for both sides of the connection to be upgraded and splices them together
+pub async fn splice(
+    client: OnUpgrade,
+    upstream: OnUpgrade,
+    name: String,
+    idle_timeout: Option<Duration>,
+) {
+    let (client, upstream) = match tokio::try_join!(client, upstream) {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            log::warn!("failed to upgrade connection to {}: {}", name, e);
+            return;
+        }
+    };
+
+    log::debug!("upgraded connection to {}", name);
+
+    let mut transferred = Transferred::default();
+    let result = copy(client, upstream, idle_timeout, &mut transferred).await;
+
+    match result {
+        Ok(_) => log::info!(
+            "upgraded connection to {} closed (sent={} bytes, received={} bytes)",
+            name,
+            transferred.sent,
+            transferred.received
+        ),
+        Err(e) => log::info!(
+            "upgraded connection to {} closed: {} (sent={} bytes, received={} bytes)",
+            name,
+            e,
+            transferred.sent,
+            transferred.received
+        ),
+    }
+}